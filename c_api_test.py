@@ -1,7 +1,6 @@
 import ctypes
 import time
 import numpy as np
-import cv2
 import time
 from sys import platform
 
@@ -50,6 +49,9 @@ lib.cnokhwa_device_format_type.restype = ctypes.c_size_t
 lib.cnokhwa_has_first_frame.argtypes = [ctypes.c_int32]
 lib.cnokhwa_has_first_frame.restype = ctypes.c_int32
 
+lib.cnokhwa_wait_frame.argtypes = [ctypes.c_int32, ctypes.c_uint64, ctypes.c_uint32]
+lib.cnokhwa_wait_frame.restype = ctypes.c_int32
+
 lib.cnokhwa_frame_width.argtypes = [ctypes.c_int32]
 lib.cnokhwa_frame_width.restype = ctypes.c_int32
 
@@ -62,15 +64,29 @@ lib.cnokhwa_frame_bytes_per_row.restype = ctypes.c_int32
 lib.cnokhwa_grab_frame.argtypes = [ctypes.c_int32, ctypes.POINTER(ctypes.c_uint8), ctypes.c_size_t]
 lib.cnokhwa_grab_frame.restype = ctypes.c_int32
 
+lib.cnokhwa_grab_frame_with_stride.argtypes = [ctypes.c_int32, ctypes.POINTER(ctypes.c_uint8), ctypes.c_size_t, ctypes.c_size_t]
+lib.cnokhwa_grab_frame_with_stride.restype = ctypes.c_int32
+
+lib.cnokhwa_snapshot.argtypes = [ctypes.c_int32, ctypes.c_int32, ctypes.c_uint32, ctypes.c_char_p]
+lib.cnokhwa_snapshot.restype = ctypes.c_int32
+
+lib.cnokhwa_last_error_message.argtypes = [ctypes.POINTER(ctypes.c_char), ctypes.c_size_t]
+lib.cnokhwa_last_error_message.restype = ctypes.c_size_t
+
 OK = 0
 RESULT_YES = OK
 RESULT_NO = -256
+ERROR_TIMEOUT = -14
+SNAPSHOT_JPEG = 0
 
 def get_string_from_function(func, *args, buffer_size=256):
     buf = (ctypes.c_char * buffer_size)()
     func(*args, buf, buffer_size)
     return buf.value.decode('utf-8')
 
+def last_error():
+    return get_string_from_function(lib.cnokhwa_last_error_message, buffer_size=1024)
+
 if lib.cnokhwa_initialize() != OK:
     print("Initialization failed")
     exit(1)
@@ -125,31 +141,30 @@ height = max_height
 # Start capture
 result = lib.cnokhwa_start_capture(device_index, width, height)
 if result != OK:
-    print(f"Error starting capture: {result}")
+    print(f"Error starting capture: {result} ({last_error()})")
     exit(1)
 
 try:
-    time.sleep(1)
-    # Wait for a new frame
+    # Wait for the first frame
     print("Grabbing a frame...")
     while True:
-        has_frame = lib.cnokhwa_has_first_frame(device_index)
-        if has_frame == RESULT_NO:
+        result = lib.cnokhwa_wait_frame(device_index, 0, 1000)
+        if result == ERROR_TIMEOUT:
             print('Still no frame available')
-            time.sleep(0.01)
             continue
-        elif has_frame == RESULT_YES:
+        elif result == OK:
             print("First frame available!")
             break
         else:
-            print(f"Error checking for first frame: {has_frame}")
-            break
+            print(f"Error waiting for first frame: {result} ({last_error()})")
+            exit(1)
 
     # Get frame dimensions
     frame_width = lib.cnokhwa_frame_width(device_index)
     frame_height = lib.cnokhwa_frame_height(device_index)
-    bytes_per_row = lib.cnokhwa_frame_bytes_per_row(device_index)
-    buffer_size = frame_width * frame_height * 3  # RGB format
+    # Pad rows to 64 bytes to exercise the strided grab
+    bytes_per_row = (lib.cnokhwa_frame_bytes_per_row(device_index) + 63) // 64 * 64
+    buffer_size = bytes_per_row * frame_height
 
     print(frame_width, frame_height, bytes_per_row)
 
@@ -158,9 +173,9 @@ try:
 
     # Grab the frame
     start = time.time()
-    result = lib.cnokhwa_grab_frame(device_index, buffer, buffer_size)
+    result = lib.cnokhwa_grab_frame_with_stride(device_index, buffer, buffer_size, bytes_per_row)
     if result != OK:
-        print(f"Error grabbing frame: {result}")
+        print(f"Error grabbing frame: {result} ({last_error()})")
         exit(1)
 
     print("Grab time: " + str(time.time() - start))
@@ -185,20 +200,15 @@ try:
     # Ensure data type is uint8
     frame_array = frame_array.astype(np.uint8)
 
-    # Convert RGBA to BGR
-    frame_bgr = cv2.cvtColor(frame_array, cv2.COLOR_RGBA2BGR)
-
     print(f"frame_array.shape: {frame_array.shape}")
     print(f"frame_array.dtype: {frame_array.dtype}")
 
-    print(f"frame_bgr.shape: {frame_bgr.shape}")
-    print(f"frame_bgr.dtype: {frame_bgr.dtype}")
-
     filename = 'frame.jpg'
     print('Writing to ' + filename)
 
-    if not cv2.imwrite(filename, frame_bgr):
-        print('Error writing image')
+    result = lib.cnokhwa_snapshot(device_index, SNAPSHOT_JPEG, 90, filename.encode('utf-8'))
+    if result != OK:
+        print(f"Error writing image: {result} ({last_error()})")
     else:
         print('Image written successfully')
 
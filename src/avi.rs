@@ -0,0 +1,338 @@
+use nokhwa::utils::Resolution;
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Layout of the headers written by AviWriter::new, sizes and counts are patched when finishing:
+//   RIFF 'AVI '
+//     LIST 'hdrl'
+//       'avih' main header
+//       LIST 'strl'
+//         'strh' stream header
+//         'strf' BITMAPINFOHEADER
+//     LIST 'movi'
+//       '00dc' JPEG image, one chunk per frame interval (empty for intervals without a new frame)
+//     'idx1' index
+const RIFF_SIZE_OFFSET: u64 = 4;
+const AVIH_MAX_BYTES_PER_SEC_OFFSET: u64 = 36;
+const AVIH_TOTAL_FRAMES_OFFSET: u64 = 48;
+const AVIH_SUGGESTED_BUFFER_SIZE_OFFSET: u64 = 60;
+const STRH_LENGTH_OFFSET: u64 = 140;
+const STRH_SUGGESTED_BUFFER_SIZE_OFFSET: u64 = 144;
+const MOVI_SIZE_OFFSET: u64 = 216;
+// idx1 offsets are relative to the 'movi' fourcc
+const MOVI_OFFSET: u64 = 220;
+
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+// Stay below the 2 GB that AVI 1.0 readers reliably support, keeping room for the index
+const MAX_FILE_SIZE: u64 = i32::MAX as u64;
+
+pub type AviFile = AviWriter<BufWriter<File>>;
+
+struct IndexEntry {
+    offset: u32,
+    size: u32,
+}
+
+/// Writes JPEG images into an MJPEG AVI file with a constant frame rate.
+/// Frames are placed in the interval of their capture timestamp: intervals without a frame repeat the previous
+/// one and frames arriving within an interval that already has one are dropped, so playback keeps real time.
+/// Dropping the writer finishes the file too, errors are ignored then.
+pub struct AviWriter<W: Write + Seek> {
+    output: W,
+    frame_rate: u32,
+    first_timestamp_us: Option<u64>,
+    // Chunks written, including empty ones
+    index: Vec<IndexEntry>,
+    position: u64,
+    max_frame_size: u32,
+    finished: bool,
+}
+
+impl AviFile {
+    /// Creates the file, overwriting any existing one.
+    pub fn create(path: &Path, resolution: Resolution, frame_rate: u32) -> io::Result<AviFile> {
+        AviWriter::new(BufWriter::new(File::create(path)?), resolution, frame_rate)
+    }
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    pub fn new(mut output: W, resolution: Resolution, frame_rate: u32) -> io::Result<AviWriter<W>> {
+        let frame_rate = frame_rate.max(1);
+        let (width, height) = (resolution.width(), resolution.height());
+
+        let mut header = vec![];
+        header.extend_from_slice(b"RIFF");
+        put_u32(&mut header, 0);
+        header.extend_from_slice(b"AVI ");
+
+        header.extend_from_slice(b"LIST");
+        put_u32(&mut header, 192);
+        header.extend_from_slice(b"hdrl");
+
+        header.extend_from_slice(b"avih");
+        put_u32(&mut header, 56);
+        put_u32(&mut header, 1_000_000 / frame_rate);
+        put_u32(&mut header, 0); // max bytes per second
+        put_u32(&mut header, 0); // padding granularity
+        put_u32(&mut header, AVIF_HASINDEX);
+        put_u32(&mut header, 0); // total frames
+        put_u32(&mut header, 0); // initial frames
+        put_u32(&mut header, 1); // streams
+        put_u32(&mut header, 0); // suggested buffer size
+        put_u32(&mut header, width);
+        put_u32(&mut header, height);
+        header.extend_from_slice(&[0u8; 16]);
+
+        header.extend_from_slice(b"LIST");
+        put_u32(&mut header, 116);
+        header.extend_from_slice(b"strl");
+
+        header.extend_from_slice(b"strh");
+        put_u32(&mut header, 56);
+        header.extend_from_slice(b"vids");
+        header.extend_from_slice(b"MJPG");
+        put_u32(&mut header, 0); // flags
+        put_u32(&mut header, 0); // priority and language
+        put_u32(&mut header, 0); // initial frames
+        put_u32(&mut header, 1); // scale
+        put_u32(&mut header, frame_rate); // rate, frames per second is rate / scale
+        put_u32(&mut header, 0); // start
+        put_u32(&mut header, 0); // length
+        put_u32(&mut header, 0); // suggested buffer size
+        put_u32(&mut header, u32::MAX); // quality, default
+        put_u32(&mut header, 0); // sample size, varies
+        header.extend_from_slice(&[0u8; 4]);
+        header.extend_from_slice(&(width.min(u16::MAX as u32) as u16).to_le_bytes());
+        header.extend_from_slice(&(height.min(u16::MAX as u32) as u16).to_le_bytes());
+
+        header.extend_from_slice(b"strf");
+        put_u32(&mut header, 40);
+        put_u32(&mut header, 40);
+        put_u32(&mut header, width);
+        put_u32(&mut header, height);
+        header.extend_from_slice(&1u16.to_le_bytes()); // planes
+        header.extend_from_slice(&24u16.to_le_bytes()); // bits per pixel
+        header.extend_from_slice(b"MJPG");
+        put_u32(&mut header, width.saturating_mul(height).saturating_mul(3));
+        header.extend_from_slice(&[0u8; 16]);
+
+        header.extend_from_slice(b"LIST");
+        put_u32(&mut header, 0);
+        header.extend_from_slice(b"movi");
+
+        debug_assert_eq!(header.len() as u64, MOVI_OFFSET + 4);
+
+        output.write_all(&header)?;
+
+        Ok(AviWriter {
+            output,
+            frame_rate,
+            first_timestamp_us: None,
+            index: vec![],
+            position: header.len() as u64,
+            max_frame_size: 0,
+            finished: false,
+        })
+    }
+
+    /// Adds a JPEG image captured at `timestamp_us`. Returns false if it was dropped because its frame interval
+    /// already has an image. Fails once the file reaches the AVI size limit, the file can still be finished then.
+    pub fn write_frame(&mut self, timestamp_us: u64, jpeg: &[u8]) -> io::Result<bool> {
+        let first_timestamp_us = *self.first_timestamp_us.get_or_insert(timestamp_us);
+        let elapsed_us = timestamp_us.saturating_sub(first_timestamp_us);
+        let slot = ((elapsed_us * self.frame_rate as u64 + 500_000) / 1_000_000) as usize;
+
+        if slot < self.index.len() {
+            return Ok(false);
+        }
+
+        let empty_chunks = (slot - self.index.len()) as u64;
+        let needed = (empty_chunks + 1) * (8 + 16) + jpeg.len() as u64 + 1 + 8;
+        if self.position + needed + self.index.len() as u64 * 16 > MAX_FILE_SIZE {
+            return Err(io::Error::new(ErrorKind::StorageFull, "The recording reached the AVI file size limit"));
+        }
+
+        // Players show the previous image again for empty chunks
+        while self.index.len() < slot {
+            self.write_chunk(&[])?;
+        }
+
+        self.write_chunk(jpeg)?;
+
+        Ok(true)
+    }
+
+    /// Writes the index and the final sizes, and flushes the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finalize()
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        let offset = (self.position - MOVI_OFFSET) as u32;
+        let size = data.len() as u32;
+
+        self.output.write_all(b"00dc")?;
+        self.output.write_all(&size.to_le_bytes())?;
+        self.output.write_all(data)?;
+        self.position += 8 + data.len() as u64;
+
+        // Chunks are word aligned
+        if data.len() % 2 == 1 {
+            self.output.write_all(&[0])?;
+            self.position += 1;
+        }
+
+        self.index.push(IndexEntry { offset, size });
+        self.max_frame_size = self.max_frame_size.max(size);
+
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        let movi_size = (self.position - MOVI_OFFSET) as u32;
+
+        let mut index = Vec::with_capacity(8 + self.index.len() * 16);
+        index.extend_from_slice(b"idx1");
+        put_u32(&mut index, (self.index.len() * 16) as u32);
+        for entry in &self.index {
+            index.extend_from_slice(b"00dc");
+            put_u32(&mut index, if entry.size > 0 { AVIIF_KEYFRAME } else { 0 });
+            put_u32(&mut index, entry.offset);
+            put_u32(&mut index, entry.size);
+        }
+        self.output.write_all(&index)?;
+
+        let file_size = self.position + index.len() as u64;
+        let frames = self.index.len() as u32;
+        let total_bytes: u64 = self.index.iter().map(|e| e.size as u64).sum();
+        let bytes_per_second = if frames > 0 { total_bytes * self.frame_rate as u64 / frames as u64 } else { 0 };
+        let buffer_size = self.max_frame_size + 8;
+
+        self.patch(RIFF_SIZE_OFFSET, (file_size - 8) as u32)?;
+        self.patch(AVIH_MAX_BYTES_PER_SEC_OFFSET, bytes_per_second.min(u32::MAX as u64) as u32)?;
+        self.patch(AVIH_TOTAL_FRAMES_OFFSET, frames)?;
+        self.patch(AVIH_SUGGESTED_BUFFER_SIZE_OFFSET, buffer_size)?;
+        self.patch(STRH_LENGTH_OFFSET, frames)?;
+        self.patch(STRH_SUGGESTED_BUFFER_SIZE_OFFSET, buffer_size)?;
+        self.patch(MOVI_SIZE_OFFSET, movi_size)?;
+
+        self.output.seek(SeekFrom::Start(file_size))?;
+        self.output.flush()
+    }
+
+    fn patch(&mut self, offset: u64, value: u32) -> io::Result<()> {
+        self.output.seek(SeekFrom::Start(offset))?;
+        self.output.write_all(&value.to_le_bytes())
+    }
+}
+
+impl<W: Write + Seek> Drop for AviWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}
+
+fn put_u32(output: &mut Vec<u8>, value: u32) {
+    output.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn record(frames: &[(u64, &[u8])]) -> Vec<u8> {
+        let mut output = Cursor::new(vec![]);
+        {
+            let mut writer = AviWriter::new(&mut output, Resolution::new(64, 48), 10).unwrap();
+            for (timestamp_us, jpeg) in frames {
+                writer.write_frame(*timestamp_us, jpeg).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+        output.into_inner()
+    }
+
+    // (fourcc, flags, offset, size) of every index entry
+    fn index(avi: &[u8]) -> Vec<([u8; 4], u32, u32, u32)> {
+        let movi_size = u32_at(avi, MOVI_SIZE_OFFSET as usize) as usize;
+        let idx1 = MOVI_OFFSET as usize + movi_size;
+        assert_eq!(&avi[idx1..idx1 + 4], b"idx1");
+
+        avi[idx1 + 8..].chunks_exact(16)
+            .map(|e| (e[..4].try_into().unwrap(), u32_at(e, 4), u32_at(e, 8), u32_at(e, 12)))
+            .collect()
+    }
+
+    #[test]
+    fn writes_a_valid_structure() {
+        let avi = record(&[(1_000_000, b"abc"), (1_100_000, b"defg")]);
+
+        assert_eq!(&avi[..4], b"RIFF");
+        assert_eq!(u32_at(&avi, 4) as usize, avi.len() - 8);
+        assert_eq!(&avi[8..12], b"AVI ");
+        assert_eq!(&avi[12..16], b"LIST");
+        assert_eq!(&avi[20..28], b"hdrlavih");
+        assert_eq!(u32_at(&avi, 32), 100_000);
+        assert_eq!(u32_at(&avi, AVIH_TOTAL_FRAMES_OFFSET as usize), 2);
+        assert_eq!(u32_at(&avi, 64), 64);
+        assert_eq!(u32_at(&avi, 68), 48);
+        assert_eq!(&avi[96..104], b"strlstrh");
+        assert_eq!(&avi[108..116], b"vidsMJPG");
+        assert_eq!(u32_at(&avi, 132), 10);
+        assert_eq!(u32_at(&avi, STRH_LENGTH_OFFSET as usize), 2);
+        assert_eq!(&avi[164..168], b"strf");
+        assert_eq!(&avi[188..192], b"MJPG");
+        assert_eq!(&avi[MOVI_OFFSET as usize..MOVI_OFFSET as usize + 4], b"movi");
+
+        let entries = index(&avi);
+        assert_eq!(entries.len(), 2);
+
+        // Odd sized chunks are padded and offsets point at the chunk headers
+        for ((fourcc, flags, offset, size), data) in entries.iter().zip([&b"abc"[..], b"defg"]) {
+            assert_eq!(fourcc, b"00dc");
+            assert_eq!(*flags, AVIIF_KEYFRAME);
+            let chunk = MOVI_OFFSET as usize + *offset as usize;
+            assert_eq!(&avi[chunk..chunk + 4], b"00dc");
+            assert_eq!(*size as usize, data.len());
+            assert_eq!(&avi[chunk + 8..chunk + 8 + data.len()], data);
+        }
+        assert_eq!(entries[1].2 - entries[0].2, 8 + 4);
+    }
+
+    #[test]
+    fn frames_follow_capture_timestamps() {
+        // 10 fps: a gap of three intervals, then a frame too early for its interval
+        let avi = record(&[(0, b"a"), (100_000, b"b"), (400_000, b"c"), (430_000, b"d"), (500_000, b"e")]);
+
+        let sizes: Vec<u32> = index(&avi).iter().map(|e| e.3).collect();
+        assert_eq!(sizes, vec![1, 1, 0, 0, 1, 1]);
+        assert_eq!(u32_at(&avi, STRH_LENGTH_OFFSET as usize), 6);
+        assert_eq!(index(&avi)[2].1, 0);
+    }
+
+    #[test]
+    fn dropping_the_writer_finishes_the_file() {
+        let mut output = Cursor::new(vec![]);
+        {
+            let mut writer = AviWriter::new(&mut output, Resolution::new(4, 4), 30).unwrap();
+            writer.write_frame(0, b"jpeg").unwrap();
+        }
+        let avi = output.into_inner();
+
+        assert_eq!(u32_at(&avi, 4) as usize, avi.len() - 8);
+        assert_eq!(index(&avi).len(), 1);
+    }
+}
@@ -0,0 +1,287 @@
+use nokhwa::utils::{CameraControl, ControlValueDescription, ControlValueSetter, KnownCameraControl, KnownCameraControlFlag};
+
+// Ids above this value map to KnownCameraControl::Other, offset by the backend specific id
+const OTHER_CONTROL_ID_BASE: u64 = 256;
+
+pub const CONTROL_KIND_NONE: i32 = 0;
+pub const CONTROL_KIND_INTEGER: i32 = 1;
+pub const CONTROL_KIND_INTEGER_RANGE: i32 = 2;
+pub const CONTROL_KIND_FLOAT: i32 = 3;
+pub const CONTROL_KIND_FLOAT_RANGE: i32 = 4;
+pub const CONTROL_KIND_BOOLEAN: i32 = 5;
+pub const CONTROL_KIND_MENU: i32 = 6;
+pub const CONTROL_KIND_UNSUPPORTED: i32 = 7;
+
+pub const CONTROL_FLAG_AUTOMATIC: u32 = 1;
+pub const CONTROL_FLAG_MANUAL: u32 = 1 << 1;
+pub const CONTROL_FLAG_CONTINUOUS: u32 = 1 << 2;
+pub const CONTROL_FLAG_READ_ONLY: u32 = 1 << 3;
+pub const CONTROL_FLAG_WRITE_ONLY: u32 = 1 << 4;
+pub const CONTROL_FLAG_VOLATILE: u32 = 1 << 5;
+pub const CONTROL_FLAG_DISABLED: u32 = 1 << 6;
+pub const CONTROL_FLAG_ACTIVE: u32 = 1 << 7;
+
+/// Description of a camera control, as exposed through the C API.
+/// Numeric values are doubles so integer and float controls can share the same functions.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ControlInfo {
+    pub id: u64,
+    pub kind: i32,
+    pub flags: u32,
+    pub min: f64,
+    pub max: f64,
+    pub step: f64,
+    pub default_value: f64,
+    pub value: f64,
+    pub menu_entries_count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceControl {
+    pub control: KnownCameraControl,
+    pub name: String,
+    pub info: ControlInfo,
+    pub menu_entries: Vec<i64>,
+}
+
+pub fn control_id(control: KnownCameraControl) -> Option<u64> {
+    let id = match control {
+        KnownCameraControl::Brightness => 0,
+        KnownCameraControl::Contrast => 1,
+        KnownCameraControl::Hue => 2,
+        KnownCameraControl::Saturation => 3,
+        KnownCameraControl::Sharpness => 4,
+        KnownCameraControl::Gamma => 5,
+        KnownCameraControl::WhiteBalance => 6,
+        KnownCameraControl::BacklightComp => 7,
+        KnownCameraControl::Gain => 8,
+        KnownCameraControl::Pan => 9,
+        KnownCameraControl::Tilt => 10,
+        KnownCameraControl::Zoom => 11,
+        KnownCameraControl::Exposure => 12,
+        KnownCameraControl::Iris => 13,
+        KnownCameraControl::Focus => 14,
+        KnownCameraControl::Other(other) => {
+            return u64::try_from(other).ok()?.checked_add(OTHER_CONTROL_ID_BASE);
+        }
+    };
+
+    Some(id)
+}
+
+pub fn known_control(id: u64) -> Option<KnownCameraControl> {
+    let control = match id {
+        0 => KnownCameraControl::Brightness,
+        1 => KnownCameraControl::Contrast,
+        2 => KnownCameraControl::Hue,
+        3 => KnownCameraControl::Saturation,
+        4 => KnownCameraControl::Sharpness,
+        5 => KnownCameraControl::Gamma,
+        6 => KnownCameraControl::WhiteBalance,
+        7 => KnownCameraControl::BacklightComp,
+        8 => KnownCameraControl::Gain,
+        9 => KnownCameraControl::Pan,
+        10 => KnownCameraControl::Tilt,
+        11 => KnownCameraControl::Zoom,
+        12 => KnownCameraControl::Exposure,
+        13 => KnownCameraControl::Iris,
+        14 => KnownCameraControl::Focus,
+        other if other >= OTHER_CONTROL_ID_BASE => KnownCameraControl::Other((other - OTHER_CONTROL_ID_BASE) as u128),
+        _ => return None,
+    };
+
+    Some(control)
+}
+
+impl DeviceControl {
+    /// Returns `None` for controls that can't be addressed through the C API.
+    pub fn from_camera_control(control: &CameraControl) -> Option<DeviceControl> {
+        let id = control_id(control.control())?;
+
+        let mut flags = control.flag().iter().fold(0, |flags, flag| flags | match flag {
+            KnownCameraControlFlag::Automatic => CONTROL_FLAG_AUTOMATIC,
+            KnownCameraControlFlag::Manual => CONTROL_FLAG_MANUAL,
+            KnownCameraControlFlag::Continuous => CONTROL_FLAG_CONTINUOUS,
+            KnownCameraControlFlag::ReadOnly => CONTROL_FLAG_READ_ONLY,
+            KnownCameraControlFlag::WriteOnly => CONTROL_FLAG_WRITE_ONLY,
+            KnownCameraControlFlag::Volatile => CONTROL_FLAG_VOLATILE,
+            KnownCameraControlFlag::Disabled => CONTROL_FLAG_DISABLED,
+        });
+        if control.active() {
+            flags |= CONTROL_FLAG_ACTIVE;
+        }
+
+        let mut info = ControlInfo { id, flags, ..Default::default() };
+        let mut menu_entries = vec![];
+
+        match control.description() {
+            ControlValueDescription::None => info.kind = CONTROL_KIND_NONE,
+            ControlValueDescription::Integer { value, default, step } => {
+                info.kind = CONTROL_KIND_INTEGER;
+                info.value = *value as f64;
+                info.default_value = *default as f64;
+                info.step = *step as f64;
+                info.min = f64::MIN;
+                info.max = f64::MAX;
+            }
+            ControlValueDescription::IntegerRange { min, max, value, step, default } => {
+                info.kind = CONTROL_KIND_INTEGER_RANGE;
+                info.min = *min as f64;
+                info.max = *max as f64;
+                info.value = *value as f64;
+                info.step = *step as f64;
+                info.default_value = *default as f64;
+            }
+            ControlValueDescription::Float { value, default, step } => {
+                info.kind = CONTROL_KIND_FLOAT;
+                info.value = *value;
+                info.default_value = *default;
+                info.step = *step;
+                info.min = f64::MIN;
+                info.max = f64::MAX;
+            }
+            ControlValueDescription::FloatRange { min, max, value, step, default } => {
+                info.kind = CONTROL_KIND_FLOAT_RANGE;
+                info.min = *min;
+                info.max = *max;
+                info.value = *value;
+                info.step = *step;
+                info.default_value = *default;
+            }
+            ControlValueDescription::Boolean { value, default } => {
+                info.kind = CONTROL_KIND_BOOLEAN;
+                info.min = 0.0;
+                info.max = 1.0;
+                info.step = 1.0;
+                info.value = if *value { 1.0 } else { 0.0 };
+                info.default_value = if *default { 1.0 } else { 0.0 };
+            }
+            ControlValueDescription::Enum { value, possible, default } => {
+                info.kind = CONTROL_KIND_MENU;
+                info.value = *value as f64;
+                info.default_value = *default as f64;
+                info.min = possible.iter().min().copied().unwrap_or_default() as f64;
+                info.max = possible.iter().max().copied().unwrap_or_default() as f64;
+                menu_entries = possible.clone();
+            }
+            _ => info.kind = CONTROL_KIND_UNSUPPORTED,
+        }
+
+        info.menu_entries_count = menu_entries.len() as u32;
+
+        Some(DeviceControl {
+            control: control.control(),
+            name: control.name().to_string(),
+            info,
+            menu_entries,
+        })
+    }
+}
+
+/// Builds the setter matching the kind of `control` for a numeric value, `None` if the control is not numeric.
+pub fn value_setter(control: &CameraControl, value: f64) -> Option<ControlValueSetter> {
+    match control.description() {
+        ControlValueDescription::Integer { .. } | ControlValueDescription::IntegerRange { .. } => {
+            Some(ControlValueSetter::Integer(value.round() as i64))
+        }
+        ControlValueDescription::Float { .. } | ControlValueDescription::FloatRange { .. } => {
+            Some(ControlValueSetter::Float(value))
+        }
+        ControlValueDescription::Boolean { .. } => Some(ControlValueSetter::Boolean(value != 0.0)),
+        ControlValueDescription::Enum { .. } => Some(ControlValueSetter::EnumValue(value.round() as i64)),
+        _ => None,
+    }
+}
+
+/// Numeric value of a control, `None` if the control is not numeric.
+pub fn numeric_value(control: &CameraControl) -> Option<f64> {
+    match control.value() {
+        ControlValueSetter::Integer(v) | ControlValueSetter::EnumValue(v) => Some(v as f64),
+        ControlValueSetter::Float(v) => Some(v),
+        ControlValueSetter::Boolean(v) => Some(if v { 1.0 } else { 0.0 }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn control(control: KnownCameraControl, description: ControlValueDescription, flags: Vec<KnownCameraControlFlag>) -> CameraControl {
+        CameraControl::new(control, "Test".to_string(), description, flags, true)
+    }
+
+    #[test]
+    fn maps_ids_both_ways() {
+        for id in 0..15 {
+            let known = known_control(id).unwrap();
+            assert!(!matches!(known, KnownCameraControl::Other(_)));
+            assert_eq!(control_id(known), Some(id));
+        }
+
+        // Backend specific controls are offset past the known ones
+        assert_eq!(control_id(KnownCameraControl::Other(5)), Some(261));
+        assert_eq!(known_control(261), Some(KnownCameraControl::Other(5)));
+        assert_eq!(known_control(OTHER_CONTROL_ID_BASE), Some(KnownCameraControl::Other(0)));
+        assert_eq!(known_control(15), None);
+        assert_eq!(known_control(OTHER_CONTROL_ID_BASE - 1), None);
+        assert_eq!(control_id(KnownCameraControl::Other(u128::MAX)), None);
+    }
+
+    #[test]
+    fn describes_kinds_and_flags() {
+        let range = ControlValueDescription::IntegerRange { min: -10, max: 10, value: 3, step: 2, default: 0 };
+        let brightness = DeviceControl::from_camera_control(&control(
+            KnownCameraControl::Brightness,
+            range,
+            vec![KnownCameraControlFlag::Automatic, KnownCameraControlFlag::ReadOnly],
+        )).unwrap();
+        assert_eq!(brightness.info, ControlInfo {
+            id: 0,
+            kind: CONTROL_KIND_INTEGER_RANGE,
+            flags: CONTROL_FLAG_AUTOMATIC | CONTROL_FLAG_READ_ONLY | CONTROL_FLAG_ACTIVE,
+            min: -10.0,
+            max: 10.0,
+            step: 2.0,
+            default_value: 0.0,
+            value: 3.0,
+            menu_entries_count: 0,
+        });
+
+        let boolean = ControlValueDescription::Boolean { value: true, default: false };
+        let info = DeviceControl::from_camera_control(&control(KnownCameraControl::BacklightComp, boolean, vec![])).unwrap().info;
+        assert_eq!((info.kind, info.flags, info.min, info.max, info.value, info.default_value), (CONTROL_KIND_BOOLEAN, CONTROL_FLAG_ACTIVE, 0.0, 1.0, 1.0, 0.0));
+
+        let menu = ControlValueDescription::Enum { value: 50, possible: vec![50, 60, 0], default: 60 };
+        let power_line = DeviceControl::from_camera_control(&control(KnownCameraControl::Other(7), menu, vec![KnownCameraControlFlag::Disabled])).unwrap();
+        assert_eq!(power_line.info.id, 263);
+        assert_eq!((power_line.info.kind, power_line.info.flags), (CONTROL_KIND_MENU, CONTROL_FLAG_DISABLED | CONTROL_FLAG_ACTIVE));
+        assert_eq!((power_line.info.min, power_line.info.max, power_line.info.menu_entries_count), (0.0, 60.0, 3));
+        assert_eq!(power_line.menu_entries, [50, 60, 0]);
+
+        let unaddressable = control(KnownCameraControl::Other(u128::MAX), ControlValueDescription::None, vec![]);
+        assert_eq!(DeviceControl::from_camera_control(&unaddressable), None);
+    }
+
+    #[test]
+    fn builds_setters_for_numeric_controls() {
+        let integer = control(KnownCameraControl::Gain, ControlValueDescription::Integer { value: 1, default: 1, step: 1 }, vec![]);
+        assert_eq!(value_setter(&integer, 2.6), Some(ControlValueSetter::Integer(3)));
+
+        let float = control(KnownCameraControl::Gamma, ControlValueDescription::FloatRange { min: 0.0, max: 2.0, value: 1.0, step: 0.1, default: 1.0 }, vec![]);
+        assert_eq!(value_setter(&float, 1.5), Some(ControlValueSetter::Float(1.5)));
+
+        let boolean = control(KnownCameraControl::BacklightComp, ControlValueDescription::Boolean { value: false, default: false }, vec![]);
+        assert_eq!(value_setter(&boolean, 0.0), Some(ControlValueSetter::Boolean(false)));
+        assert_eq!(value_setter(&boolean, -1.0), Some(ControlValueSetter::Boolean(true)));
+
+        let menu = control(KnownCameraControl::Other(7), ControlValueDescription::Enum { value: 50, possible: vec![50, 60], default: 50 }, vec![]);
+        assert_eq!(value_setter(&menu, 59.7), Some(ControlValueSetter::EnumValue(60)));
+        assert_eq!(numeric_value(&menu), Some(50.0));
+
+        let none = control(KnownCameraControl::Focus, ControlValueDescription::None, vec![]);
+        assert_eq!(value_setter(&none, 1.0), None);
+        assert_eq!(numeric_value(&none), None);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::output_format::OutputFormat;
+use nokhwa::utils::{FrameFormat, Resolution};
+
+// Frames of this height or more are HD video, which uses BT.709
+const HD_HEIGHT: u32 = 720;
+
+/// Matrix turning YUV into RGB. The discriminants are the values used by the C API.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum YuvMatrix {
+    /// BT.709 for frames of 720 lines or more and BT.601 below, except MJPEG which is always BT.601
+    #[default]
+    Auto = 0,
+    Bt601 = 1,
+    Bt709 = 2,
+}
+
+impl YuvMatrix {
+    pub fn from_code(code: i32) -> Option<YuvMatrix> {
+        match code {
+            0 => Some(YuvMatrix::Auto),
+            1 => Some(YuvMatrix::Bt601),
+            2 => Some(YuvMatrix::Bt709),
+            _ => None,
+        }
+    }
+
+    /// Weights of red and blue in the luma, for a resolved matrix.
+    pub fn luma_weights(self) -> (f64, f64) {
+        match self {
+            YuvMatrix::Bt709 => (0.2126, 0.0722),
+            YuvMatrix::Bt601 | YuvMatrix::Auto => (0.299, 0.114),
+        }
+    }
+}
+
+/// Range of the YUV values. The discriminants are the values used by the C API.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum YuvRange {
+    /// Full range for MJPEG, limited range for the other formats
+    #[default]
+    Auto = 0,
+    /// Luma from 16 to 235 and chroma from 16 to 240, as sent by most webcams
+    Limited = 1,
+    /// Luma and chroma from 0 to 255
+    Full = 2,
+}
+
+impl YuvRange {
+    pub fn from_code(code: i32) -> Option<YuvRange> {
+        match code {
+            0 => Some(YuvRange::Auto),
+            1 => Some(YuvRange::Limited),
+            2 => Some(YuvRange::Full),
+            _ => None,
+        }
+    }
+}
+
+/// How the YUV frames of a session are turned into RGB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct YuvColorSpace {
+    pub matrix: YuvMatrix,
+    pub range: YuvRange,
+}
+
+impl YuvColorSpace {
+    /// The color space of JFIF files, which libjpeg converts to RGB itself.
+    pub const JFIF: YuvColorSpace = YuvColorSpace { matrix: YuvMatrix::Bt601, range: YuvRange::Full };
+
+    /// Replaces the automatic choices with the ones for camera frames of `format` and `resolution`.
+    /// MJPEG frames are JFIF files, whatever their resolution.
+    pub fn resolve(self, format: FrameFormat, resolution: Resolution) -> YuvColorSpace {
+        let jpeg = format == FrameFormat::MJPEG;
+
+        let matrix = match self.matrix {
+            YuvMatrix::Auto if jpeg || resolution.height() < HD_HEIGHT => YuvMatrix::Bt601,
+            YuvMatrix::Auto => YuvMatrix::Bt709,
+            matrix => matrix,
+        };
+        let range = match self.range {
+            YuvRange::Auto if jpeg => YuvRange::Full,
+            YuvRange::Auto => YuvRange::Limited,
+            range => range,
+        };
+
+        YuvColorSpace { matrix, range }
+    }
+
+    /// Fixed point coefficients of the conversion, for a resolved color space.
+    pub fn coefficients(self) -> YuvCoefficients {
+        let (kr, kb) = self.matrix.luma_weights();
+        let kg = 1.0 - kr - kb;
+
+        let (luma_offset, luma_scale, chroma_scale) = match self.range {
+            YuvRange::Full => (0, 1.0, 1.0),
+            YuvRange::Limited | YuvRange::Auto => (16, 255.0 / 219.0, 255.0 / 224.0),
+        };
+        let fixed = |value: f64| (value * 256.0).round() as i32;
+
+        YuvCoefficients {
+            luma_offset,
+            luma: fixed(luma_scale),
+            red_v: fixed(2.0 * (1.0 - kr) * chroma_scale),
+            green_u: fixed(2.0 * (1.0 - kb) * kb / kg * chroma_scale),
+            green_v: fixed(2.0 * (1.0 - kr) * kr / kg * chroma_scale),
+            blue_u: fixed(2.0 * (1.0 - kb) * chroma_scale),
+        }
+    }
+}
+
+/// YUV to RGB in fixed point with 8 fractional bits, with U and V centered on 128:
+/// r = luma (y - luma_offset) + red_v v, g = luma (y - luma_offset) - green_u u - green_v v, b = luma (y - luma_offset) + blue_u u
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct YuvCoefficients {
+    pub luma_offset: i32,
+    pub luma: i32,
+    pub red_v: i32,
+    pub green_u: i32,
+    pub green_v: i32,
+    pub blue_u: i32,
+}
+
+impl YuvCoefficients {
+    pub fn to_rgb(self, y: u8, u: u8, v: u8) -> [u8; 3] {
+        let c = (y as i32 - self.luma_offset) * self.luma;
+        let d = u as i32 - 128;
+        let e = v as i32 - 128;
+
+        [
+            ((c + self.red_v * e + 128) >> 8).clamp(0, 255) as u8,
+            ((c - self.green_u * d - self.green_v * e + 128) >> 8).clamp(0, 255) as u8,
+            ((c + self.blue_u * d + 128) >> 8).clamp(0, 255) as u8,
+        ]
+    }
+
+    /// Luma as a full range gray level.
+    pub fn gray(self, y: u8) -> u8 {
+        (((y as i32 - self.luma_offset) * self.luma + 128) >> 8).clamp(0, 255) as u8
+    }
+}
+
+/// Writes an RGB pixel in one of the color output formats, opaque when it has alpha.
+pub fn write_pixel([r, g, b]: [u8; 3], output_format: OutputFormat, dst: &mut [u8]) {
+    match output_format {
+        OutputFormat::Rgb => dst.copy_from_slice(&[r, g, b]),
+        OutputFormat::Bgr => dst.copy_from_slice(&[b, g, r]),
+        OutputFormat::Rgba => dst.copy_from_slice(&[r, g, b, 255]),
+        OutputFormat::Bgra => dst.copy_from_slice(&[b, g, r, 255]),
+        OutputFormat::Gray8 => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolved(matrix: YuvMatrix, range: YuvRange, format: FrameFormat, height: u32) -> YuvColorSpace {
+        YuvColorSpace { matrix, range }.resolve(format, Resolution::new(height * 16 / 9, height))
+    }
+
+    #[test]
+    fn resolves_automatic_choices() {
+        let auto = (YuvMatrix::Auto, YuvRange::Auto);
+        assert_eq!(resolved(auto.0, auto.1, FrameFormat::YUYV, 480), YuvColorSpace { matrix: YuvMatrix::Bt601, range: YuvRange::Limited });
+        assert_eq!(resolved(auto.0, auto.1, FrameFormat::NV12, 720), YuvColorSpace { matrix: YuvMatrix::Bt709, range: YuvRange::Limited });
+        assert_eq!(resolved(auto.0, auto.1, FrameFormat::MJPEG, 1080), YuvColorSpace::JFIF);
+        assert_eq!(resolved(YuvMatrix::Bt601, YuvRange::Full, FrameFormat::NV12, 1080), YuvColorSpace { matrix: YuvMatrix::Bt601, range: YuvRange::Full });
+    }
+
+    #[test]
+    fn computes_the_usual_coefficients() {
+        // The integer coefficients of nokhwa, and of most BT.601 converters
+        let bt601 = YuvColorSpace { matrix: YuvMatrix::Bt601, range: YuvRange::Limited }.coefficients();
+        assert_eq!(bt601, YuvCoefficients { luma_offset: 16, luma: 298, red_v: 409, green_u: 100, green_v: 208, blue_u: 516 });
+
+        let bt709 = YuvColorSpace { matrix: YuvMatrix::Bt709, range: YuvRange::Limited }.coefficients();
+        assert_eq!(bt709, YuvCoefficients { luma_offset: 16, luma: 298, red_v: 459, green_u: 55, green_v: 136, blue_u: 541 });
+
+        let full = YuvColorSpace::JFIF.coefficients();
+        assert_eq!((full.luma_offset, full.luma, full.red_v, full.blue_u), (0, 256, 359, 454));
+    }
+
+    #[test]
+    fn converts_the_range_extremes() {
+        let limited = YuvColorSpace { matrix: YuvMatrix::Bt709, range: YuvRange::Limited }.coefficients();
+        assert_eq!(limited.to_rgb(16, 128, 128), [0, 0, 0]);
+        assert_eq!(limited.to_rgb(235, 128, 128), [255, 255, 255]);
+        assert_eq!(limited.gray(126), 128);
+
+        let full = YuvColorSpace::JFIF.coefficients();
+        assert_eq!(full.to_rgb(0, 128, 128), [0, 0, 0]);
+        assert_eq!(full.to_rgb(255, 128, 128), [255, 255, 255]);
+        assert_eq!(full.gray(126), 126);
+    }
+}
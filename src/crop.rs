@@ -0,0 +1,153 @@
+use crate::color_space::YuvColorSpace;
+use crate::mjpeg;
+use crate::output_format::OutputFormat;
+use nokhwa::utils::{CameraFormat, FrameFormat, Resolution};
+use nokhwa::{Buffer, NokhwaError};
+
+/// Rectangle of the camera frames kept by the grab functions and the frame callback, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CropRect {
+    /// Checks that the rectangle fits in frames of `format` and starts and ends on the pixel pairs sharing chroma
+    /// in YUV formats: even columns for YUYV, even columns and rows for NV12.
+    pub fn validate(&self, format: &CameraFormat) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 {
+            return Err(format!("The crop rectangle {}x{} is empty", self.width, self.height));
+        }
+
+        if !self.fits(format.resolution()) {
+            return Err(format!("The crop rectangle {} does not fit in {}x{} frames", self, format.width(), format.height()));
+        }
+
+        let (even_columns, even_rows) = match format.format() {
+            FrameFormat::YUYV => (true, false),
+            FrameFormat::NV12 => (true, true),
+            _ => (false, false),
+        };
+
+        let odd = |value: u32| !value.is_multiple_of(2);
+        if (even_columns && (odd(self.x) || odd(self.width))) || (even_rows && (odd(self.y) || odd(self.height))) {
+            return Err(format!("The crop rectangle {} must be aligned on pixel pairs in {} frames", self, format.format()));
+        }
+
+        Ok(())
+    }
+
+    fn fits(&self, resolution: Resolution) -> bool {
+        self.x as u64 + self.width as u64 <= resolution.width() as u64
+            && self.y as u64 + self.height as u64 <= resolution.height() as u64
+    }
+}
+
+impl std::fmt::Display for CropRect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}+{}+{}", self.width, self.height, self.x, self.y)
+    }
+}
+
+/// Copies the rectangle out of a frame, so only its pixels are converted. The format is kept, except for MJPEG
+/// frames which have to be decoded whole, with the resolved `color_space`, and are returned as RGB.
+pub fn crop_frame(frame: &Buffer, rect: CropRect, color_space: YuvColorSpace) -> Result<Buffer, NokhwaError> {
+    let resolution = frame.resolution();
+    let source_format = frame.source_frame_format();
+    let crop_error = |error: String| NokhwaError::ProcessFrameError {
+        src: source_format,
+        destination: format!("crop {}", rect),
+        error,
+    };
+
+    if !rect.fits(resolution) {
+        return Err(crop_error(format!("The frame is {}x{}", resolution.width(), resolution.height())));
+    }
+
+    let width = resolution.width() as usize;
+    let height = resolution.height() as usize;
+    let (x, y) = (rect.x as usize, rect.y as usize);
+    let (crop_width, crop_height) = (rect.width as usize, rect.height as usize);
+    let mut output = vec![];
+
+    let copied = match source_format {
+        FrameFormat::GRAY => copy_rect(frame.buffer(), width, x, y, crop_width, crop_height, &mut output),
+        FrameFormat::RAWRGB | FrameFormat::RAWBGR => copy_rect(frame.buffer(), width * 3, x * 3, y, crop_width * 3, crop_height, &mut output),
+        FrameFormat::YUYV => copy_rect(frame.buffer(), width * 2, x * 2, y, crop_width * 2, crop_height, &mut output),
+        FrameFormat::NV12 => {
+            // Chroma rows hold a U and V pair for every two pixels, at half the height
+            let chroma_stride = width.div_ceil(2) * 2;
+            let chroma = frame.buffer().get(width * height..).unwrap_or_default();
+
+            copy_rect(frame.buffer(), width, x, y, crop_width, crop_height, &mut output)
+                .and_then(|_| copy_rect(chroma, chroma_stride, x / 2 * 2, y / 2, crop_width.div_ceil(2) * 2, crop_height.div_ceil(2), &mut output))
+        }
+        FrameFormat::MJPEG => {
+            let mut rgb = vec![0u8; width * 3 * height];
+            mjpeg::decode(frame, 8, OutputFormat::Rgb, color_space, width * 3, &mut rgb)?;
+            let copied = copy_rect(&rgb, width * 3, x * 3, y, crop_width * 3, crop_height, &mut output);
+
+            return copied
+                .map(|_| Buffer::new(Resolution::new(rect.width, rect.height), &output, FrameFormat::RAWRGB))
+                .ok_or_else(|| crop_error("The decoded frame is smaller than its resolution".to_string()));
+        }
+    };
+
+    copied
+        .map(|_| Buffer::new(Resolution::new(rect.width, rect.height), &output, source_format))
+        .ok_or_else(|| crop_error("The frame is smaller than its resolution".to_string()))
+}
+
+// Appends `rows` rows of `row_bytes` bytes, starting `x_bytes` into row `y` of an image of rows `stride` bytes apart
+fn copy_rect(src: &[u8], stride: usize, x_bytes: usize, y: usize, row_bytes: usize, rows: usize, output: &mut Vec<u8>) -> Option<()> {
+    for row in y..y + rows {
+        let start = row * stride + x_bytes;
+        output.extend_from_slice(src.get(start..start + row_bytes)?);
+    }
+
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> CropRect {
+        CropRect { x, y, width, height }
+    }
+
+    #[test]
+    fn validates_against_the_format() {
+        let yuyv = CameraFormat::new(Resolution::new(8, 4), FrameFormat::YUYV, 30);
+        let nv12 = CameraFormat::new(Resolution::new(8, 4), FrameFormat::NV12, 30);
+
+        assert!(rect(2, 1, 4, 3).validate(&yuyv).is_ok());
+        assert!(rect(1, 1, 4, 3).validate(&yuyv).is_err());
+        assert!(rect(2, 1, 4, 3).validate(&nv12).is_err());
+        assert!(rect(2, 2, 6, 2).validate(&nv12).is_ok());
+        assert!(rect(2, 2, 8, 2).validate(&nv12).is_err());
+        assert!(rect(0, 0, 0, 4).validate(&nv12).is_err());
+    }
+
+    #[test]
+    fn crops_packed_frames() {
+        let gray: Vec<u8> = (0..12).collect();
+        let frame = Buffer::new(Resolution::new(4, 3), &gray, FrameFormat::GRAY);
+        let cropped = crop_frame(&frame, rect(1, 1, 2, 2), YuvColorSpace::default()).unwrap();
+
+        assert_eq!(cropped.resolution(), Resolution::new(2, 2));
+        assert_eq!(cropped.buffer(), &[5, 6, 9, 10]);
+        assert!(crop_frame(&frame, rect(3, 0, 2, 1), YuvColorSpace::default()).is_err());
+    }
+
+    #[test]
+    fn crops_both_nv12_planes() {
+        let nv12: Vec<u8> = (0..16).chain(100..108).collect();
+        let frame = Buffer::new(Resolution::new(4, 4), &nv12, FrameFormat::NV12);
+        let cropped = crop_frame(&frame, rect(2, 2, 2, 2), YuvColorSpace::default()).unwrap();
+
+        assert_eq!(cropped.buffer(), &[10, 11, 14, 15, 106, 107]);
+    }
+}
@@ -0,0 +1,99 @@
+use parking_lot::Mutex;
+use std::sync::LazyLock;
+
+/// Device arguments below this value are positional indices, values from it on are handles.
+pub const DEVICE_HANDLE_BASE: u32 = 0x4000_0000;
+
+// Unique ids by handle offset. Handles are never reused, so they stay valid across re-initializations
+static HANDLES: LazyLock<Mutex<Vec<String>>> = LazyLock::new(Default::default);
+
+pub fn is_handle(device: u32) -> bool {
+    device >= DEVICE_HANDLE_BASE
+}
+
+/// Returns the handle of a unique id, assigning a new one the first time the unique id is seen.
+pub fn handle_for(unique_id: &str) -> u32 {
+    let mut handles = HANDLES.lock();
+
+    let position = match handles.iter().position(|id| id == unique_id) {
+        Some(position) => position,
+        None => {
+            handles.push(unique_id.to_string());
+            handles.len() - 1
+        }
+    };
+
+    DEVICE_HANDLE_BASE + position as u32
+}
+
+/// Returns the unique id a handle was assigned to, `None` if the handle was never assigned.
+pub fn unique_id(handle: u32) -> Option<String> {
+    let position = handle.checked_sub(DEVICE_HANDLE_BASE)? as usize;
+
+    HANDLES.lock().get(position).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{State, VideoDevice, ERROR_DEVICE_DISCONNECTED, ERROR_DEVICE_NOT_FOUND};
+    use nokhwa::utils::CameraIndex;
+    use std::collections::HashMap;
+
+    fn device(index: u32, unique_id: &str) -> VideoDevice {
+        VideoDevice {
+            index: CameraIndex::Index(index),
+            handle: handle_for(unique_id),
+            unique_id: unique_id.to_string(),
+            model_id: "Test".to_string(),
+            name: unique_id.to_string(),
+            formats: vec![],
+        }
+    }
+
+    fn state(devices: Vec<VideoDevice>) -> State {
+        State { devices, camera_sessions: HashMap::new(), device_controls: HashMap::new() }
+    }
+
+    #[test]
+    fn assigns_one_handle_per_unique_id() {
+        let front = handle_for("handles:front");
+        let back = handle_for("handles:back");
+
+        assert!(is_handle(front) && is_handle(back));
+        assert_ne!(front, back);
+        assert_eq!(handle_for("handles:front"), front);
+        assert_eq!(unique_id(front).as_deref(), Some("handles:front"));
+        assert_eq!(unique_id(DEVICE_HANDLE_BASE - 1), None);
+        assert_eq!(unique_id(u32::MAX), None);
+    }
+
+    #[test]
+    fn resolves_handles_across_device_lists() {
+        let front = device(0, "lists:front");
+        let back = device(1, "lists:back");
+        let listed = state(vec![front.clone(), back.clone()]);
+        assert_eq!(listed.device(front.handle).unwrap().unique_id, "lists:front");
+        assert_eq!(listed.device(back.handle).unwrap().unique_id, "lists:back");
+
+        // Initializing again lists the devices anew, in another order and with a device plugged in between
+        let relisted = state(vec![device(0, "lists:usb"), device(1, "lists:back"), device(2, "lists:front")]);
+        assert_eq!(relisted.device(front.handle).unwrap().unique_id, "lists:front");
+        assert_eq!(relisted.device(back.handle).unwrap().unique_id, "lists:back");
+
+        // Arguments below the base are positions in the current list
+        assert_eq!(relisted.device(0).unwrap().unique_id, "lists:usb");
+        assert_eq!(relisted.device(2).unwrap().unique_id, "lists:front");
+        assert_eq!(relisted.device(3).unwrap_err(), ERROR_DEVICE_NOT_FOUND);
+        assert_eq!(relisted.device(DEVICE_HANDLE_BASE - 1).unwrap_err(), ERROR_DEVICE_NOT_FOUND);
+    }
+
+    #[test]
+    fn reports_vanished_devices_as_disconnected() {
+        let front = device(0, "vanished:front");
+        let unplugged = state(vec![device(0, "vanished:back")]);
+
+        assert_eq!(unplugged.device(front.handle).unwrap_err(), ERROR_DEVICE_DISCONNECTED);
+        assert_eq!(unplugged.device(DEVICE_HANDLE_BASE + 0x0fff_ffff).unwrap_err(), ERROR_DEVICE_NOT_FOUND);
+    }
+}
@@ -0,0 +1,31 @@
+use std::os::raw::c_void;
+
+/// Frame handed to a registered frame callback. The data pointer is only valid during the callback.
+#[repr(C)]
+pub struct CnokhwaFrame {
+    pub data: *const u8,
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    pub format: i32,
+    pub timestamp_us: u64,
+    pub sequence: u64,
+    pub wall_clock_us: u64,
+}
+
+pub type FrameCallbackFn = extern "C" fn(user_data: *mut c_void, frame: *const CnokhwaFrame);
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCallback {
+    pub func: FrameCallbackFn,
+    pub user_data: *mut c_void,
+}
+
+// The user data pointer is opaque to us, it's the caller's responsibility to make it usable from the capture thread
+unsafe impl Send for FrameCallback {}
+
+impl FrameCallback {
+    pub fn invoke(&self, frame: &CnokhwaFrame) {
+        (self.func)(self.user_data, frame);
+    }
+}
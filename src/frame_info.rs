@@ -0,0 +1,32 @@
+use std::sync::LazyLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Reference point for monotonic frame timestamps
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Capture metadata of a frame, as exposed through the C API.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// Starts at 1 for the first frame of a session and increases by one for every frame received.
+    pub sequence: u64,
+    /// Monotonic capture time in microseconds, only meaningful relative to other timestamps.
+    pub timestamp_us: u64,
+    /// Capture time in microseconds since the Unix epoch.
+    pub wall_clock_us: u64,
+}
+
+impl FrameInfo {
+    pub fn captured_now(sequence: u64) -> FrameInfo {
+        let wall_clock_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        FrameInfo {
+            sequence,
+            timestamp_us: EPOCH.elapsed().as_micros() as u64,
+            wall_clock_us,
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::avi::AviFile;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+/// A JPEG frame kept in the ring, shared with the dumps writing it.
+#[derive(Clone)]
+pub struct RingFrame {
+    pub timestamp_us: u64,
+    pub jpeg: Arc<[u8]>,
+}
+
+/// How much history a ring keeps. A zero limit is unlimited, but at least one limit must be set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RingLimits {
+    pub max_duration_us: u64,
+    pub max_bytes: usize,
+}
+
+impl RingLimits {
+    pub fn is_bounded(&self) -> bool {
+        self.max_duration_us > 0 || self.max_bytes > 0
+    }
+}
+
+/// The most recent compressed frames of a session, oldest first.
+/// The newest frame is always kept, even when it is larger than the byte limit on its own.
+pub struct FrameRing {
+    limits: RingLimits,
+    frames: VecDeque<RingFrame>,
+    bytes: usize,
+    // Dumps still recording the frames after the event
+    followers: Vec<Sender<RingFrame>>,
+}
+
+impl FrameRing {
+    pub fn new(limits: RingLimits) -> FrameRing {
+        FrameRing { limits, frames: VecDeque::new(), bytes: 0, followers: vec![] }
+    }
+
+    /// Changes the limits, dropping the frames that no longer fit.
+    pub fn set_limits(&mut self, limits: RingLimits) {
+        self.limits = limits;
+        self.evict();
+    }
+
+    pub fn push(&mut self, frame: RingFrame) {
+        self.followers.retain(|follower| follower.send(frame.clone()).is_ok());
+
+        self.bytes += frame.jpeg.len();
+        self.frames.push_back(frame);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        let Some(newest) = self.frames.back().map(|f| f.timestamp_us) else { return };
+
+        while self.frames.len() > 1 {
+            let oldest = &self.frames[0];
+            let too_old = self.limits.max_duration_us > 0 && newest - oldest.timestamp_us > self.limits.max_duration_us;
+            let too_big = self.limits.max_bytes > 0 && self.bytes > self.limits.max_bytes;
+            if !too_old && !too_big {
+                break;
+            }
+
+            if let Some(frame) = self.frames.pop_front() {
+                self.bytes -= frame.jpeg.len();
+            }
+        }
+    }
+
+    /// The buffered frames, oldest first.
+    pub fn history(&self) -> Vec<RingFrame> {
+        self.frames.iter().cloned().collect()
+    }
+
+    /// Receives every frame pushed from now on, until the receiver or the ring is dropped.
+    pub fn follow(&mut self) -> Receiver<RingFrame> {
+        let (sender, receiver) = mpsc::channel();
+        self.followers.push(sender);
+        receiver
+    }
+}
+
+/// Writes the history of a ring to a video recording. When `follow` is given, the frames received until its
+/// deadline are appended from a background thread, which finishes the file and is returned; otherwise the file is
+/// finished right away.
+pub fn dump(mut writer: AviFile, history: Vec<RingFrame>, follow: Option<(Receiver<RingFrame>, Instant)>) -> io::Result<Option<JoinHandle<()>>> {
+    for frame in &history {
+        writer.write_frame(frame.timestamp_us, &frame.jpeg)?;
+    }
+
+    let Some((frames, deadline)) = follow else { return writer.finish().map(|_| None) };
+
+    let thread = std::thread::spawn(move || {
+        // Ends at the deadline, or earlier when the ring is dropped with its session
+        while let Ok(frame) = frames.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            if let Err(err) = writer.write_frame(frame.timestamp_us, &frame.jpeg) {
+                eprintln!("Error writing ring dump, finishing it early: {}", err);
+                break;
+            }
+        }
+
+        if let Err(err) = writer.finish() {
+            eprintln!("Error finishing ring dump: {}", err);
+        }
+    });
+
+    Ok(Some(thread))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::temp_path;
+    use nokhwa::utils::Resolution;
+
+    // Frame count of the main header of a finished AVI file
+    fn avi_frames(path: &std::path::Path) -> u32 {
+        let avi = std::fs::read(path).unwrap();
+        assert_eq!(u32::from_le_bytes(avi[4..8].try_into().unwrap()) as usize, avi.len() - 8);
+        u32::from_le_bytes(avi[48..52].try_into().unwrap())
+    }
+
+    fn frame(timestamp_us: u64, size: usize) -> RingFrame {
+        RingFrame { timestamp_us, jpeg: vec![0u8; size].into() }
+    }
+
+    fn timestamps(ring: &FrameRing) -> Vec<u64> {
+        ring.history().iter().map(|f| f.timestamp_us).collect()
+    }
+
+    #[test]
+    fn keeps_the_configured_duration() {
+        let mut ring = FrameRing::new(RingLimits { max_duration_us: 100, max_bytes: 0 });
+        for timestamp in [0, 50, 100, 150, 200] {
+            ring.push(frame(timestamp, 10));
+        }
+
+        assert_eq!(timestamps(&ring), vec![100, 150, 200]);
+    }
+
+    #[test]
+    fn keeps_the_configured_size_and_the_newest_frame() {
+        let mut ring = FrameRing::new(RingLimits { max_duration_us: 0, max_bytes: 25 });
+        for timestamp in 0..4 {
+            ring.push(frame(timestamp, 10));
+        }
+        assert_eq!(timestamps(&ring), vec![2, 3]);
+
+        ring.push(frame(4, 40));
+        assert_eq!(timestamps(&ring), vec![4]);
+
+        ring.set_limits(RingLimits { max_duration_us: 0, max_bytes: 10 });
+        assert_eq!(timestamps(&ring), vec![4]);
+    }
+
+    #[test]
+    fn followers_receive_new_frames_until_dropped() {
+        let mut ring = FrameRing::new(RingLimits { max_duration_us: 100, max_bytes: 0 });
+        ring.push(frame(0, 1));
+
+        let follower = ring.follow();
+        ring.push(frame(1, 1));
+        assert_eq!(follower.try_recv().unwrap().timestamp_us, 1);
+
+        drop(follower);
+        ring.push(frame(2, 1));
+        assert!(ring.followers.is_empty());
+    }
+
+    #[test]
+    fn dumps_follow_the_ring_until_it_is_dropped() {
+        let mut ring = FrameRing::new(RingLimits { max_duration_us: 1_000_000, max_bytes: 0 });
+        for timestamp in [0, 100_000] {
+            ring.push(frame(timestamp, 3));
+        }
+
+        let file = temp_path("ring-follow", "avi");
+        let writer = AviFile::create(&file, Resolution::new(64, 48), 10).unwrap();
+        let deadline = Instant::now() + std::time::Duration::from_secs(60);
+        let thread = dump(writer, ring.history(), Some((ring.follow(), deadline))).unwrap().unwrap();
+
+        for timestamp in [200_000, 300_000] {
+            ring.push(frame(timestamp, 3));
+        }
+        drop(ring);
+        thread.join().unwrap();
+
+        assert_eq!(avi_frames(&file), 4);
+        let _ = std::fs::remove_file(&file);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{network_camera, replay, virtual_camera};
+use nokhwa::utils::{CameraControl, CameraFormat, CameraIndex, ControlValueSetter, KnownCameraControl, RequestedFormat};
+use nokhwa::{native_api_backend, Buffer, CallbackCamera, Camera, NokhwaError};
+
+/// Receives every frame of a source, called from the thread the source captures on.
+pub type FrameSink = Box<dyn FnMut(Buffer) + Send>;
+
+/// Something sessions can capture frames from. Sessions only talk to their input through this trait,
+/// so other inputs and test doubles can be plugged into the same session machinery and C API.
+///
+/// Sources of devices are opened by `open`, the only place telling the kinds of devices apart; other sources are
+/// handed to a session when it starts. Sources don't keep frames: the session keeps the latest one its sink got.
+pub trait FrameSource: Send {
+    /// Every format the source can deliver.
+    fn formats(&mut self) -> Result<Vec<CameraFormat>, NokhwaError>;
+
+    /// The format frames are delivered in.
+    fn format(&self) -> Result<CameraFormat, NokhwaError>;
+
+    /// Starts delivering frames to `sink`, sessions keep track of the latest one.
+    fn start(&mut self, sink: FrameSink) -> Result<(), NokhwaError>;
+
+    /// Stops delivering frames. Once it returns the sink is dropped and won't be called again, even when stopping
+    /// the input itself failed.
+    fn stop(&mut self) -> Result<(), NokhwaError>;
+
+    fn controls(&self) -> Result<Vec<CameraControl>, NokhwaError>;
+
+    fn control(&self, control: KnownCameraControl) -> Result<CameraControl, NokhwaError>;
+
+    fn set_control(&mut self, control: KnownCameraControl, value: ControlValueSetter) -> Result<(), NokhwaError>;
+}
+
+/// Opens the source of a device index: the native backend, the virtual camera backend for virtual devices,
+/// a raw stream file for replay devices or an HTTP stream for network devices.
+pub fn open(index: &CameraIndex, format: RequestedFormat) -> Result<Box<dyn FrameSource>, NokhwaError> {
+    if replay::is_replay(index) {
+        return replay::open(index, format);
+    }
+
+    if network_camera::is_network(index) {
+        return network_camera::open(index, format);
+    }
+
+    let camera = if virtual_camera::is_virtual(index) {
+        virtual_camera::open(index, format)?
+    } else {
+        let Some(backend) = native_api_backend()
+        else { return Err(NokhwaError::GeneralError("Error creating native API backend".to_string())) };
+
+        Camera::with_backend(index.clone(), format, backend)?
+    };
+
+    // Frames are dropped until the source is started
+    let camera = CallbackCamera::with_custom(camera, |_| {});
+
+    Ok(Box::new(CameraSource { camera }))
+}
+
+/// A nokhwa camera, capturing on the thread of its `CallbackCamera`.
+struct CameraSource {
+    camera: CallbackCamera,
+}
+
+impl FrameSource for CameraSource {
+    fn formats(&mut self) -> Result<Vec<CameraFormat>, NokhwaError> {
+        let mut formats = vec![];
+
+        for fourcc in self.camera.compatible_fourcc()? {
+            for (resolution, frame_rates) in self.camera.compatible_list_by_resolution(fourcc)? {
+                for frame_rate in frame_rates {
+                    formats.push(CameraFormat::new(resolution, fourcc, frame_rate));
+                }
+            }
+        }
+
+        Ok(formats)
+    }
+
+    fn format(&self) -> Result<CameraFormat, NokhwaError> {
+        self.camera.camera_format()
+    }
+
+    fn start(&mut self, sink: FrameSink) -> Result<(), NokhwaError> {
+        self.camera.set_callback(sink)?;
+        self.camera.open_stream()
+    }
+
+    fn stop(&mut self) -> Result<(), NokhwaError> {
+        let stopped = self.camera.stop_stream();
+
+        // The thread of the camera only ends when it is dropped, replacing the sink waits for a call in progress
+        self.camera.set_callback(|_| {})?;
+
+        stopped
+    }
+
+    fn controls(&self) -> Result<Vec<CameraControl>, NokhwaError> {
+        self.camera.camera_controls()
+    }
+
+    fn control(&self, control: KnownCameraControl) -> Result<CameraControl, NokhwaError> {
+        self.camera.camera_control(control)
+    }
+
+    fn set_control(&mut self, control: KnownCameraControl, value: ControlValueSetter) -> Result<(), NokhwaError> {
+        self.camera.set_camera_control(control, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw_stream::{RawStreamFile, RawStreamHeader};
+    use crate::test_device::{pattern_frames, temp_path, ManualSource, WAIT_MS};
+    use crate::virtual_camera::VirtualDevice;
+    use nokhwa::utils::{FrameFormat, RequestedFormatType, Resolution};
+    use std::sync::mpsc::{channel, TryRecvError};
+    use std::time::Duration;
+
+    // Runs `source` through the trait, `deliver` making it deliver a frame when it doesn't on its own
+    fn capture_and_stop(mut source: Box<dyn FrameSource>, deliver: impl Fn()) {
+        let format = source.format().unwrap();
+        assert!(source.formats().unwrap().contains(&format));
+
+        let (sender, receiver) = channel();
+        source.start(Box::new(move |buffer| {
+            let _ = sender.send(buffer.resolution());
+        })).unwrap();
+
+        deliver();
+        let resolution = receiver.recv_timeout(Duration::from_millis(WAIT_MS as u64)).unwrap();
+        assert_eq!(resolution, format.resolution());
+
+        source.stop().unwrap();
+        deliver();
+
+        // The sink, with the sender it holds, is gone once stopped, frames delivered before may still be queued
+        loop {
+            match receiver.try_recv() {
+                Ok(_) => continue,
+                Err(err) => break assert_eq!(err, TryRecvError::Disconnected),
+            }
+        }
+    }
+
+    fn requested(format: CameraFormat) -> RequestedFormat<'static> {
+        RequestedFormat::new::<nokhwa::pixel_format::RgbFormat>(RequestedFormatType::Exact(format))
+    }
+
+    #[test]
+    fn stops_test_doubles() {
+        let format = CameraFormat::new(Resolution::new(64, 48), FrameFormat::YUYV, 30);
+        let (source, feed) = ManualSource::new(format);
+
+        let frame = pattern_frames(64, 48, FrameFormat::YUYV, 1).remove(0);
+        capture_and_stop(Box::new(source), || {
+            feed.push(frame.clone());
+        });
+        assert!(!feed.push(frame));
+    }
+
+    #[test]
+    fn stops_cameras() {
+        let format = CameraFormat::new(Resolution::new(64, 48), FrameFormat::YUYV, 30);
+        let device = VirtualDevice { name: "Stopped".to_string(), formats: vec![format] };
+        let camera = virtual_camera::open_device(0, device, requested(format)).unwrap();
+
+        capture_and_stop(Box::new(CameraSource { camera: CallbackCamera::with_custom(camera, |_| {}) }), || {});
+    }
+
+    #[test]
+    fn stops_replays() {
+        let path = temp_path("stopped-replay", "raw");
+        let header = RawStreamHeader { name: "stopped-replay".to_string(), frame_rate: 100 };
+        let mut writer = RawStreamFile::create(&path, &header).unwrap();
+        for (n, frame) in pattern_frames(64, 48, FrameFormat::YUYV, 2).iter().enumerate() {
+            writer.write_frame(n as u64 * 10_000, frame).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let info = replay::add_file(&path).unwrap();
+        let format = CameraFormat::new(Resolution::new(64, 48), FrameFormat::YUYV, 100);
+        capture_and_stop(replay::open(info.index(), requested(format)).unwrap(), || {});
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
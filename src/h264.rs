@@ -0,0 +1,108 @@
+use crate::i420::I420Frame;
+use crate::mp4::Mp4Writer;
+use nokhwa::utils::Resolution;
+use openh264::encoder::{BitRate, Encoder, EncoderConfig, FrameRate, RateControlMode};
+use openh264::formats::YUVBuffer;
+use openh264::OpenH264API;
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::path::Path;
+
+/// File format of H.264 recordings. The discriminants are the values used by the C API.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum H264Container {
+    /// Raw Annex-B elementary stream, as written by the encoder
+    AnnexB = 0,
+    /// Fragmented MP4
+    Mp4 = 1,
+}
+
+impl H264Container {
+    pub fn from_code(code: i32) -> Option<H264Container> {
+        match code {
+            0 => Some(H264Container::AnnexB),
+            1 => Some(H264Container::Mp4),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct H264Settings {
+    pub container: H264Container,
+    pub bitrate_bps: u32,
+    /// Frames from one keyframe to the next
+    pub gop_frames: u32,
+    pub frame_rate: u32,
+}
+
+enum H264Output {
+    AnnexB(BufWriter<File>),
+    Mp4(Mp4Writer<BufWriter<File>>),
+}
+
+/// Encodes frames with OpenH264 into a file.
+pub struct H264Recording {
+    encoder: Encoder,
+    output: H264Output,
+    gop_frames: u64,
+    // Size of the recording, rounded down to even dimensions like I420 frames
+    size: (usize, usize),
+    frames: u64,
+}
+
+impl H264Recording {
+    /// Creates the file, overwriting any existing one.
+    pub fn create(path: &Path, resolution: Resolution, settings: H264Settings) -> io::Result<H264Recording> {
+        let config = EncoderConfig::new()
+            .bitrate(BitRate::from_bps(settings.bitrate_bps))
+            .max_frame_rate(FrameRate::from_hz(settings.frame_rate.max(1) as f32))
+            .rate_control_mode(RateControlMode::Bitrate);
+
+        let encoder = Encoder::with_api_config(OpenH264API::from_source(), config)
+            .map_err(|e| io::Error::other(format!("Error creating H.264 encoder: {}", e)))?;
+
+        let size = (resolution.width() as usize & !1, resolution.height() as usize & !1);
+        let file = BufWriter::new(File::create(path)?);
+        let output = match settings.container {
+            H264Container::AnnexB => H264Output::AnnexB(file),
+            H264Container::Mp4 => H264Output::Mp4(Mp4Writer::new(file, size.0 as u32, size.1 as u32, settings.frame_rate)),
+        };
+
+        Ok(H264Recording { encoder, output, gop_frames: settings.gop_frames.max(1) as u64, size, frames: 0 })
+    }
+
+    pub fn write_frame(&mut self, timestamp_us: u64, frame: I420Frame) -> io::Result<()> {
+        if (frame.width, frame.height) != self.size {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "The frame size changed during the recording"));
+        }
+
+        // Keyframes are forced here rather than configured, so every group of pictures has the same length
+        if self.frames.is_multiple_of(self.gop_frames) {
+            self.encoder.force_intra_frame();
+        }
+        self.frames += 1;
+
+        let yuv = YUVBuffer::from_vec(frame.data, frame.width, frame.height);
+        let bitstream = self.encoder.encode(&yuv)
+            .map_err(|e| io::Error::other(format!("Error encoding H.264 frame: {}", e)))?;
+        let annex_b = bitstream.to_vec();
+
+        // Frames skipped by the rate control produce no data
+        if annex_b.is_empty() {
+            return Ok(());
+        }
+
+        match &mut self.output {
+            H264Output::AnnexB(file) => file.write_all(&annex_b),
+            H264Output::Mp4(writer) => writer.write_frame(timestamp_us, &annex_b),
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        match self.output {
+            H264Output::AnnexB(mut file) => file.flush(),
+            H264Output::Mp4(writer) => writer.finish(),
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::os::raw::c_void;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::LazyLock;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+pub const DEVICE_EVENT_ADDED: i32 = 1;
+pub const DEVICE_EVENT_REMOVED: i32 = 2;
+
+// Oldest events are dropped when nobody polls the queue
+const MAX_QUEUED_EVENTS: usize = 256;
+
+// Shorter intervals are raised to this, a zero interval would make the watcher spin
+const MIN_SCAN_INTERVAL: Duration = Duration::from_millis(100);
+
+// Devices that can't be opened are retried after this delay, doubled on every failure up to the maximum
+const FIRST_PROBE_RETRY: Duration = Duration::from_secs(1);
+const MAX_PROBE_RETRY: Duration = Duration::from_secs(60);
+
+/// A device that appeared or disappeared, identified by its handle.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceEvent {
+    pub kind: i32,
+    pub handle: u32,
+}
+
+pub type DeviceEventCallbackFn = extern "C" fn(user_data: *mut c_void, event: *const DeviceEvent);
+
+#[derive(Clone, Copy)]
+struct DeviceEventCallback {
+    func: DeviceEventCallbackFn,
+    user_data: *mut c_void,
+}
+
+// The user data pointer is opaque to us, it's the caller's responsibility to make it usable from the watcher thread
+unsafe impl Send for DeviceEventCallback {}
+
+struct Watcher {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+static EVENTS: LazyLock<Mutex<VecDeque<DeviceEvent>>> = LazyLock::new(Default::default);
+static EVENT_CALLBACK: LazyLock<Mutex<Option<DeviceEventCallback>>> = LazyLock::new(Default::default);
+static WATCHER: LazyLock<Mutex<Option<Watcher>>> = LazyLock::new(Default::default);
+
+/// Devices the watcher failed to open, keyed by unique id, so busy or broken cameras aren't reopened on every scan.
+#[derive(Default)]
+pub struct FailedProbes {
+    retries: HashMap<String, (u32, Instant)>,
+}
+
+impl FailedProbes {
+    pub fn should_probe(&self, unique_id: &str, now: Instant) -> bool {
+        self.retries.get(unique_id).is_none_or(|&(_, retry_at)| now >= retry_at)
+    }
+
+    pub fn record(&mut self, unique_id: &str, probed: bool, now: Instant) {
+        if probed {
+            self.retries.remove(unique_id);
+            return;
+        }
+
+        let failures = self.retries.get(unique_id).map_or(0, |&(failures, _)| failures) + 1;
+        let delay = FIRST_PROBE_RETRY.saturating_mul(1 << (failures - 1).min(16)).min(MAX_PROBE_RETRY);
+
+        self.retries.insert(unique_id.to_string(), (failures, now + delay));
+    }
+
+    /// Forgets the devices that are no longer connected, so they are probed right away when plugged back.
+    pub fn retain_connected(&mut self, is_connected: impl Fn(&str) -> bool) {
+        self.retries.retain(|unique_id, _| is_connected(unique_id));
+    }
+}
+
+/// Starts a thread calling `scan` every `interval` (at least `MIN_SCAN_INTERVAL`) and publishing the events it
+/// returns. Returns false if the watcher is already running.
+pub fn start_watcher(interval: Duration, mut scan: impl FnMut() -> Vec<DeviceEvent> + Send + 'static) -> bool {
+    let mut watcher = WATCHER.lock();
+
+    if watcher.is_some() {
+        return false;
+    }
+
+    let interval = interval.max(MIN_SCAN_INTERVAL);
+    let (stop, stop_receiver) = channel::<()>();
+
+    let thread = std::thread::spawn(move || loop {
+        publish(scan());
+
+        match stop_receiver.recv_timeout(interval) {
+            Err(RecvTimeoutError::Timeout) => continue,
+            _ => break,
+        }
+    });
+
+    *watcher = Some(Watcher { stop, thread });
+
+    true
+}
+
+/// Stops the watcher thread and waits for it to finish. Returns false if it was not running.
+pub fn stop_watcher() -> bool {
+    let Some(watcher) = WATCHER.lock().take() else { return false };
+
+    let _ = watcher.stop.send(());
+    let _ = watcher.thread.join();
+
+    true
+}
+
+/// Replaces the callback, once a call in progress has returned.
+pub fn set_event_callback(callback: Option<DeviceEventCallbackFn>, user_data: *mut c_void) {
+    *EVENT_CALLBACK.lock() = callback.map(|func| DeviceEventCallback { func, user_data });
+}
+
+pub fn poll_event() -> Option<DeviceEvent> {
+    EVENTS.lock().pop_front()
+}
+
+fn publish(events: Vec<DeviceEvent>) {
+    if events.is_empty() {
+        return;
+    }
+
+    {
+        let mut queue = EVENTS.lock();
+        for event in &events {
+            if queue.len() == MAX_QUEUED_EVENTS {
+                queue.pop_front();
+            }
+            queue.push_back(*event);
+        }
+    }
+
+    // Held until the callback returns, so removing the callback waits for a call in progress
+    let callback = EVENT_CALLBACK.lock();
+    if let Some(callback) = *callback {
+        for event in &events {
+            (callback.func)(callback.user_data, event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_devices_failing_to_open() {
+        let start = Instant::now();
+        let mut failed = FailedProbes::default();
+        assert!(failed.should_probe("cam", start));
+
+        failed.record("cam", false, start);
+        assert!(!failed.should_probe("cam", start + Duration::from_millis(500)));
+        assert!(failed.should_probe("cam", start + FIRST_PROBE_RETRY));
+
+        failed.record("cam", false, start);
+        assert!(!failed.should_probe("cam", start + FIRST_PROBE_RETRY));
+        assert!(failed.should_probe("cam", start + FIRST_PROBE_RETRY * 2));
+
+        for _ in 0..20 {
+            failed.record("cam", false, start);
+        }
+        assert!(failed.should_probe("cam", start + MAX_PROBE_RETRY));
+
+        failed.retain_connected(|_| false);
+        assert!(failed.should_probe("cam", start));
+
+        failed.record("cam", false, start);
+        failed.record("cam", true, start);
+        assert!(failed.should_probe("cam", start));
+    }
+
+    #[derive(Default)]
+    struct Probe {
+        entered: Mutex<bool>,
+        entered_changed: parking_lot::Condvar,
+        busy: std::sync::atomic::AtomicBool,
+    }
+
+    extern "C" fn slow_callback(user_data: *mut c_void, _event: *const DeviceEvent) {
+        let probe = unsafe { &*(user_data as *const Probe) };
+        probe.busy.store(true, std::sync::atomic::Ordering::SeqCst);
+        *probe.entered.lock() = true;
+        probe.entered_changed.notify_all();
+
+        std::thread::sleep(Duration::from_millis(50));
+        probe.busy.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    #[test]
+    fn removing_the_callback_waits_for_a_call_in_progress() {
+        let probe: &'static Probe = Box::leak(Box::default());
+        set_event_callback(Some(slow_callback), probe as *const Probe as *mut c_void);
+
+        let event = DeviceEvent { kind: DEVICE_EVENT_ADDED, handle: 7 };
+        let publisher = std::thread::spawn(move || publish(vec![event]));
+
+        let mut entered = probe.entered.lock();
+        while !*entered {
+            probe.entered_changed.wait(&mut entered);
+        }
+        drop(entered);
+
+        set_event_callback(None, std::ptr::null_mut());
+        assert!(!probe.busy.load(std::sync::atomic::Ordering::SeqCst));
+
+        publisher.join().unwrap();
+        assert_eq!(poll_event(), Some(event));
+    }
+}
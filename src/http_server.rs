@@ -0,0 +1,409 @@
+use crate::color_space::YuvColorSpace;
+use crate::session::{CapturedFrame, SessionShared, WaitResult};
+use crate::video_format::VideoFormat;
+use nokhwa::NokhwaError;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, LazyLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const BOUNDARY: &str = "cnokhwa-frame";
+
+// How often the accept loop checks for a stop request, and streams for the server being stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const STREAM_WAIT: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Every connection has its own thread, and streams encode every frame for themselves
+const MAX_CONNECTIONS: usize = 8;
+
+const MAX_REQUEST_LINES: usize = 100;
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+
+/// A device as seen by the server, with its session when it's capturing.
+pub struct ServedDevice {
+    pub handle: u32,
+    pub unique_id: String,
+    pub name: String,
+    pub model_id: String,
+    pub formats: Vec<VideoFormat>,
+    pub session: Option<Arc<SessionShared>>,
+}
+
+/// Lists the devices to serve, called for every request.
+pub type DeviceLister = fn() -> Vec<ServedDevice>;
+
+/// Encodes a frame as JPEG with the color space of its session, passing through frames that already are.
+pub type JpegEncoder = fn(&CapturedFrame, YuvColorSpace) -> Result<Vec<u8>, NokhwaError>;
+
+#[derive(Clone, Copy)]
+struct Routes {
+    devices: DeviceLister,
+    encode_jpeg: JpegEncoder,
+}
+
+struct Server {
+    address: SocketAddr,
+    stop: Sender<()>,
+    // Tells open streams to end
+    stopping: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+#[derive(Serialize)]
+struct DeviceJson<'a> {
+    handle: u32,
+    unique_id: &'a str,
+    name: &'a str,
+    model_id: &'a str,
+    capturing: bool,
+    formats: Vec<FormatJson>,
+}
+
+#[derive(Serialize)]
+struct FormatJson {
+    width: u32,
+    height: u32,
+    format: String,
+    frame_rate: u32,
+}
+
+static SERVER: LazyLock<Mutex<Option<Server>>> = LazyLock::new(Default::default);
+
+/// Starts serving on `port` of the interface with address `ip` (0 picks a free port), returning the address bound.
+/// Returns `Ok(None)` if the server is already running.
+pub fn start_server(ip: IpAddr, port: u16, devices: DeviceLister, encode_jpeg: JpegEncoder) -> io::Result<Option<SocketAddr>> {
+    let mut server = SERVER.lock();
+
+    if server.is_some() {
+        return Ok(None);
+    }
+
+    let listener = TcpListener::bind((ip, port))?;
+    listener.set_nonblocking(true)?;
+    let address = listener.local_addr()?;
+
+    let (stop, stop_receiver) = channel::<()>();
+    let stopping = Arc::new(AtomicBool::new(false));
+    let routes = Routes { devices, encode_jpeg };
+
+    let thread_stopping = stopping.clone();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let thread = std::thread::spawn(move || loop {
+        match listener.accept() {
+            Ok((connection, _)) => {
+                // Connections over the limit are closed right away, answering them would take a thread too
+                if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+
+                let stopping = thread_stopping.clone();
+                let connections = connections.clone();
+                // Streams last as long as the client watches, every connection gets its own thread
+                std::thread::spawn(move || {
+                    let result = handle_connection(connection, routes, &stopping);
+                    connections.fetch_sub(1, Ordering::SeqCst);
+
+                    if let Err(err) = result {
+                        if !matches!(err.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset) {
+                            eprintln!("Error serving HTTP request: {}", err);
+                        }
+                    }
+                });
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                match stop_receiver.recv_timeout(POLL_INTERVAL) {
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    _ => break,
+                }
+            }
+            Err(err) => eprintln!("Error accepting HTTP connection: {}", err),
+        }
+    });
+
+    *server = Some(Server { address, stop, stopping, thread });
+
+    Ok(Some(address))
+}
+
+/// Stops accepting connections and ends open streams. Returns false if the server was not running.
+pub fn stop_server() -> bool {
+    let Some(server) = SERVER.lock().take() else { return false };
+
+    server.stopping.store(true, Ordering::SeqCst);
+    let _ = server.stop.send(());
+    let _ = server.thread.join();
+
+    true
+}
+
+/// Address the server is listening on, `None` if it's not running.
+pub fn server_address() -> Option<SocketAddr> {
+    SERVER.lock().as_ref().map(|s| s.address)
+}
+
+fn handle_connection(connection: TcpStream, routes: Routes, stopping: &AtomicBool) -> io::Result<()> {
+    // Accepted sockets may inherit the non-blocking mode of the listener
+    connection.set_nonblocking(false)?;
+    connection.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut reader = BufReader::new(connection.try_clone()?);
+    let mut output = connection;
+
+    let request_line = read_line(&mut reader)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+
+    // Request headers are not used, but must be read before answering
+    for _ in 0..MAX_REQUEST_LINES {
+        if read_line(&mut reader)?.trim().is_empty() {
+            break;
+        }
+    }
+
+    if method != "GET" {
+        return respond_error(&mut output, "405 Method Not Allowed", "Only GET is supported");
+    }
+
+    // Query strings are ignored, some viewers append one to defeat caching
+    let path = path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match segments.as_slice() {
+        ["devices"] => respond(&mut output, "200 OK", "application/json", &devices_json(&(routes.devices)())),
+        ["device", id, "snapshot.jpg"] => {
+            let session = match device_session(routes, id) {
+                Ok(session) => session,
+                Err((status, message)) => return respond_error(&mut output, status, &message),
+            };
+
+            let Some(frame) = session.latest_frame()
+            else { return respond_error(&mut output, "503 Service Unavailable", "No frame captured yet") };
+
+            let color_space = session.settings.lock().color_space;
+            match (routes.encode_jpeg)(&frame, color_space) {
+                Ok(jpeg) => respond(&mut output, "200 OK", "image/jpeg", &jpeg),
+                Err(err) => respond_error(&mut output, "500 Internal Server Error", &err.to_string()),
+            }
+        }
+        ["device", id, "stream.mjpg"] => {
+            match device_session(routes, id) {
+                Ok(session) => stream(&mut output, &session, routes.encode_jpeg, stopping),
+                Err((status, message)) => respond_error(&mut output, status, &message),
+            }
+        }
+        _ => respond_error(&mut output, "404 Not Found", "Unknown path"),
+    }
+}
+
+// Devices are identified by their handle
+fn device_session(routes: Routes, id: &str) -> Result<Arc<SessionShared>, (&'static str, String)> {
+    let not_found = || ("404 Not Found", format!("Unknown device {}", id));
+
+    let handle = id.parse::<u32>().map_err(|_| not_found())?;
+    let device = (routes.devices)().into_iter().find(|d| d.handle == handle).ok_or_else(not_found)?;
+
+    device.session.ok_or_else(|| ("404 Not Found", format!("Device {} is not capturing", id)))
+}
+
+fn devices_json(devices: &[ServedDevice]) -> Vec<u8> {
+    let devices: Vec<DeviceJson> = devices.iter()
+        .map(|device| DeviceJson {
+            handle: device.handle,
+            unique_id: &device.unique_id,
+            name: &device.name,
+            model_id: &device.model_id,
+            capturing: device.session.is_some(),
+            formats: device.formats.iter()
+                .map(|format| FormatJson {
+                    width: format.width,
+                    height: format.height,
+                    format: format.format.to_string(),
+                    frame_rate: format.frame_rate,
+                })
+                .collect(),
+        })
+        .collect();
+
+    serde_json::to_vec_pretty(&devices).unwrap_or_default()
+}
+
+// Sends every new frame of the session until it stops, the client goes away or the server is stopped
+fn stream(output: &mut TcpStream, session: &SessionShared, encode_jpeg: JpegEncoder, stopping: &AtomicBool) -> io::Result<()> {
+    write!(
+        output,
+        "HTTP/1.0 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        BOUNDARY
+    )?;
+
+    let mut sequence = 0;
+
+    while !stopping.load(Ordering::SeqCst) {
+        match session.wait_frame(sequence, STREAM_WAIT) {
+            WaitResult::NewFrame => {}
+            WaitResult::TimedOut => continue,
+            WaitResult::Stopped | WaitResult::DeviceLost => break,
+        }
+
+        let Some(frame) = session.latest_frame() else { continue };
+        sequence = frame.info.sequence;
+
+        let color_space = session.settings.lock().color_space;
+        let jpeg = match encode_jpeg(&frame, color_space) {
+            Ok(jpeg) => jpeg,
+            Err(err) => {
+                eprintln!("Error encoding frame for HTTP stream: {}", err);
+                continue;
+            }
+        };
+
+        write!(output, "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n", BOUNDARY, jpeg.len())?;
+        output.write_all(&jpeg)?;
+        output.write_all(b"\r\n")?;
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+fn respond(output: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        output,
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    output.write_all(body)?;
+    output.flush()
+}
+
+fn respond_error(output: &mut TcpStream, status: &str, message: &str) -> io::Result<()> {
+    respond(output, status, "text/plain; charset=utf-8", message.as_bytes())
+}
+
+fn read_line(reader: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line = vec![];
+    reader.take(MAX_LINE_LENGTH).read_until(b'\n', &mut line)?;
+
+    Ok(String::from_utf8_lossy(&line).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network_camera::HttpUrl;
+    use nokhwa::utils::{FrameFormat, Resolution};
+    use nokhwa::Buffer;
+    use std::net::Ipv4Addr;
+
+    static SESSION: LazyLock<Arc<SessionShared>> = LazyLock::new(Default::default);
+
+    fn devices() -> Vec<ServedDevice> {
+        vec![
+            ServedDevice {
+                handle: 0x4000_0007,
+                unique_id: "test-camera".to_string(),
+                name: "Kiosk".to_string(),
+                model_id: "Test".to_string(),
+                formats: vec![VideoFormat { index: 0, width: 4, height: 2, format: FrameFormat::MJPEG, frame_rate: 30 }],
+                session: Some(SESSION.clone()),
+            },
+            ServedDevice {
+                handle: 0x4000_0008,
+                unique_id: "idle-camera".to_string(),
+                name: "Idle".to_string(),
+                model_id: "Test".to_string(),
+                formats: vec![],
+                session: None,
+            },
+        ]
+    }
+
+    // Tags frames with their sequence so the test can tell them apart
+    fn encode(frame: &CapturedFrame, _color_space: YuvColorSpace) -> Result<Vec<u8>, NokhwaError> {
+        let mut jpeg = frame.buffer.buffer().to_vec();
+        jpeg.push(frame.info.sequence as u8);
+        Ok(jpeg)
+    }
+
+    fn get(address: SocketAddr, path: &str) -> (String, Vec<u8>) {
+        let mut connection = TcpStream::connect(("127.0.0.1", address.port())).unwrap();
+        write!(connection, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+
+        let mut response = vec![];
+        connection.read_to_end(&mut response).unwrap();
+
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..split]).to_string();
+
+        (head, response[split + 4..].to_vec())
+    }
+
+    #[test]
+    fn serves_devices_snapshots_and_streams() {
+        let address = start_server(Ipv4Addr::LOCALHOST.into(), 0, devices, encode).unwrap().unwrap();
+        assert_eq!(server_address(), Some(address));
+        assert!(address.ip().is_loopback());
+        assert!(start_server(Ipv4Addr::LOCALHOST.into(), 0, devices, encode).unwrap().is_none());
+
+        let (head, body) = get(address, "/devices");
+        assert!(head.starts_with("HTTP/1.0 200"));
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json[0]["handle"], 0x4000_0007);
+        assert_eq!(json[0]["capturing"], true);
+        assert_eq!(json[0]["formats"][0]["format"], "MJPEG");
+        assert_eq!(json[1]["capturing"], false);
+
+        assert!(get(address, "/device/1073741831/snapshot.jpg").0.starts_with("HTTP/1.0 503"));
+        assert!(get(address, "/device/1073741832/snapshot.jpg").0.starts_with("HTTP/1.0 404"));
+        assert!(get(address, "/device/12/stream.mjpg").0.starts_with("HTTP/1.0 404"));
+        assert!(get(address, "/nothing").0.starts_with("HTTP/1.0 404"));
+
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xD9];
+        SESSION.record_frame(Buffer::new(Resolution::new(4, 2), &jpeg, FrameFormat::MJPEG));
+
+        let (head, body) = get(address, "/device/1073741831/snapshot.jpg?t=1");
+        assert!(head.contains("Content-Type: image/jpeg"));
+        assert_eq!(body, [0xFF, 0xD8, 0xFF, 0xD9, 1]);
+
+        // Streams are read back with the network camera client
+        let feeder = std::thread::spawn(|| {
+            for _ in 0..20 {
+                std::thread::sleep(Duration::from_millis(20));
+                SESSION.record_frame(Buffer::new(Resolution::new(4, 2), &[0xFF, 0xD8, 0xFF, 0xD9], FrameFormat::MJPEG));
+            }
+        });
+
+        let url = HttpUrl::parse(&format!("http://127.0.0.1:{}/device/1073741831/stream.mjpg", address.port())).unwrap();
+        let mut stream = crate::network_camera::MjpegStream::connect(&url).unwrap();
+        let first = stream.next_frame().unwrap();
+        let second = stream.next_frame().unwrap();
+        assert_eq!(&first[..4], &jpeg);
+        assert!(second[4] > first[4]);
+
+        feeder.join().unwrap();
+
+        // Connections over the limit are closed without an answer
+        let streams: Vec<_> = (1..MAX_CONNECTIONS).map(|_| crate::network_camera::MjpegStream::connect(&url).unwrap()).collect();
+        let mut refused = TcpStream::connect(("127.0.0.1", address.port())).unwrap();
+        let _ = write!(refused, "GET /devices HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let mut response = vec![];
+        let _ = refused.read_to_end(&mut response);
+        assert!(response.is_empty());
+        drop(streams);
+
+        assert!(stop_server());
+        assert!(!stop_server());
+        assert_eq!(server_address(), None);
+
+        // Open streams end with the server, once the frames already sent are read
+        assert!((0..30).any(|_| stream.next_frame().is_err()));
+    }
+}
@@ -0,0 +1,255 @@
+use crate::color_space::{YuvColorSpace, YuvRange};
+use nokhwa::utils::Resolution;
+
+/// A frame in planar 4:2:0 YUV, the input of the H.264 encoder. YUV frames keep the values of the camera, RGB ones
+/// are converted with the color space they are given.
+/// Dimensions are even, frames with an odd width or height lose their last column or row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct I420Frame {
+    pub width: usize,
+    pub height: usize,
+    // Y plane followed by the U and V planes, without padding
+    pub data: Vec<u8>,
+}
+
+impl I420Frame {
+    fn with_size(resolution: Resolution) -> I420Frame {
+        let width = resolution.width() as usize & !1;
+        let height = resolution.height() as usize & !1;
+
+        I420Frame { width, height, data: vec![0u8; width * height * 3 / 2] }
+    }
+
+    fn planes_mut(&mut self) -> (&mut [u8], &mut [u8], &mut [u8]) {
+        let luma = self.width * self.height;
+        let (y, chroma) = self.data.split_at_mut(luma);
+        let (u, v) = chroma.split_at_mut(luma / 4);
+
+        (y, u, v)
+    }
+
+    /// Packed YUYV, where every pair of pixels shares its chroma. Chroma of row pairs is averaged.
+    pub fn from_yuyv(yuyv: &[u8], resolution: Resolution) -> Option<I420Frame> {
+        let src_stride = resolution.width() as usize * 2;
+        if yuyv.len() < src_stride * resolution.height() as usize {
+            return None;
+        }
+
+        let mut frame = I420Frame::with_size(resolution);
+        let (width, height) = (frame.width, frame.height);
+        let (y, u, v) = frame.planes_mut();
+
+        for row in 0..height {
+            let src = &yuyv[row * src_stride..];
+            for col in 0..width {
+                y[row * width + col] = src[col * 2];
+            }
+        }
+
+        for row in 0..height / 2 {
+            let top = &yuyv[row * 2 * src_stride..];
+            let bottom = &yuyv[(row * 2 + 1) * src_stride..];
+            for col in 0..width / 2 {
+                let i = row * width / 2 + col;
+                u[i] = average(top[col * 4 + 1], bottom[col * 4 + 1]);
+                v[i] = average(top[col * 4 + 3], bottom[col * 4 + 3]);
+            }
+        }
+
+        Some(frame)
+    }
+
+    /// NV12, a Y plane followed by interleaved U and V samples.
+    pub fn from_nv12(nv12: &[u8], resolution: Resolution) -> Option<I420Frame> {
+        let src_width = resolution.width() as usize;
+        let src_height = resolution.height() as usize;
+        // Chroma rows hold a U and V pair for every two pixels, rounded up
+        let chroma_stride = src_width.div_ceil(2) * 2;
+        if nv12.len() < src_width * src_height + chroma_stride * src_height.div_ceil(2) {
+            return None;
+        }
+
+        let mut frame = I420Frame::with_size(resolution);
+        let (width, height) = (frame.width, frame.height);
+        let (y, u, v) = frame.planes_mut();
+
+        for row in 0..height {
+            y[row * width..(row + 1) * width].copy_from_slice(&nv12[row * src_width..row * src_width + width]);
+        }
+
+        let chroma = &nv12[src_width * src_height..];
+        for row in 0..height / 2 {
+            for col in 0..width / 2 {
+                let i = row * width / 2 + col;
+                u[i] = chroma[row * chroma_stride + col * 2];
+                v[i] = chroma[row * chroma_stride + col * 2 + 1];
+            }
+        }
+
+        Some(frame)
+    }
+
+    /// Packed RGB, converted with a resolved color space and with the chroma of every 2x2 block averaged.
+    pub fn from_rgb(rgb: &[u8], resolution: Resolution, color_space: YuvColorSpace) -> Option<I420Frame> {
+        let src_stride = resolution.width() as usize * 3;
+        if rgb.len() < src_stride * resolution.height() as usize {
+            return None;
+        }
+
+        let mut frame = I420Frame::with_size(resolution);
+        let (width, height) = (frame.width, frame.height);
+        let (y, u, v) = frame.planes_mut();
+        let coefficients = RgbCoefficients::new(color_space);
+
+        let pixel = |row: usize, col: usize| {
+            let i = row * src_stride + col * 3;
+            (rgb[i] as i32, rgb[i + 1] as i32, rgb[i + 2] as i32)
+        };
+
+        for row in 0..height {
+            for col in 0..width {
+                y[row * width + col] = coefficients.luma(pixel(row, col));
+            }
+        }
+
+        for row in 0..height / 2 {
+            for col in 0..width / 2 {
+                let (mut r, mut g, mut b) = (0, 0, 0);
+                for (dy, dx) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                    let (pr, pg, pb) = pixel(row * 2 + dy, col * 2 + dx);
+                    r += pr;
+                    g += pg;
+                    b += pb;
+                }
+                let average = ((r + 2) / 4, (g + 2) / 4, (b + 2) / 4);
+
+                let i = row * width / 2 + col;
+                (u[i], v[i]) = coefficients.chroma(average);
+            }
+        }
+
+        Some(frame)
+    }
+}
+
+/// RGB to YUV in fixed point with 8 fractional bits, the inverse of `YuvCoefficients`:
+/// y = luma_offset + y_r r + y_g g + y_b b, u = 128 + u_r r + u_g g + u_b b and v = 128 + v_r r + v_g g + v_b b
+struct RgbCoefficients {
+    luma_offset: i32,
+    y: [i32; 3],
+    u: [i32; 3],
+    v: [i32; 3],
+}
+
+impl RgbCoefficients {
+    fn new(color_space: YuvColorSpace) -> RgbCoefficients {
+        let (kr, kb) = color_space.matrix.luma_weights();
+        let kg = 1.0 - kr - kb;
+
+        let (luma_offset, luma_scale, chroma_scale) = match color_space.range {
+            YuvRange::Full => (0, 1.0, 1.0),
+            YuvRange::Limited | YuvRange::Auto => (16, 219.0 / 255.0, 224.0 / 255.0),
+        };
+        let fixed = |weights: [f64; 3], scale: f64| weights.map(|w| (w * scale * 256.0).round() as i32);
+
+        RgbCoefficients {
+            luma_offset,
+            y: fixed([kr, kg, kb], luma_scale),
+            u: fixed([-kr / (2.0 * (1.0 - kb)), -kg / (2.0 * (1.0 - kb)), 0.5], chroma_scale),
+            v: fixed([0.5, -kg / (2.0 * (1.0 - kr)), -kb / (2.0 * (1.0 - kr))], chroma_scale),
+        }
+    }
+
+    fn luma(&self, rgb: (i32, i32, i32)) -> u8 {
+        (self.luma_offset + weigh(self.y, rgb)).clamp(0, 255) as u8
+    }
+
+    fn chroma(&self, rgb: (i32, i32, i32)) -> (u8, u8) {
+        ((128 + weigh(self.u, rgb)).clamp(0, 255) as u8, (128 + weigh(self.v, rgb)).clamp(0, 255) as u8)
+    }
+}
+
+fn weigh([wr, wg, wb]: [i32; 3], (r, g, b): (i32, i32, i32)) -> i32 {
+    (wr * r + wg * g + wb * b + 128) >> 8
+}
+
+fn average(a: u8, b: u8) -> u8 {
+    (a as u16 + b as u16).div_ceil(2) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color_space::YuvMatrix;
+
+    #[test]
+    fn converts_yuyv() {
+        // Two rows of four pixels
+        let yuyv = [
+            10, 100, 11, 200, 12, 50, 13, 60,
+            20, 102, 21, 202, 22, 52, 23, 62,
+        ];
+        let frame = I420Frame::from_yuyv(&yuyv, Resolution::new(4, 2)).unwrap();
+
+        assert_eq!(frame.data, vec![10, 11, 12, 13, 20, 21, 22, 23, 101, 51, 201, 61]);
+    }
+
+    #[test]
+    fn converts_nv12_and_crops_odd_sizes() {
+        // 3x3 luma, chroma rows of two U/V pairs
+        let nv12 = [
+            1, 2, 3,
+            4, 5, 6,
+            7, 8, 9,
+            100, 200, 101, 201,
+            102, 202, 103, 203,
+        ];
+        let frame = I420Frame::from_nv12(&nv12, Resolution::new(3, 3)).unwrap();
+
+        assert_eq!((frame.width, frame.height), (2, 2));
+        assert_eq!(frame.data, vec![1, 2, 4, 5, 100, 200]);
+    }
+
+    const BT601: YuvColorSpace = YuvColorSpace { matrix: YuvMatrix::Bt601, range: YuvRange::Limited };
+    const BT709: YuvColorSpace = YuvColorSpace { matrix: YuvMatrix::Bt709, range: YuvRange::Limited };
+
+    #[test]
+    fn converts_rgb_with_limited_range() {
+        let white_and_black: Vec<u8> = [[255u8; 3], [255; 3], [0; 3], [0; 3]].concat();
+
+        for color_space in [BT601, BT709] {
+            let frame = I420Frame::from_rgb(&white_and_black, Resolution::new(2, 2), color_space).unwrap();
+            assert_eq!(&frame.data[..4], &[235, 235, 16, 16]);
+            assert_eq!(&frame.data[4..], &[128, 128]);
+        }
+
+        let frame = I420Frame::from_rgb(&white_and_black, Resolution::new(2, 2), YuvColorSpace::JFIF).unwrap();
+        assert_eq!(&frame.data[..4], &[255, 255, 0, 0]);
+    }
+
+    #[test]
+    fn converts_rgb_with_the_matrix_it_is_given() {
+        // The integer coefficients of most BT.601 converters
+        let coefficients = RgbCoefficients::new(BT601);
+        assert_eq!((coefficients.y, coefficients.u, coefficients.v), ([66, 129, 25], [-38, -74, 112], [112, -94, -18]));
+
+        // Converted back with the same color space, colors come out as they went in
+        let orange = [200u8, 100, 50];
+        for color_space in [BT601, BT709, YuvColorSpace::JFIF] {
+            let frame = I420Frame::from_rgb(&orange.repeat(4), Resolution::new(2, 2), color_space).unwrap();
+            let back = color_space.coefficients().to_rgb(frame.data[0], frame.data[4], frame.data[5]);
+            assert!(back.iter().zip(orange).all(|(a, b)| a.abs_diff(b) <= 2), "{:?}: {:?}", color_space, back);
+        }
+
+        let bt601 = I420Frame::from_rgb(&orange.repeat(4), Resolution::new(2, 2), BT601).unwrap();
+        let bt709 = I420Frame::from_rgb(&orange.repeat(4), Resolution::new(2, 2), BT709).unwrap();
+        assert_ne!(bt601.data, bt709.data);
+    }
+
+    #[test]
+    fn rejects_short_buffers() {
+        assert!(I420Frame::from_yuyv(&[0; 7], Resolution::new(2, 2)).is_none());
+        assert!(I420Frame::from_nv12(&[0; 5], Resolution::new(2, 2)).is_none());
+        assert!(I420Frame::from_rgb(&[0; 11], Resolution::new(2, 2), BT601).is_none());
+    }
+}
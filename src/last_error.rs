@@ -0,0 +1,123 @@
+use nokhwa::NokhwaError;
+use std::cell::RefCell;
+use std::fmt::Display;
+
+/// Details of the last failing call made from the current thread.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastError {
+    pub code: i32,
+    pub message: String,
+    /// Variant name of the underlying `NokhwaError`, if any
+    pub kind: Option<String>,
+    pub os_error: Option<i32>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<LastError>> = const { RefCell::new(None) };
+}
+
+/// Records an error for the current thread and returns its code, so it can be used as `return record_error(..)`.
+pub fn record_error(code: i32, message: impl Into<String>) -> i32 {
+    store(LastError {
+        code,
+        message: message.into(),
+        kind: None,
+        os_error: None,
+    })
+}
+
+/// Same as `record_error` but keeping the details of the `NokhwaError` that caused it.
+pub fn record_nokhwa_error(code: i32, context: impl Display, error: &NokhwaError) -> i32 {
+    let message = error.to_string();
+
+    store(LastError {
+        code,
+        os_error: os_error(&message),
+        message: format!("{}: {}", context, message),
+        kind: Some(nokhwa_error_kind(error).to_string()),
+    })
+}
+
+/// Same as `record_error` but keeping the OS error number of an IO error.
+pub fn record_io_error(code: i32, context: impl Display, error: &std::io::Error) -> i32 {
+    store(LastError {
+        code,
+        message: format!("{}: {}", context, error),
+        kind: None,
+        os_error: error.raw_os_error(),
+    })
+}
+
+pub fn last_error() -> Option<LastError> {
+    LAST_ERROR.with(|e| e.borrow().clone())
+}
+
+fn store(error: LastError) -> i32 {
+    let code = error.code;
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(error));
+    code
+}
+
+fn nokhwa_error_kind(error: &NokhwaError) -> &'static str {
+    match error {
+        NokhwaError::UnitializedError => "UnitializedError",
+        NokhwaError::InitializeError { .. } => "InitializeError",
+        NokhwaError::ShutdownError { .. } => "ShutdownError",
+        NokhwaError::GeneralError(_) => "GeneralError",
+        NokhwaError::StructureError { .. } => "StructureError",
+        NokhwaError::OpenDeviceError(_, _) => "OpenDeviceError",
+        NokhwaError::GetPropertyError { .. } => "GetPropertyError",
+        NokhwaError::SetPropertyError { .. } => "SetPropertyError",
+        NokhwaError::OpenStreamError(_) => "OpenStreamError",
+        NokhwaError::ReadFrameError(_) => "ReadFrameError",
+        NokhwaError::ProcessFrameError { .. } => "ProcessFrameError",
+        NokhwaError::StreamShutdownError(_) => "StreamShutdownError",
+        NokhwaError::UnsupportedOperationError(_) => "UnsupportedOperationError",
+        NokhwaError::NotImplementedError(_) => "NotImplementedError",
+    }
+}
+
+// Backends only keep the text of OS errors, which std formats as "... (os error N)"
+fn os_error(message: &str) -> Option<i32> {
+    let start = message.rfind("(os error ")? + "(os error ".len();
+    let end = start + message[start..].find(')')?;
+
+    message[start..end].trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_os_errors_from_messages() {
+        assert_eq!(os_error("Device or resource busy (os error 16)"), Some(16));
+        assert_eq!(os_error("VIDIOC_S_FMT (os error 5): retried (os error 16)"), Some(16));
+        assert_eq!(os_error("No such device"), None);
+        assert_eq!(os_error("(os error busy)"), None);
+        assert_eq!(os_error("truncated (os error 16"), None);
+
+        let error = NokhwaError::OpenDeviceError("/dev/video0".to_string(), "Device or resource busy (os error 16)".to_string());
+        assert_eq!(record_nokhwa_error(-5, "Error opening device 0", &error), -5);
+        let recorded = last_error().unwrap();
+        assert_eq!((recorded.os_error, recorded.kind.as_deref()), (Some(16), Some("OpenDeviceError")));
+        assert!(recorded.message.starts_with("Error opening device 0: "), "{}", recorded.message);
+
+        record_io_error(-7, "Error saving profile", &std::io::Error::from_raw_os_error(13));
+        assert_eq!(last_error().unwrap().os_error, Some(13));
+    }
+
+    #[test]
+    fn keeps_errors_per_thread() {
+        record_error(-3, "main thread");
+
+        std::thread::spawn(|| {
+            assert_eq!(last_error(), None);
+            record_error(-4, "other thread");
+            assert_eq!(last_error().unwrap().code, -4);
+        }).join().unwrap();
+
+        let recorded = last_error().unwrap();
+        assert_eq!((recorded.code, recorded.message.as_str(), recorded.kind, recorded.os_error), (-3, "main thread", None, None));
+    }
+}
@@ -1,24 +1,83 @@
 mod video_format;
 mod video_device;
+mod output_format;
+mod frame_callback;
+mod frame_info;
+mod session;
+mod camera_control;
+mod profile;
+mod device_handle;
+mod hotplug;
+mod last_error;
+mod virtual_camera;
+mod frame_source;
+mod raw_stream;
+mod replay;
+mod network_camera;
+mod http_server;
+mod snapshot;
+mod avi;
+mod frame_ring;
+mod resize;
+mod crop;
+mod orientation;
+mod yuyv;
+mod mjpeg;
+mod color_space;
+#[cfg(feature = "h264")]
+mod i420;
+#[cfg(feature = "h264")]
+mod mp4;
+#[cfg(feature = "h264")]
+mod h264;
+#[cfg(test)]
+mod test_device;
 
 use dcv_color_primitives::{convert_image, ColorSpace, ImageFormat, PixelFormat};
 use nokhwa::error::NokhwaError;
 
-use nokhwa::pixel_format::RgbFormat;
+use nokhwa::pixel_format::{RgbAFormat, RgbFormat};
 use nokhwa::{native_api_backend, nokhwa_check, nokhwa_initialize, query, utils::{
-    CameraFormat, CameraIndex,
+    CameraFormat, CameraIndex, CameraInfo,
     RequestedFormat, RequestedFormatType, Resolution,
-}, Buffer, CallbackCamera, Camera};
+}, Buffer};
 use std::collections::{HashMap, HashSet};
 
-use std::os::raw::c_char;
+use std::ffi::CStr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::raw::{c_char, c_void};
+use std::path::{Path, PathBuf};
 use std::ptr;
 
+use crate::avi::AviFile;
+use crate::color_space::{YuvCoefficients, YuvColorSpace, YuvMatrix, YuvRange};
+use crate::crop::CropRect;
+use crate::camera_control::{control_id, known_control, numeric_value, value_setter, ControlInfo, DeviceControl};
+use crate::frame_callback::{CnokhwaFrame, FrameCallback, FrameCallbackFn};
+use crate::frame_info::FrameInfo;
+use crate::frame_ring::{FrameRing, RingLimits};
+use crate::frame_source::FrameSource;
+#[cfg(feature = "h264")]
+use crate::h264::{H264Container, H264Recording, H264Settings};
+#[cfg(feature = "h264")]
+use crate::i420::I420Frame;
+use crate::last_error::{last_error, record_error, record_io_error, record_nokhwa_error};
+use crate::http_server::ServedDevice;
+use crate::hotplug::{DeviceEvent, DeviceEventCallbackFn, FailedProbes, DEVICE_EVENT_ADDED, DEVICE_EVENT_REMOVED};
+use crate::orientation::{Orientation, Rotation};
+use crate::output_format::OutputFormat;
+use crate::profile::{DeviceProfile, ProfileControl, ProfileFormat};
+use crate::raw_stream::{RawStreamFile, RawStreamHeader};
+use crate::resize::{Interpolation, OutputSize, ScaleMode, MAX_OUTPUT_SIDE};
+use crate::snapshot::{PendingSnapshot, SnapshotFormat};
+use crate::session::{CapturedFrame, Session, SessionSettings, SessionShared, WaitResult};
 use crate::video_device::VideoDevice;
 use crate::video_format::VideoFormat;
-use nokhwa::utils::FrameFormat;
+use crate::yuyv::PackedYuv;
+use nokhwa::utils::{CameraControl, ControlValueSetter, FrameFormat, KnownCameraControl, KnownCameraControlFlag};
 use parking_lot::Mutex;
 use std::sync::{Arc, LazyLock, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 
 // This small library exposes nokhwa as a simple C library.
 // Disclaimer: It's literally my first Rust program, so probably it will contain some bad parts!
@@ -38,85 +97,151 @@ static ERROR_READING_FRAME : i32 = -8;
 static ERROR_DECODING_FRAME : i32 = -9;
 static ERROR_BUFFER_NULL : i32 = -10;
 static ERROR_BUFFER_NOT_ENOUGH_CAPACITY : i32 = -11;
+static ERROR_INVALID_OUTPUT_FORMAT : i32 = -12;
+static ERROR_INVALID_STRIDE : i32 = -13;
+static ERROR_TIMEOUT : i32 = -14;
+static ERROR_SESSION_STOPPED : i32 = -15;
+static ERROR_DEVICE_LOST : i32 = -16;
+static ERROR_CONTROL_NOT_FOUND : i32 = -17;
+static ERROR_SETTING_CONTROL : i32 = -18;
+static ERROR_READING_CONTROLS : i32 = -19;
+static ERROR_PROFILES_DISABLED : i32 = -20;
+static ERROR_PROFILE_NOT_FOUND : i32 = -21;
+static ERROR_PROFILE_IO : i32 = -22;
+static ERROR_PROFILE_PARTIALLY_APPLIED : i32 = -23;
+static ERROR_DEVICE_DISCONNECTED : i32 = -24;
+static ERROR_WATCHER_ALREADY_STARTED : i32 = -25;
+static ERROR_WATCHER_NOT_STARTED : i32 = -26;
+static ERROR_RECORDING_IO : i32 = -27;
+static ERROR_RECORDING_ALREADY_STARTED : i32 = -28;
+static ERROR_RECORDING_NOT_STARTED : i32 = -29;
+static ERROR_INVALID_RECORDING : i32 = -30;
+static ERROR_INVALID_URL : i32 = -31;
+static ERROR_NETWORK_DEVICE : i32 = -32;
+static ERROR_HTTP_SERVER_ALREADY_STARTED : i32 = -33;
+static ERROR_HTTP_SERVER_NOT_STARTED : i32 = -34;
+static ERROR_HTTP_SERVER_IO : i32 = -35;
+static ERROR_INVALID_SNAPSHOT_FORMAT : i32 = -36;
+static ERROR_ENCODING_SNAPSHOT : i32 = -37;
+static ERROR_SNAPSHOT_IO : i32 = -38;
+#[cfg(not(feature = "h264"))]
+static ERROR_H264_NOT_AVAILABLE : i32 = -39;
+#[cfg(feature = "h264")]
+static ERROR_INVALID_H264_SETTINGS : i32 = -40;
+static ERROR_RING_NOT_STARTED : i32 = -41;
+static ERROR_INVALID_RING_SIZE : i32 = -42;
+static ERROR_INVALID_OUTPUT_SIZE : i32 = -43;
+static ERROR_INVALID_CROP : i32 = -44;
+static ERROR_INVALID_ORIENTATION : i32 = -45;
+static ERROR_CORRUPT_FRAME : i32 = -46;
+static ERROR_INVALID_COLOR_SPACE : i32 = -47;
 static ERROR_UNKNOWN : i32 = -512;
 
 static STATUS_AUTHORIZED : i32 = 0;
 static STATUS_DENIED : i32 = -1;
 
 
-fn list_devices() -> Result<Vec<VideoDevice>, &'static str> {
-    let backend = match native_api_backend() {
-        Some(b) => b,
-        None => return Err("Error creating native API backend"),
-    };
-
-    let Ok(devices) = query(backend) else { return Err("Error listing devices") };
+fn list_devices() -> Result<Vec<VideoDevice>, NokhwaError> {
+    let devices = query_devices()?;
 
     let mut result: Vec<VideoDevice> = vec![];
     for device in devices {
-        let mut unique_formats: HashSet<VideoFormat> = HashSet::new();
+        if let Some(video_device) = probe_device(&device) {
+            result.push(video_device);
+        }
+    }
 
-        let index = device.index();
-        let requested_format = RequestedFormat::new::<RgbFormat>(RequestedFormatType::None);
+    Ok(result)
+}
 
-        let mut camera = match Camera::with_backend(index.clone(), requested_format, backend.clone()) {
-            Ok(cam) => cam,
-            Err(err) => {
-                eprintln!("Error creating camera for device index {}", index);
-                eprintln!("{:?}", err);
-                continue;
-            }
-        };
+/// Lists the cameras of the native backend, followed by the virtual ones when they are enabled, the replay ones
+/// and the network ones.
+fn query_devices() -> Result<Vec<CameraInfo>, NokhwaError> {
+    let mut virtual_devices = virtual_camera::devices();
+    virtual_devices.extend(replay::devices());
+    virtual_devices.extend(network_camera::devices());
 
-        let camera_formats = match camera.compatible_camera_formats() {
-            Ok(f) => f,
-            Err(err) => {
-                eprintln!("Error listing compatible formats for device index {}", index);
-                eprintln!("{:?}", err);
-                continue;
-            }
-        };
+    let native = match native_api_backend() {
+        Some(backend) => query(backend),
+        None => Err(NokhwaError::GeneralError("Error creating native API backend".to_string())),
+    };
 
-        for (index, format) in camera_formats.iter().enumerate() {
-            let vf = VideoFormat {
-                index,
-                width: format.resolution().width(),
-                height: format.resolution().height(),
-                format: format.format(),
-                frame_rate: format.frame_rate()
-            };
+    match native {
+        Ok(mut devices) => {
+            devices.extend(virtual_devices);
+            Ok(devices)
+        },
+        // Virtual cameras must keep working on machines without any capture backend, like CI boxes
+        Err(_) if !virtual_devices.is_empty() => Ok(virtual_devices),
+        Err(err) => Err(err)
+    }
+}
 
-            unique_formats.insert(vf);
+fn device_unique_id(device: &CameraInfo) -> String {
+    if device.misc().is_empty() { device.description().to_string() } else { device.misc().to_string() }
+}
+
+/// Opens a device to read its formats, `None` if it can't be opened.
+fn probe_device(device: &CameraInfo) -> Option<VideoDevice> {
+    let mut unique_formats: HashSet<VideoFormat> = HashSet::new();
+
+    let index = device.index();
+    let requested_format = RequestedFormat::new::<RgbFormat>(RequestedFormatType::None);
+
+    let mut source = match frame_source::open(index, requested_format) {
+        Ok(s) => s,
+        Err(err) => {
+            eprintln!("Error creating camera for device index {}", index);
+            eprintln!("{:?}", err);
+            return None;
         }
+    };
 
-        let mut formats: Vec<VideoFormat> = unique_formats.iter().cloned().collect();
-        formats.sort();
+    let camera_formats = match source.formats() {
+        Ok(f) => f,
+        Err(err) => {
+            eprintln!("Error listing compatible formats for device index {}", index);
+            eprintln!("{:?}", err);
+            return None;
+        }
+    };
 
-        let model_id = device.description().to_string();
-        let unique_id = if device.misc().is_empty() { device.description().to_string() } else { device.misc().to_string() };
-        let name = device.human_name();
+    for (index, format) in camera_formats.iter().enumerate() {
+        let vf = VideoFormat {
+            index,
+            width: format.resolution().width(),
+            height: format.resolution().height(),
+            format: format.format(),
+            frame_rate: format.frame_rate()
+        };
 
-        result.push(VideoDevice {
-            index: device.index().clone(),
-            model_id,
-            unique_id,
-            name,
-            formats
-        });
+        unique_formats.insert(vf);
     }
 
-    Ok(result)
-}
-
-#[derive(Clone)]
-struct Session {
-    pub camera: Arc<Mutex<CallbackCamera>>
+    let mut formats: Vec<VideoFormat> = unique_formats.iter().cloned().collect();
+    formats.sort();
+
+    let model_id = device.description().to_string();
+    let unique_id = device_unique_id(device);
+    let name = device.human_name();
+
+    Some(VideoDevice {
+        index: device.index().clone(),
+        handle: device_handle::handle_for(&unique_id),
+        model_id,
+        unique_id,
+        name,
+        formats
+    })
 }
 
 #[derive(Clone)]
 struct State {
     pub devices: Vec<VideoDevice>,
-    pub camera_sessions: HashMap<CameraIndex, Session>
+    // Sessions and controls are keyed by device unique id, which survives re-enumeration
+    pub camera_sessions: HashMap<String, Session>,
+    // Snapshot of the controls of each device, taken by cnokhwa_device_controls_count
+    pub device_controls: HashMap<String, Vec<DeviceControl>>
 }
 
 impl State {
@@ -125,6 +250,26 @@ impl State {
 
         read_guard.clone()
     }
+    /// Resolves a device argument of the C API, which can be a positional index or a device handle.
+    fn device(&self, device: u32) -> Result<&VideoDevice, i32> {
+        if !device_handle::is_handle(device) {
+            return self.devices.get(device as usize)
+                .ok_or_else(|| record_error(ERROR_DEVICE_NOT_FOUND, format!("No device at index {}", device)));
+        }
+
+        let Some(unique_id) = device_handle::unique_id(device)
+        else { return Err(record_error(ERROR_DEVICE_NOT_FOUND, format!("Unknown device handle {}", device))) };
+
+        self.devices.iter()
+            .find(|d| d.unique_id == unique_id)
+            .ok_or_else(|| record_error(ERROR_DEVICE_DISCONNECTED, format!("Device {} is disconnected", unique_id)))
+    }
+    fn device_control(&self, device_index: i32, control_index: i32) -> Option<&DeviceControl> {
+        let device = self.device(device_index as u32).ok()?;
+        let controls = self.device_controls.get(&device.unique_id)?;
+
+        controls.get(usize::try_from(control_index).ok()?)
+    }
     pub fn make_current(self) -> Result<(), PoisonError<MutexGuard<'static, Option<State>>>> {
         let mut w = STATE.lock();
         *w = Some(self);
@@ -145,22 +290,16 @@ pub extern "C" fn cnokhwa_initialize() -> i32 {
 
                 let camera_sessions = sessions.unwrap_or(HashMap::new());
 
-                let new_state = State { devices, camera_sessions };
+                let new_state = State { devices, camera_sessions, device_controls: HashMap::new() };
                 let result = new_state.make_current();
 
                 match result {
                     Ok(()) => RESULT_OK,
-                    Err(err) => {
-                        eprintln!("Error setting up new state, {:?}", err);
-                        ERROR_UNKNOWN
-                    }
+                    Err(err) => record_error(ERROR_UNKNOWN, format!("Error setting up new state, {:?}", err))
                 }
             }
         },
-        Err(err) => {
-            eprintln!("Error listing devices: {:?}", err);
-            ERROR_UNKNOWN
-        }
+        Err(err) => record_nokhwa_error(ERROR_UNKNOWN, "Error listing devices", &err)
     }
 }
 
@@ -176,419 +315,2281 @@ pub extern "C" fn cnokhwa_has_videocapture_auth() -> i32 {
     if nokhwa_check() { STATUS_AUTHORIZED } else { STATUS_DENIED }
 }
 
-
+/// Code of the last error recorded by a failing call on the calling thread, `RESULT_OK` if none.
+/// Like `errno`, successful calls don't clear it, so only read it right after a call fails.
 #[no_mangle]
-pub extern "C" fn cnokhwa_devices_count() -> i32 {
-    let Some(state) = State::current() else { return ERROR_STATE_NOT_INITIALIZED };
-
-    state.devices.len() as i32
+pub extern "C" fn cnokhwa_last_error_code() -> i32 {
+    last_error().map(|e| e.code).unwrap_or(RESULT_OK)
 }
 
+/// Copies the message of the last error on the calling thread, including the underlying cause when known.
 #[no_mangle]
-pub extern "C" fn cnokhwa_device_name(device_index: i32, buf: *mut c_char, buf_len: usize) -> usize {
-    let Some(state) = State::current() else { return 0 };
+pub extern "C" fn cnokhwa_last_error_message(buf: *mut c_char, buf_len: usize) -> usize {
+    let message = last_error().map(|e| e.message).unwrap_or_default();
 
-    if device_index < 0 || (device_index as usize) >= state.devices.len() {
-        return 0;
+    unsafe {
+        copy_str(&message, buf, buf_len)
     }
+}
 
-    let name = &state.devices[device_index as usize].name;
+/// Copies the kind of the camera backend error behind the last error (e.g. `OpenDeviceError`), empty if none.
+#[no_mangle]
+pub extern "C" fn cnokhwa_last_error_kind(buf: *mut c_char, buf_len: usize) -> usize {
+    let kind = last_error().and_then(|e| e.kind).unwrap_or_default();
 
     unsafe {
-        copy_str(name, buf, buf_len)
+        copy_str(&kind, buf, buf_len)
     }
 }
 
+/// OS error number behind the last error, 0 if there is none.
 #[no_mangle]
-pub extern "C" fn cnokhwa_device_unique_id(device_index: i32, buf: *mut c_char, buf_len: usize) -> usize {
-    let Some(state) = State::current() else { return 0 };
+pub extern "C" fn cnokhwa_last_error_os_code() -> i32 {
+    last_error().and_then(|e| e.os_error).unwrap_or(0)
+}
 
-    if device_index < 0 || (device_index as usize) >= state.devices.len() {
-        return 0;
-    }
 
-    let unique_id = &state.devices[device_index as usize].unique_id;
+/// Starts a background thread that checks the connected devices every `interval_ms` (at least every 100 ms). New
+/// devices are appended to the device list and removed ones are taken out of it (use handles, positional indices
+/// can shift), without touching running sessions. Changes are reported through `cnokhwa_poll_device_event` and the
+/// event callback. Devices that can't be opened are retried less and less often, up to once a minute.
+#[no_mangle]
+pub extern "C" fn cnokhwa_start_device_watcher(interval_ms: u32) -> i32 {
+    let mut failed_probes = FailedProbes::default();
 
-    unsafe {
-        copy_str(unique_id, buf, buf_len)
+    if hotplug::start_watcher(Duration::from_millis(interval_ms as u64), move || scan_devices(&mut failed_probes)) {
+        RESULT_OK
+    } else {
+        record_error(ERROR_WATCHER_ALREADY_STARTED, "The device watcher is already running")
     }
 }
 
 #[no_mangle]
-pub extern "C" fn cnokhwa_device_model_id(device_index: i32, buf: *mut c_char, buf_len: usize) -> usize {
-    let Some(state) = State::current() else { return 0 };
-
-    if device_index < 0 || (device_index as usize) >= state.devices.len() {
-        return 0;
-    }
-
-    let model_id = &state.devices[device_index as usize].model_id;
-
-    unsafe {
-        copy_str(model_id, buf, buf_len)
+pub extern "C" fn cnokhwa_stop_device_watcher() -> i32 {
+    if hotplug::stop_watcher() {
+        RESULT_OK
+    } else {
+        record_error(ERROR_WATCHER_NOT_STARTED, "The device watcher is not running")
     }
 }
 
+/// Pops the oldest device event into `event`. Returns `RESULT_NO` when there are no pending events.
 #[no_mangle]
-pub extern "C" fn cnokhwa_device_formats_count(device_index: i32) -> i32 {
-    let Some(state) = State::current() else { return ERROR_STATE_NOT_INITIALIZED };
+pub extern "C" fn cnokhwa_poll_device_event(event: *mut DeviceEvent) -> i32 {
+    if event.is_null() {
+        return buffer_null();
+    }
 
-    if device_index < 0 || (device_index as usize) >= state.devices.len() {
-        return ERROR_DEVICE_NOT_FOUND;
+    match hotplug::poll_event() {
+        Some(e) => {
+            unsafe {
+                *event = e;
+            }
+            RESULT_YES
+        },
+        None => RESULT_NO
     }
+}
 
-    let device = &state.devices[device_index as usize];
+/// Registers a function called from the watcher thread for every device event, a null function removes it.
+/// Events are queued for `cnokhwa_poll_device_event` too. The callback must not stop the watcher or change the
+/// callback. Removing the callback waits for a call in progress: once it returns, the callback won't be called
+/// again and `user_data` can be released.
+#[no_mangle]
+pub extern "C" fn cnokhwa_set_device_event_callback(callback: Option<DeviceEventCallbackFn>, user_data: *mut c_void) -> i32 {
+    hotplug::set_event_callback(callback, user_data);
 
-    device.formats.len() as i32
+    RESULT_OK
 }
 
+/// Copies the unique id a handle was assigned to, which also works after the device has been removed.
 #[no_mangle]
-pub extern "C" fn cnokhwa_device_format_width(device_index: i32, format_index: i32) -> u32 {
-    let Some(state) = State::current() else { return 0 };
-
-    if device_index < 0 || (device_index as usize) >= state.devices.len() {
+pub extern "C" fn cnokhwa_handle_unique_id(handle: u32, buf: *mut c_char, buf_len: usize) -> usize {
+    let Some(unique_id) = device_handle::unique_id(handle) else {
+        record_error(ERROR_DEVICE_NOT_FOUND, format!("Unknown device handle {}", handle));
         return 0;
-    }
-
-    let device = &state.devices[device_index as usize];
+    };
 
-    if format_index < 0 || (format_index as usize) >= device.formats.len() {
-        return 0;
+    unsafe {
+        copy_str(&unique_id, buf, buf_len)
     }
-
-    device.formats[format_index as usize].width
 }
 
+/// Lists a raw stream file written by `cnokhwa_start_raw_recording` as a device, returning its handle.
+/// Capturing from it replays the recorded frames in a loop, in their original format and timing.
 #[no_mangle]
-pub extern "C" fn cnokhwa_device_format_height(device_index: i32, format_index: i32) -> u32 {
-    let Some(state) = State::current() else { return 0 };
-
-    if device_index < 0 || (device_index as usize) >= state.devices.len() {
-        return 0;
+pub extern "C" fn cnokhwa_add_replay_device(path: *const c_char) -> i32 {
+    if path.is_null() {
+        return buffer_null();
     }
 
-    let device = &state.devices[device_index as usize];
+    let path = unsafe { CStr::from_ptr(path) };
+    let Ok(path) = path.to_str()
+    else { return record_error(ERROR_INVALID_RECORDING, "The replay file path is not valid UTF-8") };
 
-    if format_index < 0 || (format_index as usize) >= device.formats.len() {
-        return 0;
-    }
+    let info = match replay::add_file(Path::new(path)) {
+        Ok(info) => info,
+        Err(err) => return record_io_error(ERROR_INVALID_RECORDING, format!("Error reading replay file {}", path), &err)
+    };
 
-    device.formats[format_index as usize].height
-}
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
 
-#[no_mangle]
-pub extern "C" fn cnokhwa_device_format_frame_rate(device_index: i32, format_index: i32) -> u32 {
-    let Some(state) = State::current() else { return 0 };
+    let unique_id = device_unique_id(&info);
 
-    if device_index < 0 || (device_index as usize) >= state.devices.len() {
-        return 0;
+    if let Some(device) = state.devices.iter().find(|d| d.unique_id == unique_id) {
+        return device.handle as i32;
     }
 
-    let device = &state.devices[device_index as usize];
+    let Some(device) = probe_device(&info)
+    else { return record_error(ERROR_INVALID_RECORDING, format!("Error opening replay file {}", path)) };
 
-    if format_index < 0 || (format_index as usize) >= device.formats.len() {
-        return 0;
-    }
+    let handle = device.handle as i32;
+    state.devices.push(device);
 
-    device.formats[format_index as usize].frame_rate
+    handle
 }
 
+/// Lists an IP camera streaming MJPEG over HTTP (`multipart/x-mixed-replace`) as a device, returning its handle.
+/// The URL must be `http://[user:password@]host[:port]/path`. The camera is connected to once to read its resolution
+/// and frame rate, which become the only format of the device. `name` may be null to name the device after the host.
+/// Adding the same URL again updates the device name and format. Frames the camera sends at another resolution are
+/// dropped, since sessions and buffers are sized for the format of the device, until the device is added again and
+/// the capture restarted.
 #[no_mangle]
-pub extern "C" fn cnokhwa_device_format_type(
-    device_index: i32,
-    format_index: i32,
-    buf: *mut c_char,
-    buf_len: usize,
-) -> usize {
-    let Some(state) = State::current() else { return 0 };
-
-    if device_index < 0 || (device_index as usize) >= state.devices.len() {
-        return 0;
+pub extern "C" fn cnokhwa_add_network_device(url: *const c_char, name: *const c_char) -> i32 {
+    if url.is_null() {
+        return buffer_null();
     }
 
-    let device = &state.devices[device_index as usize];
-
-    if format_index < 0 || (format_index as usize) >= device.formats.len() {
-        return 0;
-    }
+    let url = unsafe { CStr::from_ptr(url) };
+    let Some(url) = url.to_str().ok().and_then(network_camera::HttpUrl::parse)
+    else { return record_error(ERROR_INVALID_URL, "The URL is not a valid http:// URL") };
 
-    let type_str = &device.formats[format_index as usize].format.to_string();
+    let name = if name.is_null() {
+        None
+    } else {
+        let name = unsafe { CStr::from_ptr(name) };
+        Some(name.to_string_lossy().to_string())
+    };
 
-    unsafe {
-        copy_str(type_str, buf, buf_len)
+    if STATE.lock().is_none() {
+        return state_not_initialized();
     }
-}
 
-#[no_mangle]
-pub extern "C" fn cnokhwa_start_capture(device_index: u32, width: u32, height: u32) -> i32 {
+    // Connects to the camera, without holding the state lock
+    let info = match network_camera::add_device(&url, name) {
+        Ok(info) => info,
+        Err(err) => return record_io_error(ERROR_NETWORK_DEVICE, format!("Error reading network camera {}", url.public()), &err)
+    };
+
     let mut state_guard = STATE.lock();
     let state = match state_guard.as_mut() {
         Some(s) => s,
-        None => return ERROR_STATE_NOT_INITIALIZED
+        None => return state_not_initialized()
     };
 
-    let device = match state.devices.get(device_index as usize) {
-        Some(dev) => dev,
-        None => return ERROR_DEVICE_NOT_FOUND
-    };
+    let Some(device) = probe_device(&info)
+    else { return record_error(ERROR_NETWORK_DEVICE, format!("Error opening network camera {}", url.public())) };
 
-    if state.camera_sessions.get(&device.index).is_some() {
-        return ERROR_SESSION_ALREADY_STARTED;
-    }
+    let handle = device.handle as i32;
 
-    fn format_priority(format: FrameFormat) -> u8 {
-        match format {
-            FrameFormat::RAWRGB | FrameFormat::RAWBGR => 4,
-            FrameFormat::NV12 => 3,
-            FrameFormat::YUYV => 2,
-            FrameFormat::MJPEG => 1,
-            _ => 0, // Unknown or other formats
-        }
+    match state.devices.iter_mut().find(|d| d.unique_id == device.unique_id) {
+        Some(existing) => *existing = device,
+        None => state.devices.push(device),
     }
 
-    let Some(format) = device.formats.iter()
-        .filter(|f| f.width == width && f.height == height)
-        .max_by(|a, b| {
-            let priority_a = format_priority(a.format);
-            let priority_b = format_priority(b.format);
-
-            if priority_a == priority_b {
-                // If priorities are equal, compare frame rates
-                a.frame_rate.cmp(&b.frame_rate)
-            } else {
-                // Otherwise, compare priorities
-                priority_a.cmp(&priority_b)
-            }
-        })
-    else { return ERROR_FORMAT_NOT_FOUND };
+    handle
+}
 
-    start_capture_with_format_internal(state, device_index, format.index as u32)
+/// Starts an HTTP server on `port` of the loopback interface (0 picks a free one, see `cnokhwa_http_server_port`)
+/// to look at running sessions from a browser on the same machine. It serves `/devices` as JSON, and
+/// `/device/{handle}/snapshot.jpg` and `/device/{handle}/stream.mjpg` for devices that are capturing. MJPEG frames
+/// are sent as received from the camera, with Huffman tables added if needed, other formats are encoded to JPEG. At most 8 connections are served at
+/// once, further ones are closed right away.
+#[no_mangle]
+pub extern "C" fn cnokhwa_start_http_server(port: u32) -> i32 {
+    start_http_server(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
 }
 
+/// Same as `cnokhwa_start_http_server` but listening on the interface with the IPv4 or IPv6 `address`, such as
+/// "0.0.0.0" for every interface. There is no authentication, only listen on trusted networks.
 #[no_mangle]
-pub extern "C" fn cnokhwa_start_capture_with_format(device_index: u32, format_index: u32) -> i32 {
-    let mut state_guard = STATE.lock();
-    let state = match state_guard.as_mut() {
-        Some(s) => s,
-        None => return ERROR_STATE_NOT_INITIALIZED
-    };
+pub extern "C" fn cnokhwa_start_http_server_on(address: *const c_char, port: u32) -> i32 {
+    if address.is_null() {
+        return buffer_null();
+    }
 
-    start_capture_with_format_internal(state, device_index, format_index)
+    let address = unsafe { CStr::from_ptr(address) }.to_string_lossy();
+    let Ok(ip) = address.parse::<IpAddr>()
+    else { return record_error(ERROR_HTTP_SERVER_IO, format!("Invalid address {}", address)) };
+
+    start_http_server(ip, port)
 }
 
-fn start_capture_with_format_internal(state: &mut State, device_index: u32, format_index: u32) -> i32 {
-    let device = match state.devices.get(device_index as usize) {
-        Some(dev) => dev,
-        None => return ERROR_DEVICE_NOT_FOUND
-    };
+fn start_http_server(ip: IpAddr, port: u32) -> i32 {
+    let Ok(port) = u16::try_from(port)
+    else { return record_error(ERROR_HTTP_SERVER_IO, format!("Invalid port {}", port)) };
 
-    if state.camera_sessions.get(&device.index).is_some() {
-        return ERROR_SESSION_ALREADY_STARTED;
+    match http_server::start_server(ip, port, served_devices, encode_frame_jpeg) {
+        Ok(Some(_)) => RESULT_OK,
+        Ok(None) => record_error(ERROR_HTTP_SERVER_ALREADY_STARTED, "The HTTP server is already running"),
+        Err(err) => record_io_error(ERROR_HTTP_SERVER_IO, format!("Error listening on {}:{}", ip, port), &err)
     }
+}
 
-    let Some(format) = device.formats.get(format_index as usize)
-    else { return ERROR_FORMAT_NOT_FOUND };
-
-    println!("Starting capture on device {} ({}) with format {}", device.index, device.name, format.format);
+/// Stops the HTTP server, ending open streams.
+#[no_mangle]
+pub extern "C" fn cnokhwa_stop_http_server() -> i32 {
+    if http_server::stop_server() {
+        RESULT_OK
+    } else {
+        record_error(ERROR_HTTP_SERVER_NOT_STARTED, "The HTTP server is not running")
+    }
+}
 
-    let resolution = Resolution::new(format.width, format.height);
-    let camera_format = CameraFormat::new(resolution, format.format, format.frame_rate);
+/// Port the HTTP server is listening on.
+#[no_mangle]
+pub extern "C" fn cnokhwa_http_server_port() -> i32 {
+    match http_server::server_address() {
+        Some(address) => address.port() as i32,
+        None => record_error(ERROR_HTTP_SERVER_NOT_STARTED, "The HTTP server is not running")
+    }
+}
 
-    let format = RequestedFormat::new::<RgbFormat>(RequestedFormatType::Exact(camera_format));
+// Runs in the HTTP server threads for every request
+fn served_devices() -> Vec<ServedDevice> {
+    let state_guard = STATE.lock();
+    let Some(state) = state_guard.as_ref() else { return vec![] };
+
+    state.devices.iter()
+        .map(|device| ServedDevice {
+            handle: device.handle,
+            unique_id: device.unique_id.clone(),
+            name: device.name.clone(),
+            model_id: device.model_id.clone(),
+            formats: device.formats.clone(),
+            session: state.camera_sessions.get(&device.unique_id).map(|s| s.shared.clone()),
+        })
+        .collect()
+}
 
-    let Ok(mut camera_session) = CallbackCamera::new(device.index.clone(), format, |_buffer| {
-        //NOOP
-    }) else { return ERROR_OPENING_DEVICE };
+fn encode_frame_jpeg(frame: &CapturedFrame, color_space: YuvColorSpace) -> Result<Vec<u8>, NokhwaError> {
+    encode_snapshot(frame, SnapshotFormat::Jpeg, snapshot::DEFAULT_JPEG_QUALITY, color_space)
+}
 
-    let Ok(_) =  camera_session.open_stream() else { return ERROR_OPENING_DEVICE };
+// Runs in the watcher thread: diffs the connected devices against the state by unique id
+fn scan_devices(failed_probes: &mut FailedProbes) -> Vec<DeviceEvent> {
+    let Ok(connected) = query_devices() else { return vec![] };
 
-    // save camera session in state:
-    let session = Session {
-        camera: Arc::new(Mutex::new(camera_session))
+    let known: HashSet<String> = match State::current() {
+        Some(state) => state.devices.iter().map(|d| d.unique_id.clone()).collect(),
+        None => return vec![]
     };
 
-    state.camera_sessions.insert(device.index.clone(), session);
+    let connected_ids: HashSet<String> = connected.iter().map(device_unique_id).collect();
+    failed_probes.retain_connected(|unique_id| connected_ids.contains(unique_id));
+
+    // Only new devices are opened to read their formats, and without holding the state lock
+    let now = Instant::now();
+    let added: Vec<VideoDevice> = connected.iter()
+        .filter(|info| !known.contains(&device_unique_id(info)))
+        .filter_map(|info| {
+            let unique_id = device_unique_id(info);
+            if !failed_probes.should_probe(&unique_id, now) {
+                return None;
+            }
 
-    RESULT_OK
-}
+            let device = probe_device(info);
+            failed_probes.record(&unique_id, device.is_some(), now);
+            device
+        })
+        .collect();
 
-#[no_mangle]
-pub extern "C" fn cnokhwa_stop_capture(device_index: u32) -> i32 {
     let mut state_guard = STATE.lock();
-    let state = match state_guard.as_mut() {
-        Some(s) => s,
-        None => return ERROR_STATE_NOT_INITIALIZED
-    };
+    let Some(state) = state_guard.as_mut() else { return vec![] };
 
-    let device = match state.devices.get(device_index as usize) {
-        Some(dev) => dev,
-        None => return ERROR_DEVICE_NOT_FOUND
-    };
+    let mut events = vec![];
 
-    let Some(session) = state.camera_sessions.remove(&device.index) else { return ERROR_SESSION_NOT_STARTED };
+    state.devices.retain(|device| {
+        if connected_ids.contains(&device.unique_id) {
+            return true;
+        }
 
-    let mut camera_session_guard = session.camera.lock();
+        if let Some(session) = state.camera_sessions.get(&device.unique_id) {
+            session.shared.mark_device_lost();
+        }
+        events.push(DeviceEvent { kind: DEVICE_EVENT_REMOVED, handle: device.handle });
+        false
+    });
 
-    println!("Stopping capture on device {} ({})", device.index, device.name);
+    for device in added {
+        // The state may have been re-initialized while probing
+        if state.devices.iter().any(|d| d.unique_id == device.unique_id) {
+            continue;
+        }
 
-    let Ok(_) = camera_session_guard.stop_stream() else { return ERROR_SESSION_NOT_STARTED };
+        events.push(DeviceEvent { kind: DEVICE_EVENT_ADDED, handle: device.handle });
+        state.devices.push(device);
+    }
 
-    RESULT_OK
+    events
 }
 
 #[no_mangle]
-pub extern "C" fn cnokhwa_has_first_frame(device_index: u32) -> i32 {
-    let mut state_guard = STATE.lock();
-    let state = match state_guard.as_mut() {
-        Some(s) => s,
-        None => return ERROR_STATE_NOT_INITIALIZED
-    };
+pub extern "C" fn cnokhwa_devices_count() -> i32 {
+    let Some(state) = State::current() else { return state_not_initialized() };
+
+    state.devices.len() as i32
+}
 
-    let device = match state.devices.get(device_index as usize) {
-        Some(dev) => dev,
-        None => return ERROR_DEVICE_NOT_FOUND
+#[no_mangle]
+pub extern "C" fn cnokhwa_device_name(device_index: i32, buf: *mut c_char, buf_len: usize) -> usize {
+    let Some(state) = State::current() else {
+        state_not_initialized();
+        return 0;
     };
 
-    let Some(session) = state.camera_sessions.get(&device.index)
-    else { return ERROR_SESSION_NOT_STARTED };
+    let Ok(device) = state.device(device_index as u32) else { return 0 };
 
-    let mut camera_session_guard = session.camera.lock();
+    let name = &device.name;
 
-    match camera_session_guard.poll_frame() {
-        Ok(_) => RESULT_YES,
-        Err(_) => RESULT_NO
+    unsafe {
+        copy_str(name, buf, buf_len)
     }
 }
 
 #[no_mangle]
-pub extern "C" fn cnokhwa_grab_frame(
-    device_index: u32,
-    buffer: *mut u8,
-    available_bytes: usize,
-) -> i32 {
-    let frame = {
-        let mut state_guard = STATE.lock();
-        let state = match state_guard.as_mut() {
-            Some(s) => s,
-            None => return ERROR_STATE_NOT_INITIALIZED,
-        };
+pub extern "C" fn cnokhwa_device_unique_id(device_index: i32, buf: *mut c_char, buf_len: usize) -> usize {
+    let Some(state) = State::current() else {
+        state_not_initialized();
+        return 0;
+    };
 
-        let device = match state.devices.get(device_index as usize) {
-            Some(dev) => dev,
-            None => return ERROR_DEVICE_NOT_FOUND,
-        };
+    let Ok(device) = state.device(device_index as u32) else { return 0 };
 
-        let Some(session) = state.camera_sessions.get(&device.index)
-        else { return ERROR_SESSION_NOT_STARTED };
+    let unique_id = &device.unique_id;
 
-        let camera_session_guard = session.camera.lock();
+    unsafe {
+        copy_str(unique_id, buf, buf_len)
+    }
+}
 
-        match camera_session_guard.last_frame() {
-            Ok(f) => f,
-            Err(_) => return ERROR_READING_FRAME,
-        }
+#[no_mangle]
+pub extern "C" fn cnokhwa_device_model_id(device_index: i32, buf: *mut c_char, buf_len: usize) -> usize {
+    let Some(state) = State::current() else {
+        state_not_initialized();
+        return 0;
     };
 
-    let resolution = frame.resolution();
-    let width = resolution.width() as usize;
-    let height = resolution.height() as usize;
-    let dst_size = width * height * 3; // RGB output
+    let Ok(device) = state.device(device_index as u32) else { return 0 };
 
-    if available_bytes < dst_size {
-        return ERROR_BUFFER_NOT_ENOUGH_CAPACITY;
-    }
+    let model_id = &device.model_id;
 
     unsafe {
-        if buffer.is_null() {
-            return ERROR_BUFFER_NULL;
-        }
+        copy_str(model_id, buf, buf_len)
+    }
+}
 
-        // Create a mutable slice from the raw pointer
-        let output = std::slice::from_raw_parts_mut(buffer, dst_size);
+/// Returns the stable handle of a device. Handles can be passed to every function taking a device index,
+/// and keep pointing to the same camera after re-initializing, as long as it's still connected.
+#[no_mangle]
+pub extern "C" fn cnokhwa_device_handle(device_index: i32) -> i32 {
+    let Some(state) = State::current() else { return state_not_initialized() };
 
-        match convert_to_rgb(frame, output) {
-            Ok(_) => RESULT_OK,
-            Err(e) => {
-                eprintln!("Decoding error: {:?}", e);
-                ERROR_DECODING_FRAME
-            }
-        }
+    match state.device(device_index as u32) {
+        Ok(device) => device.handle as i32,
+        Err(err) => err
     }
 }
 
-
 #[no_mangle]
-pub extern "C" fn cnokhwa_frame_width(device_index: u32) -> i32 {
-    let mut state_guard = STATE.lock();
-    let state = match state_guard.as_mut() {
-        Some(s) => s,
-        None => return ERROR_STATE_NOT_INITIALIZED
-    };
-
-    let device = match state.devices.get(device_index as usize) {
-        Some(dev) => dev,
-        None => return ERROR_DEVICE_NOT_FOUND
-    };
+pub extern "C" fn cnokhwa_device_handle_from_unique_id(unique_id: *const c_char) -> i32 {
+    if unique_id.is_null() {
+        return buffer_null();
+    }
 
-    let Some(session) = state.camera_sessions.get(&device.index)
-    else { return ERROR_SESSION_NOT_STARTED };
+    let Some(state) = State::current() else { return state_not_initialized() };
 
-    let camera_session_guard = session.camera.lock();
+    let unique_id = unsafe { CStr::from_ptr(unique_id) };
+    let unique_id = unique_id.to_string_lossy();
 
-    match camera_session_guard.camera_format() {
-        Ok(f) => f.width() as i32,
-        Err(_) => ERROR_READING_CAMERA_SESSION
+    match state.devices.iter().find(|d| d.unique_id == unique_id) {
+        Some(device) => device.handle as i32,
+        None => record_error(ERROR_DEVICE_NOT_FOUND, format!("No device with unique id {}", unique_id))
     }
 }
 
+/// Returns the current positional index of a device handle, `ERROR_DEVICE_DISCONNECTED` when the device is gone.
 #[no_mangle]
-pub extern "C" fn cnokhwa_frame_height(device_index: u32) -> i32 {
-    let mut state_guard = STATE.lock();
-    let state = match state_guard.as_mut() {
-        Some(s) => s,
-        None => return ERROR_STATE_NOT_INITIALIZED
-    };
+pub extern "C" fn cnokhwa_device_index(handle: u32) -> i32 {
+    let Some(state) = State::current() else { return state_not_initialized() };
 
-    let device = match state.devices.get(device_index as usize) {
-        Some(dev) => dev,
-        None => return ERROR_DEVICE_NOT_FOUND
+    let device = match state.device(handle) {
+        Ok(dev) => dev,
+        Err(err) => return err
     };
 
-    let Some(session) = state.camera_sessions.get(&device.index)
-    else { return ERROR_SESSION_NOT_STARTED };
-
-    let camera_session_guard = session.camera.lock();
-
-    match camera_session_guard.camera_format() {
-        Ok(f) => f.height() as i32,
-        Err(_) => ERROR_READING_CAMERA_SESSION
+    match state.devices.iter().position(|d| d.unique_id == device.unique_id) {
+        Some(index) => index as i32,
+        None => ERROR_DEVICE_NOT_FOUND
     }
 }
 
 #[no_mangle]
-pub extern "C" fn cnokhwa_frame_bytes_per_row(device_index: u32) -> i32 {
-    let mut state_guard = STATE.lock();
-    let state = match state_guard.as_mut() {
-        Some(s) => s,
-        None => return ERROR_STATE_NOT_INITIALIZED
-    };
+pub extern "C" fn cnokhwa_device_formats_count(device_index: i32) -> i32 {
+    let Some(state) = State::current() else { return state_not_initialized() };
 
-    let device = match state.devices.get(device_index as usize) {
-        Some(dev) => dev,
-        None => return ERROR_DEVICE_NOT_FOUND
+    let device = match state.device(device_index as u32) {
+        Ok(dev) => dev,
+        Err(err) => return err
     };
 
-    let Some(session) = state.camera_sessions.get(&device.index)
-    else { return ERROR_SESSION_NOT_STARTED };
-
-    let camera_session_guard = session.camera.lock();
+    device.formats.len() as i32
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_device_format_width(device_index: i32, format_index: i32) -> u32 {
+    let Some(state) = State::current() else {
+        state_not_initialized();
+        return 0;
+    };
+
+    let Ok(device) = state.device(device_index as u32) else { return 0 };
+
+    if format_index < 0 || (format_index as usize) >= device.formats.len() {
+        return 0;
+    }
+
+    device.formats[format_index as usize].width
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_device_format_height(device_index: i32, format_index: i32) -> u32 {
+    let Some(state) = State::current() else {
+        state_not_initialized();
+        return 0;
+    };
+
+    let Ok(device) = state.device(device_index as u32) else { return 0 };
+
+    if format_index < 0 || (format_index as usize) >= device.formats.len() {
+        return 0;
+    }
+
+    device.formats[format_index as usize].height
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_device_format_frame_rate(device_index: i32, format_index: i32) -> u32 {
+    let Some(state) = State::current() else {
+        state_not_initialized();
+        return 0;
+    };
+
+    let Ok(device) = state.device(device_index as u32) else { return 0 };
+
+    if format_index < 0 || (format_index as usize) >= device.formats.len() {
+        return 0;
+    }
+
+    device.formats[format_index as usize].frame_rate
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_device_format_type(
+    device_index: i32,
+    format_index: i32,
+    buf: *mut c_char,
+    buf_len: usize,
+) -> usize {
+    let Some(state) = State::current() else {
+        state_not_initialized();
+        return 0;
+    };
+
+    let Ok(device) = state.device(device_index as u32) else { return 0 };
+
+    if format_index < 0 || (format_index as usize) >= device.formats.len() {
+        return 0;
+    }
+
+    let type_str = &device.formats[format_index as usize].format.to_string();
+
+    unsafe {
+        copy_str(type_str, buf, buf_len)
+    }
+}
+
+/// Reads the controls of a device, from its running session if any or by opening the device otherwise.
+/// The result is kept as a snapshot used by the other `cnokhwa_device_control_*` functions.
+#[no_mangle]
+pub extern "C" fn cnokhwa_device_controls_count(device_index: i32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index as u32) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let controls = match device_camera_controls(state, device) {
+        Ok(controls) => controls,
+        Err(err) => {
+            return record_nokhwa_error(ERROR_READING_CONTROLS, format!("Error reading controls of device {}", device.index), &err);
+        }
+    };
+
+    let controls: Vec<DeviceControl> = controls.iter()
+        .filter_map(DeviceControl::from_camera_control)
+        .collect();
+    let count = controls.len() as i32;
+
+    let unique_id = device.unique_id.clone();
+    state.device_controls.insert(unique_id, controls);
+
+    count
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_device_control_info(device_index: i32, control_index: i32, info: *mut ControlInfo) -> i32 {
+    if info.is_null() {
+        return buffer_null();
+    }
+
+    let Some(state) = State::current() else { return state_not_initialized() };
+
+    let Some(control) = state.device_control(device_index, control_index)
+    else { return control_not_found(control_index) };
+
+    unsafe {
+        *info = control.info;
+    }
+
+    RESULT_OK
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_device_control_name(device_index: i32, control_index: i32, buf: *mut c_char, buf_len: usize) -> usize {
+    let Some(state) = State::current() else {
+        state_not_initialized();
+        return 0;
+    };
+
+    let Some(control) = state.device_control(device_index, control_index) else {
+        control_not_found(control_index);
+        return 0;
+    };
+
+    unsafe {
+        copy_str(&control.name, buf, buf_len)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_device_control_menu_entry(device_index: i32, control_index: i32, entry_index: i32, value: *mut i64) -> i32 {
+    if value.is_null() {
+        return buffer_null();
+    }
+
+    let Some(state) = State::current() else { return state_not_initialized() };
+
+    let Some(control) = state.device_control(device_index, control_index)
+    else { return control_not_found(control_index) };
+
+    if entry_index < 0 || (entry_index as usize) >= control.menu_entries.len() {
+        return record_error(ERROR_CONTROL_NOT_FOUND, format!("No menu entry {} in control {}", entry_index, control.name));
+    }
+
+    unsafe {
+        *value = control.menu_entries[entry_index as usize];
+    }
+
+    RESULT_OK
+}
+
+/// Reads the current value of a control, identified by the id reported in its `ControlInfo`.
+#[no_mangle]
+pub extern "C" fn cnokhwa_get_control(device_index: u32, control_id: u64, value: *mut f64) -> i32 {
+    if value.is_null() {
+        return buffer_null();
+    }
+
+    let Some(control) = known_control(control_id)
+    else { return record_error(ERROR_CONTROL_NOT_FOUND, format!("Unknown control id {}", control_id)) };
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let camera_control = match device_camera_control(state, device, control) {
+        Ok(c) => c,
+        Err(err) => {
+            return record_nokhwa_error(ERROR_READING_CONTROLS, format!("Error reading control {} of device {}", control, device.index), &err);
+        }
+    };
+
+    let Some(current) = numeric_value(&camera_control)
+    else { return record_error(ERROR_READING_CONTROLS, format!("Control {} has no numeric value", control)) };
+
+    unsafe {
+        *value = current;
+    }
+
+    RESULT_OK
+}
+
+/// Sets the value of a control. Integer and menu controls round the value, boolean controls treat non-zero as true.
+#[no_mangle]
+pub extern "C" fn cnokhwa_set_control(device_index: u32, control_id: u64, value: f64) -> i32 {
+    let Some(control) = known_control(control_id)
+    else { return record_error(ERROR_CONTROL_NOT_FOUND, format!("Unknown control id {}", control_id)) };
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let camera_control = match device_camera_control(state, device, control) {
+        Ok(c) => c,
+        Err(err) => {
+            return record_nokhwa_error(ERROR_READING_CONTROLS, format!("Error reading control {} of device {}", control, device.index), &err);
+        }
+    };
+
+    let Some(setter) = value_setter(&camera_control, value)
+    else { return record_error(ERROR_SETTING_CONTROL, format!("Control {} does not take numeric values", control)) };
+
+    match set_device_camera_control(state, device, control, setter) {
+        Ok(_) => RESULT_OK,
+        Err(err) => {
+            record_nokhwa_error(ERROR_SETTING_CONTROL, format!("Error setting control {} of device {}", control, device.index), &err)
+        }
+    }
+}
+
+fn open_device_source(index: &CameraIndex) -> Result<Box<dyn FrameSource>, NokhwaError> {
+    let requested_format = RequestedFormat::new::<RgbFormat>(RequestedFormatType::None);
+
+    frame_source::open(index, requested_format)
+}
+
+// Controls are read from the running session when there is one, since the device may not be opened twice
+
+fn device_camera_controls(state: &State, device: &VideoDevice) -> Result<Vec<CameraControl>, NokhwaError> {
+    match state.camera_sessions.get(&device.unique_id) {
+        Some(session) => session.source.lock().controls(),
+        None => open_device_source(&device.index)?.controls()
+    }
+}
+
+fn device_camera_control(state: &State, device: &VideoDevice, control: KnownCameraControl) -> Result<CameraControl, NokhwaError> {
+    match state.camera_sessions.get(&device.unique_id) {
+        Some(session) => session.source.lock().control(control),
+        None => open_device_source(&device.index)?.control(control)
+    }
+}
+
+fn set_device_camera_control(state: &State, device: &VideoDevice, control: KnownCameraControl, value: ControlValueSetter) -> Result<(), NokhwaError> {
+    match state.camera_sessions.get(&device.unique_id) {
+        Some(session) => session.source.lock().set_control(control, value),
+        None => open_device_source(&device.index)?.set_control(control, value)
+    }
+}
+
+/// Starts a capture at the requested size. The format saved in the profile of the device is preferred when it has
+/// this size, otherwise the format converted the most cheaply is used.
+#[no_mangle]
+pub extern "C" fn cnokhwa_start_capture(device_index: u32, width: u32, height: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    if state.camera_sessions.contains_key(&device.unique_id) {
+        return record_error(ERROR_SESSION_ALREADY_STARTED, format!("A capture is already running on device {}", device.index));
+    }
+
+    // The profile format wins among the formats of the requested size, the other settings are applied once started
+    let profile_format = profile::profiles_directory()
+        .and_then(|directory| profile::load_profile(&directory, &device.unique_id).ok().flatten())
+        .and_then(|device_profile| device_profile.format);
+
+    let Some(format) = pick_format(&device.formats, width, height, profile_format.as_ref())
+    else { return record_error(ERROR_FORMAT_NOT_FOUND, format!("Device {} has no {}x{} format", device.index, width, height)) };
+
+    start_capture_with_format_internal(state, device_index, format.index as u32)
+}
+
+// The format of the profile if it has the requested size, otherwise the one converted the most cheaply
+fn pick_format<'a>(formats: &'a [VideoFormat], width: u32, height: u32, profile_format: Option<&ProfileFormat>) -> Option<&'a VideoFormat> {
+    fn format_priority(format: FrameFormat) -> u8 {
+        match format {
+            FrameFormat::RAWRGB | FrameFormat::RAWBGR => 4,
+            FrameFormat::NV12 => 3,
+            FrameFormat::YUYV => 2,
+            FrameFormat::MJPEG => 1,
+            _ => 0, // Unknown or other formats
+        }
+    }
+
+    let candidates = formats.iter().filter(|f| f.width == width && f.height == height);
+
+    if let Some(saved) = profile_format.and_then(|p| candidates.clone().find(|f| p.matches(f))) {
+        return Some(saved);
+    }
+
+    candidates.max_by(|a, b| {
+        let priority_a = format_priority(a.format);
+        let priority_b = format_priority(b.format);
+
+        if priority_a == priority_b {
+            // If priorities are equal, compare frame rates
+            a.frame_rate.cmp(&b.frame_rate)
+        } else {
+            // Otherwise, compare priorities
+            priority_a.cmp(&priority_b)
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_start_capture_with_format(device_index: u32, format_index: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    start_capture_with_format_internal(state, device_index, format_index)
+}
+
+fn start_capture_with_format_internal(state: &mut State, device_index: u32, format_index: u32) -> i32 {
+    let device = match state.device(device_index) {
+        Ok(dev) => dev.clone(),
+        Err(err) => return err
+    };
+
+    if state.camera_sessions.contains_key(&device.unique_id) {
+        return record_error(ERROR_SESSION_ALREADY_STARTED, format!("A capture is already running on device {}", device.index));
+    }
+
+    let Some(format) = device.formats.get(format_index as usize)
+    else { return record_error(ERROR_FORMAT_NOT_FOUND, format!("Device {} has no format at index {}", device.index, format_index)) };
+
+    println!("Starting capture on device {} ({}) with format {}", device.index, device.name, format.format);
+
+    let resolution = Resolution::new(format.width, format.height);
+    let camera_format = CameraFormat::new(resolution, format.format, format.frame_rate);
+
+    let format = RequestedFormat::new::<RgbFormat>(RequestedFormatType::Exact(camera_format));
+
+    let source = match frame_source::open(&device.index, format) {
+        Ok(s) => s,
+        Err(err) => return record_nokhwa_error(ERROR_OPENING_DEVICE, format!("Error opening device {}", device.index), &err)
+    };
+
+    start_session(state, &device, source)
+}
+
+/// Starts delivering the frames of `source` to a new session of `device`.
+fn start_session(state: &mut State, device: &VideoDevice, mut source: Box<dyn FrameSource>) -> i32 {
+    let shared = Arc::new(SessionShared::default());
+    let callback_shared = shared.clone();
+
+    if let Err(err) = source.start(Box::new(move |buffer| deliver_frame(&callback_shared, buffer))) {
+        return record_nokhwa_error(ERROR_OPENING_DEVICE, format!("Error opening stream of device {}", device.index), &err);
+    }
+
+    // save camera session in state:
+    let session = Session {
+        source: Arc::new(Mutex::new(source)),
+        shared
+    };
+
+    state.camera_sessions.insert(device.unique_id.clone(), session);
+
+    // Failures are reported through cnokhwa_profile_failures_count, the capture is running anyway
+    if let Some(directory) = profile::profiles_directory() {
+        apply_profile_internal(state, device, &directory);
+    }
+
+    RESULT_OK
+}
+
+/// Sets the directory where device profiles are saved. Once set, the profile of a device is applied every time
+/// a capture is started on it. A null path disables profiles.
+///
+/// The controls of the profile are set once the capture runs. Its format can only be chosen when starting:
+/// cnokhwa_start_capture picks it if it has the requested size and cnokhwa_start_capture_with_profile always uses it.
+/// A capture running at another format is reported through cnokhwa_profile_failures_count.
+#[no_mangle]
+pub extern "C" fn cnokhwa_set_profiles_directory(path: *const c_char) -> i32 {
+    if path.is_null() {
+        profile::set_profiles_directory(None);
+        return RESULT_OK;
+    }
+
+    let path = unsafe { CStr::from_ptr(path) };
+    let Ok(path) = path.to_str()
+    else { return record_error(ERROR_PROFILE_IO, "The profiles directory is not valid UTF-8") };
+
+    profile::set_profiles_directory(Some(PathBuf::from(path)));
+
+    RESULT_OK
+}
+
+/// Saves the current control values of a device, and its format when a capture is running, to its profile.
+#[no_mangle]
+pub extern "C" fn cnokhwa_save_profile(device_index: u32) -> i32 {
+    let Some(directory) = profile::profiles_directory() else { return profiles_disabled() };
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let format = match state.camera_sessions.get(&device.unique_id) {
+        Some(session) => match session.source.lock().format() {
+            Ok(f) => Some(ProfileFormat::from_camera_format(&f)),
+            Err(err) => return record_nokhwa_error(ERROR_READING_CAMERA_SESSION, "Error reading the session format", &err)
+        },
+        None => None
+    };
+
+    let controls = match device_camera_controls(state, device) {
+        Ok(controls) => controls,
+        Err(err) => {
+            return record_nokhwa_error(ERROR_READING_CONTROLS, format!("Error reading controls of device {}", device.index), &err);
+        }
+    };
+
+    let controls = controls.iter()
+        .filter(|c| !c.flag().contains(&KnownCameraControlFlag::ReadOnly))
+        .filter_map(|c| Some(ProfileControl {
+            id: control_id(c.control())?,
+            name: c.name().to_string(),
+            value: numeric_value(c)?,
+        }))
+        .collect();
+
+    let device_profile = DeviceProfile {
+        unique_id: device.unique_id.clone(),
+        name: device.name.clone(),
+        format,
+        controls,
+    };
+
+    match profile::save_profile(&directory, &device_profile) {
+        Ok(_) => RESULT_OK,
+        Err(err) => record_io_error(ERROR_PROFILE_IO, format!("Error saving profile of device {}", device.index), &err)
+    }
+}
+
+/// Applies the saved control values of a device. Returns `ERROR_PROFILE_PARTIALLY_APPLIED` when some of them
+/// could not be set, see `cnokhwa_profile_failures_count`.
+#[no_mangle]
+pub extern "C" fn cnokhwa_apply_profile(device_index: u32) -> i32 {
+    let Some(directory) = profile::profiles_directory() else { return profiles_disabled() };
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    apply_profile_internal(state, device, &directory)
+}
+
+/// Starts a capture with the format saved in the profile of the device.
+#[no_mangle]
+pub extern "C" fn cnokhwa_start_capture_with_profile(device_index: u32) -> i32 {
+    let Some(directory) = profile::profiles_directory() else { return profiles_disabled() };
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let device_profile = match profile::load_profile(&directory, &device.unique_id) {
+        Ok(Some(p)) => p,
+        Ok(None) => return profile_not_found(device),
+        Err(err) => {
+            return record_io_error(ERROR_PROFILE_IO, format!("Error loading profile of device {}", device.index), &err);
+        }
+    };
+
+    let Some(profile_format) = device_profile.format
+    else { return record_error(ERROR_FORMAT_NOT_FOUND, format!("The profile of device {} has no format", device.index)) };
+
+    let Some(format) = device.formats.iter().find(|f| profile_format.matches(f))
+    else {
+        profile::set_apply_failures(&device.unique_id, vec![format!("format: {} is not available", profile_format)]);
+        return record_error(ERROR_FORMAT_NOT_FOUND, format!("The profile format of device {} is not available", device.index));
+    };
+
+    start_capture_with_format_internal(state, device_index, format.index as u32)
+}
+
+/// Number of settings that could not be applied the last time the profile of the device was applied.
+#[no_mangle]
+pub extern "C" fn cnokhwa_profile_failures_count(device_index: i32) -> i32 {
+    let Some(state) = State::current() else { return state_not_initialized() };
+
+    let device = match state.device(device_index as u32) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    profile::apply_failures(&device.unique_id).len() as i32
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_profile_failure(device_index: i32, failure_index: i32, buf: *mut c_char, buf_len: usize) -> usize {
+    let Some(state) = State::current() else {
+        state_not_initialized();
+        return 0;
+    };
+
+    let Ok(device) = state.device(device_index as u32) else { return 0 };
+
+    let failures = profile::apply_failures(&device.unique_id);
+
+    if failure_index < 0 || (failure_index as usize) >= failures.len() {
+        return 0;
+    }
+
+    unsafe {
+        copy_str(&failures[failure_index as usize], buf, buf_len)
+    }
+}
+
+fn apply_profile_internal(state: &State, device: &VideoDevice, directory: &Path) -> i32 {
+    let device_profile = match profile::load_profile(directory, &device.unique_id) {
+        Ok(Some(p)) => p,
+        Ok(None) => {
+            profile::set_apply_failures(&device.unique_id, vec![]);
+            return profile_not_found(device);
+        },
+        Err(err) => {
+            profile::set_apply_failures(&device.unique_id, vec![format!("profile: {}", err)]);
+            return record_io_error(ERROR_PROFILE_IO, format!("Error loading profile of device {}", device.index), &err);
+        }
+    };
+
+    let mut failures = vec![];
+
+    // Formats are only chosen when starting a capture, by cnokhwa_start_capture among the formats of the requested
+    // size and by cnokhwa_start_capture_with_profile
+    if let (Some(saved), Some(session)) = (&device_profile.format, state.camera_sessions.get(&device.unique_id)) {
+        let running = session.source.lock().format().map(|f| ProfileFormat::from_camera_format(&f));
+        if running.as_ref().ok() != Some(saved) {
+            failures.push(format!("format: the capture is not running at {}", saved));
+        }
+    }
+
+    for saved in &device_profile.controls {
+        let result = known_control(saved.id)
+            .ok_or_else(|| "unknown control".to_string())
+            .and_then(|control| {
+                let camera_control = device_camera_control(state, device, control).map_err(|e| e.to_string())?;
+                let setter = value_setter(&camera_control, saved.value).ok_or_else(|| "not a numeric control".to_string())?;
+
+                set_device_camera_control(state, device, control, setter).map_err(|e| e.to_string())
+            });
+
+        if let Err(err) = result {
+            failures.push(format!("{}: {}", saved.name, err));
+        }
+    }
+
+    let result = if failures.is_empty() {
+        RESULT_OK
+    } else {
+        record_error(ERROR_PROFILE_PARTIALLY_APPLIED, format!("Some settings could not be applied: {}", failures.join(", ")))
+    };
+    profile::set_apply_failures(&device.unique_id, failures);
+
+    result
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_stop_capture(device_index: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    // A session can still be stopped through the handle of a device that has been disconnected
+    let unique_id = match state.device(device_index) {
+        Ok(dev) => dev.unique_id.clone(),
+        Err(err) => match device_handle::unique_id(device_index) {
+            Some(unique_id) if state.camera_sessions.contains_key(&unique_id) => unique_id,
+            _ => return err
+        }
+    };
+
+    let Some(session) = state.camera_sessions.remove(&unique_id) else { return session_not_started() };
+
+    // Waits for a callback in progress, no more frames reach the caller once the capture is stopped
+    *session.shared.frame_callback.lock() = None;
+    session.shared.mark_stopped();
+
+    println!("Stopping capture on device {}", unique_id);
+
+    let stopped = session.source.lock().stop();
+
+    // The session is gone either way, so its files are finished even when the camera failed to stop: taking them
+    // also keeps a capture thread that is still running from writing to them
+    if let Some(recording) = session.shared.raw_recording.lock().take() {
+        if let Err(err) = recording.finish() {
+            eprintln!("Error finishing raw recording of device {}: {}", unique_id, err);
+        }
+    }
+
+    if let Some(Err(err)) = session.shared.finish_video_recording() {
+        eprintln!("Error finishing video recording of device {}: {}", unique_id, err);
+    }
+
+    #[cfg(feature = "h264")]
+    if let Some(Err(err)) = session.shared.finish_h264_recording() {
+        eprintln!("Error finishing H.264 recording of device {}: {}", unique_id, err);
+    }
+
+    // Ends the ring dumps still following the session
+    *session.shared.frame_ring.lock() = None;
+
+    match stopped {
+        Ok(()) => RESULT_OK,
+        Err(err) => record_nokhwa_error(ERROR_SESSION_NOT_STARTED, format!("Error stopping capture on device {}", unique_id), &err)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_has_first_frame(device_index: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    // Every frame goes through the capture callback, so there is no need to poll the camera here
+    if session.shared.latest_frame().is_some() { RESULT_YES } else { RESULT_NO }
+}
+
+/// Blocks until a frame with a sequence number greater than `last_sequence` arrives (pass 0 to wait for the
+/// first frame) or `timeout_ms` elapses. Returns `ERROR_TIMEOUT` on timeout, `ERROR_SESSION_STOPPED` when the
+/// capture is stopped while waiting and `ERROR_DEVICE_LOST` when the device is reported as disconnected, by
+/// `cnokhwa_start_device_watcher`, while waiting.
+#[no_mangle]
+pub extern "C" fn cnokhwa_wait_frame(device_index: u32, last_sequence: u64, timeout_ms: u32) -> i32 {
+    // Don't hold the state lock while waiting, other calls (including stopping the capture) must go on
+    let session = {
+        let mut state_guard = STATE.lock();
+        let state = match state_guard.as_mut() {
+            Some(s) => s,
+            None => return state_not_initialized()
+        };
+
+        let device = match state.device(device_index) {
+            Ok(dev) => dev,
+            Err(err) => return err
+        };
+
+        let Some(session) = state.camera_sessions.get(&device.unique_id)
+        else { return session_not_started() };
+
+        session.clone()
+    };
+
+    match session.shared.wait_frame(last_sequence, Duration::from_millis(timeout_ms as u64)) {
+        WaitResult::NewFrame => RESULT_OK,
+        WaitResult::Stopped => record_error(ERROR_SESSION_STOPPED, "The capture was stopped while waiting for a frame"),
+        WaitResult::DeviceLost => record_error(ERROR_DEVICE_LOST, "The device was disconnected"),
+        // The source isn't asked whether it is still running: cameras are locked by their capture thread while it
+        // waits for a frame, which is exactly when this times out
+        WaitResult::TimedOut => record_error(ERROR_TIMEOUT, format!("No new frame after {} ms", timeout_ms))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_set_output_format(device_index: u32, output_format: i32) -> i32 {
+    let Some(output_format) = OutputFormat::from_code(output_format)
+    else { return invalid_output_format(output_format) };
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    session.shared.settings.lock().output_format = output_format;
+
+    RESULT_OK
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_output_format(device_index: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    let output_format = session.shared.settings.lock().output_format;
+
+    output_format.code()
+}
+
+/// Scales the frames of a session to `width` x `height` for the grab functions and the frame callback, computing
+/// pixels with nearest (0), bilinear (1) or area (2) `interpolation`. When the aspect ratios differ, `mode` either
+/// stretches the image (0), fits it between black bars (1) or crops its sides (2). A zero width and height restore
+/// the camera resolution. Sides are limited to 16384 pixels. The frame size functions report the output size.
+#[no_mangle]
+pub extern "C" fn cnokhwa_set_output_size(device_index: u32, width: u32, height: u32, interpolation: i32, mode: i32) -> i32 {
+    let output_size = match output_size(width, height, interpolation, mode) {
+        Ok(size) => size,
+        Err(err) => return err
+    };
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    session.shared.settings.lock().output_size = output_size;
+
+    RESULT_OK
+}
+
+/// Keeps only the `width` x `height` rectangle at `x`, `y` of the frames of a session in the grab functions and
+/// the frame callback, cut before the conversion so the rest of the frame is not converted. It's applied before
+/// `cnokhwa_set_output_size` scaling, and the frame size functions report the cropped size. The rectangle must fit
+/// in the session format and, for YUYV and NV12, start and end on even columns (and rows for NV12). A zero width and
+/// height remove the crop. Snapshots, recordings and the HTTP server keep whole frames.
+#[no_mangle]
+pub extern "C" fn cnokhwa_set_crop(device_index: u32, x: u32, y: u32, width: u32, height: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    let crop = if width == 0 && height == 0 {
+        None
+    } else {
+        let format = match session.source.lock().format() {
+            Ok(f) => f,
+            Err(err) => return record_nokhwa_error(ERROR_READING_CAMERA_SESSION, "Error reading the session format", &err)
+        };
+
+        let rect = CropRect { x, y, width, height };
+        if let Err(message) = rect.validate(&format) {
+            return record_error(ERROR_INVALID_CROP, message);
+        }
+        Some(rect)
+    };
+
+    session.shared.settings.lock().crop = crop;
+
+    RESULT_OK
+}
+
+/// Turns the frames of a session in the grab functions and the frame callback: `rotation` is 0, 90, 180 or 270
+/// degrees clockwise, and a non zero `mirror` or `flip` then mirrors the rotated frame horizontally or flips it
+/// vertically. The frame size functions report the turned size, with width and height swapped for 90 and 270
+/// degrees, and `cnokhwa_set_output_size` sizes refer to the turned frame too.
+#[no_mangle]
+pub extern "C" fn cnokhwa_set_orientation(device_index: u32, rotation: u32, mirror: i32, flip: i32) -> i32 {
+    let Some(rotation) = Rotation::from_degrees(rotation)
+    else { return record_error(ERROR_INVALID_ORIENTATION, format!("Rotation {} is not a multiple of 90 degrees from 0 to 270", rotation)) };
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    session.shared.settings.lock().orientation = Orientation { rotation, mirror: mirror != 0, flip: flip != 0 };
+
+    RESULT_OK
+}
+
+/// Chooses how the YUV frames of a session are turned into RGB by the grab functions, the frame callback, snapshots
+/// and recordings. `matrix` is 0 for automatic, 1 for BT.601 or 2 for BT.709, and `range` is 0 for automatic, 1 for
+/// limited (16-235) or 2 for full (0-255). Automatic picks BT.709 for frames of 720 lines or more and BT.601 below,
+/// in limited range, except for MJPEG frames which are JPEG files, in BT.601 full range whatever their size.
+#[no_mangle]
+pub extern "C" fn cnokhwa_set_color_space(device_index: u32, matrix: i32, range: i32) -> i32 {
+    let Some(matrix) = YuvMatrix::from_code(matrix)
+    else { return record_error(ERROR_INVALID_COLOR_SPACE, format!("Unknown color matrix {}", matrix)) };
+
+    let Some(range) = YuvRange::from_code(range)
+    else { return record_error(ERROR_INVALID_COLOR_SPACE, format!("Unknown color range {}", range)) };
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    session.shared.settings.lock().color_space = YuvColorSpace { matrix, range };
+
+    RESULT_OK
+}
+
+fn output_size(width: u32, height: u32, interpolation: i32, mode: i32) -> Result<Option<OutputSize>, i32> {
+    if width == 0 && height == 0 {
+        return Ok(None);
+    }
+
+    if width == 0 || height == 0 || width > MAX_OUTPUT_SIDE || height > MAX_OUTPUT_SIDE {
+        return Err(record_error(ERROR_INVALID_OUTPUT_SIZE, format!("Invalid output size {}x{}", width, height)));
+    }
+
+    let Some(interpolation) = Interpolation::from_code(interpolation)
+    else { return Err(record_error(ERROR_INVALID_OUTPUT_SIZE, format!("Unknown interpolation {}", interpolation))) };
+
+    let Some(mode) = ScaleMode::from_code(mode)
+    else { return Err(record_error(ERROR_INVALID_OUTPUT_SIZE, format!("Unknown scale mode {}", mode))) };
+
+    Ok(Some(OutputSize { width, height, interpolation, mode }))
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_grab_frame(
+    device_index: u32,
+    buffer: *mut u8,
+    available_bytes: usize,
+) -> i32 {
+    grab_frame_internal(device_index, None, None, None, buffer, available_bytes, ptr::null_mut())
+}
+
+/// Same as `cnokhwa_grab_frame` but writing each row `stride` bytes apart, so the destination can have padded rows.
+/// The stride must be at least `cnokhwa_frame_bytes_per_row` and the buffer must hold `stride * height` bytes.
+#[no_mangle]
+pub extern "C" fn cnokhwa_grab_frame_with_stride(
+    device_index: u32,
+    buffer: *mut u8,
+    available_bytes: usize,
+    stride: usize,
+) -> i32 {
+    grab_frame_internal(device_index, None, None, Some(stride), buffer, available_bytes, ptr::null_mut())
+}
+
+/// Same as `cnokhwa_grab_frame_with_stride` (a zero stride means packed rows) but also writing the
+/// sequence number and capture timestamps of the grabbed frame into `info` when it's not null.
+#[no_mangle]
+pub extern "C" fn cnokhwa_grab_frame_with_info(
+    device_index: u32,
+    buffer: *mut u8,
+    available_bytes: usize,
+    stride: usize,
+    info: *mut FrameInfo,
+) -> i32 {
+    let stride = if stride == 0 { None } else { Some(stride) };
+
+    grab_frame_internal(device_index, None, None, stride, buffer, available_bytes, info)
+}
+
+/// Writes the capture metadata of the latest frame into `info` without converting it.
+/// Callers can compare sequence numbers to skip frames they already grabbed.
+#[no_mangle]
+pub extern "C" fn cnokhwa_frame_info(device_index: u32, info: *mut FrameInfo) -> i32 {
+    if info.is_null() {
+        return buffer_null();
+    }
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    let Some(frame) = session.shared.latest_frame()
+    else { return no_frame_yet() };
+
+    unsafe {
+        *info = frame.info;
+    }
+
+    RESULT_OK
+}
+
+/// Same as `cnokhwa_grab_frame` but overriding the session output format for this call only.
+#[no_mangle]
+pub extern "C" fn cnokhwa_grab_frame_with_format(
+    device_index: u32,
+    output_format: i32,
+    buffer: *mut u8,
+    available_bytes: usize,
+) -> i32 {
+    let Some(output_format) = OutputFormat::from_code(output_format)
+    else { return invalid_output_format(output_format) };
+
+    grab_frame_internal(device_index, Some(output_format), None, None, buffer, available_bytes, ptr::null_mut())
+}
+
+/// Same as `cnokhwa_grab_frame_with_stride` (a zero stride means packed rows) but scaling the frame to
+/// `width` x `height` for this call only, as described in `cnokhwa_set_output_size`.
+#[no_mangle]
+pub extern "C" fn cnokhwa_grab_frame_scaled(
+    device_index: u32,
+    width: u32,
+    height: u32,
+    interpolation: i32,
+    mode: i32,
+    buffer: *mut u8,
+    available_bytes: usize,
+    stride: usize,
+) -> i32 {
+    let output_size = match output_size(width, height, interpolation, mode) {
+        Ok(Some(size)) => size,
+        Ok(None) => return record_error(ERROR_INVALID_OUTPUT_SIZE, "The output size can't be 0x0"),
+        Err(err) => return err
+    };
+    let stride = if stride == 0 { None } else { Some(stride) };
+
+    grab_frame_internal(device_index, None, Some(output_size), stride, buffer, available_bytes, ptr::null_mut())
+}
+
+fn grab_frame_internal(
+    device_index: u32,
+    output_format: Option<OutputFormat>,
+    output_size: Option<OutputSize>,
+    stride: Option<usize>,
+    buffer: *mut u8,
+    available_bytes: usize,
+    info: *mut FrameInfo,
+) -> i32 {
+    let (frame, output_format, crop, output_size, orientation, color_space) = {
+        let mut state_guard = STATE.lock();
+        let state = match state_guard.as_mut() {
+            Some(s) => s,
+            None => return state_not_initialized(),
+        };
+
+        let device = match state.device(device_index) {
+            Ok(dev) => dev,
+            Err(err) => return err,
+        };
+
+        let Some(session) = state.camera_sessions.get(&device.unique_id)
+        else { return session_not_started() };
+
+        let Some(frame) = session.shared.latest_frame()
+        else { return no_frame_yet() };
+
+        let settings = *session.shared.settings.lock();
+
+        (frame, output_format.unwrap_or(settings.output_format), settings.crop, output_size.or(settings.output_size), settings.orientation, settings.color_space)
+    };
+
+    let CapturedFrame { buffer: frame, info: frame_info } = frame;
+    let color_space = color_space.resolve(frame.source_frame_format(), frame.resolution());
+
+    let frame = match crop {
+        Some(rect) => match crop::crop_frame(&frame, rect, color_space) {
+            Ok(cropped) => cropped,
+            Err(e) => return decoding_error("Error cropping frame", &e)
+        },
+        None => frame
+    };
+
+    let (width, height) = output_resolution(frame.resolution(), output_size, orientation);
+    let row_bytes = width * output_format.bytes_per_pixel();
+    let stride = stride.unwrap_or(row_bytes);
+
+    if stride < row_bytes {
+        return record_error(ERROR_INVALID_STRIDE, format!("Stride {} is smaller than a row of {} bytes", stride, row_bytes));
+    }
+
+    let Some(dst_size) = stride.checked_mul(height)
+    else { return record_error(ERROR_INVALID_STRIDE, format!("Stride {} is too large for {} rows", stride, height)) };
+
+    if available_bytes < dst_size {
+        return record_error(ERROR_BUFFER_NOT_ENOUGH_CAPACITY, format!("The frame needs {} bytes but the buffer has {}", dst_size, available_bytes));
+    }
+
+    unsafe {
+        if buffer.is_null() {
+            return buffer_null();
+        }
+
+        // Create a mutable slice from the raw pointer
+        let output = std::slice::from_raw_parts_mut(buffer, dst_size);
+
+        match convert_frame(frame, output_format, output_size, orientation, color_space, stride, output) {
+            Ok(_) => {
+                if !info.is_null() {
+                    *info = frame_info;
+                }
+                RESULT_OK
+            },
+            Err(e) => decoding_error("Error decoding frame", &e)
+        }
+    }
+}
+
+// Truncated or corrupt MJPEG frames get their own code, since callers usually just skip them and grab the next one
+fn decoding_error(message: &str, err: &NokhwaError) -> i32 {
+    let code = if mjpeg::is_corrupt_frame(err) { ERROR_CORRUPT_FRAME } else { ERROR_DECODING_FRAME };
+    record_nokhwa_error(code, message, err)
+}
+
+
+/// Registers a function called from the capture thread with every new frame, converted with the session settings.
+/// Passing a null function removes the callback. The callback must return quickly and must not call back into
+/// this library, since the capture thread holds the camera while it runs.
+///
+/// Removing the callback, or stopping the capture, waits for a call in progress: once either returns, the callback
+/// won't be called again and `user_data` can be released.
+#[no_mangle]
+pub extern "C" fn cnokhwa_set_frame_callback(
+    device_index: u32,
+    callback: Option<FrameCallbackFn>,
+    user_data: *mut c_void,
+) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    *session.shared.frame_callback.lock() = callback.map(|func| FrameCallback { func, user_data });
+
+    RESULT_OK
+}
+
+// Runs in the capture thread for every frame delivered by the session source
+fn deliver_frame(shared: &SessionShared, frame: Buffer) {
+    let captured = shared.record_frame(frame);
+    let color_space = shared.settings.lock().color_space;
+    shared.write_raw_frame(&captured);
+    shared.write_video_frame(&captured, |frame| encode_frame_jpeg(frame, color_space));
+    shared.write_ring_frame(&captured, |frame| encode_frame_jpeg(frame, color_space));
+    #[cfg(feature = "h264")]
+    shared.write_h264_frame(&captured, |frame| frame_to_i420(frame, color_space));
+
+    let CapturedFrame { buffer: frame, info } = captured;
+
+    // Held until the callback returns, so removing the callback waits for a call in progress
+    let callback = shared.frame_callback.lock();
+    let Some(callback) = *callback else { return };
+
+    let settings = *shared.settings.lock();
+    let color_space = settings.color_space.resolve(frame.source_frame_format(), frame.resolution());
+    let frame = match settings.crop {
+        Some(rect) => match crop::crop_frame(&frame, rect, color_space) {
+            Ok(cropped) => cropped,
+            Err(e) => {
+                eprintln!("Cropping error: {:?}", e);
+                return;
+            }
+        },
+        None => frame
+    };
+
+    let output_format = settings.output_format;
+    let (width, height) = output_resolution(frame.resolution(), settings.output_size, settings.orientation);
+    let Some((stride, dst_size)) = width.checked_mul(output_format.bytes_per_pixel())
+        .and_then(|stride| Some((stride, stride.checked_mul(height)?)))
+    else {
+        eprintln!("Frame of {}x{} is too large for the callback", width, height);
+        return;
+    };
+
+    let mut output = shared.callback_buffer.lock();
+    output.resize(dst_size, 0);
+
+    if let Err(e) = convert_frame(frame, output_format, settings.output_size, settings.orientation, color_space, stride, &mut output) {
+        eprintln!("Decoding error: {:?}", e);
+        return;
+    }
+
+    callback.invoke(&CnokhwaFrame {
+        data: output.as_ptr(),
+        width: width as u32,
+        height: height as u32,
+        stride: stride as u32,
+        format: output_format.code(),
+        timestamp_us: info.timestamp_us,
+        sequence: info.sequence,
+        wall_clock_us: info.wall_clock_us,
+    });
+}
+
+/// Starts writing the raw frames of a session, as received from the camera and before any conversion, to `path`.
+/// The file can be replayed on any machine with `cnokhwa_add_replay_device`. An existing file is overwritten.
+#[no_mangle]
+pub extern "C" fn cnokhwa_start_raw_recording(device_index: u32, path: *const c_char) -> i32 {
+    if path.is_null() {
+        return buffer_null();
+    }
+
+    let path = unsafe { CStr::from_ptr(path) };
+    let Ok(path) = path.to_str()
+    else { return record_error(ERROR_RECORDING_IO, "The recording path is not valid UTF-8") };
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    // Read before locking the recording, the capture thread holds the camera while writing to it
+    let frame_rate = match session.source.lock().format() {
+        Ok(f) => f.frame_rate(),
+        Err(err) => return record_nokhwa_error(ERROR_READING_CAMERA_SESSION, "Error reading the session format", &err)
+    };
+
+    let mut recording = session.shared.raw_recording.lock();
+    if recording.is_some() {
+        return record_error(ERROR_RECORDING_ALREADY_STARTED, format!("A raw recording is already running on device {}", device.index));
+    }
+
+    let header = RawStreamHeader { name: device.name.clone(), frame_rate };
+
+    match RawStreamFile::create(Path::new(path), &header) {
+        Ok(writer) => {
+            *recording = Some(writer);
+            RESULT_OK
+        },
+        Err(err) => record_io_error(ERROR_RECORDING_IO, format!("Error creating raw recording {}", path), &err)
+    }
+}
+
+/// Stops the raw recording of a session and flushes the file. Stopping the capture stops it too.
+#[no_mangle]
+pub extern "C" fn cnokhwa_stop_raw_recording(device_index: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    let Some(recording) = session.shared.raw_recording.lock().take()
+    else { return record_error(ERROR_RECORDING_NOT_STARTED, format!("No raw recording running on device {}", device.index)) };
+
+    match recording.finish() {
+        Ok(_) => RESULT_OK,
+        Err(err) => record_io_error(ERROR_RECORDING_IO, "Error finishing raw recording", &err)
+    }
+}
+
+/// Starts recording a session to an MJPEG AVI file at `path`, overwriting any existing file. MJPEG frames are
+/// stored as received from the camera, other formats are encoded to JPEG. Frames are timed by their capture
+/// timestamps at the frame rate of the session format. The file is finished when the recording or the capture
+/// is stopped, or when the device is lost.
+#[no_mangle]
+pub extern "C" fn cnokhwa_start_recording(device_index: u32, path: *const c_char) -> i32 {
+    if path.is_null() {
+        return buffer_null();
+    }
+
+    let path = unsafe { CStr::from_ptr(path) };
+    let Ok(path) = path.to_str()
+    else { return record_error(ERROR_RECORDING_IO, "The recording path is not valid UTF-8") };
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    // Read before locking the recording, the capture thread holds the camera while writing to it
+    let format = match session.source.lock().format() {
+        Ok(f) => f,
+        Err(err) => return record_nokhwa_error(ERROR_READING_CAMERA_SESSION, "Error reading the session format", &err)
+    };
+
+    let mut recording = session.shared.video_recording.lock();
+    if recording.is_some() {
+        return record_error(ERROR_RECORDING_ALREADY_STARTED, format!("A video recording is already running on device {}", device.index));
+    }
+
+    match AviFile::create(Path::new(path), format.resolution(), format.frame_rate()) {
+        Ok(writer) => {
+            *recording = Some(writer);
+            RESULT_OK
+        },
+        Err(err) => record_io_error(ERROR_RECORDING_IO, format!("Error creating video recording {}", path), &err)
+    }
+}
+
+/// Stops the video recording of a session and finishes the file.
+#[no_mangle]
+pub extern "C" fn cnokhwa_stop_recording(device_index: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    match session.shared.finish_video_recording() {
+        Some(Ok(_)) => RESULT_OK,
+        Some(Err(err)) => record_io_error(ERROR_RECORDING_IO, "Error finishing video recording", &err),
+        None => record_error(ERROR_RECORDING_NOT_STARTED, format!("No video recording running on device {}", device.index))
+    }
+}
+
+/// Starts keeping the latest frames of a session in memory, as JPEG like video recordings, so they can be saved
+/// with `cnokhwa_dump_ring` after an event. The ring holds at most `max_duration_ms` of frames and `max_bytes`
+/// of JPEG data, a zero limit being unlimited, but one of them must be set. Calling it again on a running ring
+/// changes its limits and keeps the frames that still fit. The ring is dropped when the capture stops.
+#[no_mangle]
+pub extern "C" fn cnokhwa_start_ring(device_index: u32, max_duration_ms: u32, max_bytes: usize) -> i32 {
+    let limits = RingLimits { max_duration_us: max_duration_ms as u64 * 1000, max_bytes };
+    if !limits.is_bounded() {
+        return record_error(ERROR_INVALID_RING_SIZE, "The ring needs a maximum duration or size");
+    }
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    let mut ring = session.shared.frame_ring.lock();
+    match ring.as_mut() {
+        Some(ring) => ring.set_limits(limits),
+        None => *ring = Some(FrameRing::new(limits))
+    }
+
+    RESULT_OK
+}
+
+/// Stops the ring of a session and frees its frames. Dumps still following it are finished.
+#[no_mangle]
+pub extern "C" fn cnokhwa_stop_ring(device_index: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    let ring = session.shared.frame_ring.lock().take();
+    match ring {
+        Some(_) => RESULT_OK,
+        None => ring_not_started(device)
+    }
+}
+
+/// Writes the frames buffered by the ring of a session to an MJPEG AVI file at `path`, overwriting any existing
+/// file. With a non zero `post_event_ms`, the frames of the following milliseconds are appended in the
+/// background and the file is finished afterwards, or earlier if the ring or the capture is stopped; the ring
+/// keeps running meanwhile and can be dumped again. Returns once the buffered frames are written.
+#[no_mangle]
+pub extern "C" fn cnokhwa_dump_ring(device_index: u32, path: *const c_char, post_event_ms: u32) -> i32 {
+    if path.is_null() {
+        return buffer_null();
+    }
+
+    let path = unsafe { CStr::from_ptr(path) };
+    let Ok(path) = path.to_str()
+    else { return record_error(ERROR_RECORDING_IO, "The recording path is not valid UTF-8") };
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    // Read before locking the ring, the capture thread holds the camera while writing to it
+    let format = match session.source.lock().format() {
+        Ok(f) => f,
+        Err(err) => return record_nokhwa_error(ERROR_READING_CAMERA_SESSION, "Error reading the session format", &err)
+    };
+
+    // Taken together, so no frame is missing between the history and the following frames
+    let (history, follow) = {
+        let mut ring = session.shared.frame_ring.lock();
+        let Some(ring) = ring.as_mut()
+        else { return ring_not_started(device) };
+
+        let follow = (post_event_ms > 0)
+            .then(|| (ring.follow(), Instant::now() + Duration::from_millis(post_event_ms as u64)));
+        (ring.history(), follow)
+    };
+
+    if history.is_empty() && follow.is_none() {
+        return no_frame_yet();
+    }
+
+    let writer = match AviFile::create(Path::new(path), format.resolution(), format.frame_rate()) {
+        Ok(writer) => writer,
+        Err(err) => return record_io_error(ERROR_RECORDING_IO, format!("Error creating ring dump {}", path), &err)
+    };
+
+    // Background writers are left to finish on their own
+    match frame_ring::dump(writer, history, follow) {
+        Ok(_) => RESULT_OK,
+        Err(err) => record_io_error(ERROR_RECORDING_IO, format!("Error writing ring dump {}", path), &err)
+    }
+}
+
+/// Starts recording a session to an H.264 file at `path`, overwriting any existing file. `container` selects a raw
+/// Annex-B elementary stream (0) or a fragmented MP4 (1). `bitrate_kbps` defaults to 2000 and `gop_frames`, the
+/// distance between keyframes, to two seconds of frames when 0. Every frame is converted to I420 and encoded in
+/// software, so the rate of the session should stay moderate. Frames with an odd width or height lose their last
+/// column or row. YUYV and NV12 frames are encoded with the YUV values of the camera, other formats in limited range
+/// with the matrix of the session, BT.709 from 720 lines and BT.601 below when automatic, which is what players
+/// assume since the stream doesn't say. The file is finished when the recording or the capture is stopped, or when
+/// the device is lost.
+/// Returns ERROR_H264_NOT_AVAILABLE when the library is built without the h264 feature.
+#[cfg(feature = "h264")]
+#[no_mangle]
+pub extern "C" fn cnokhwa_start_h264_recording(device_index: u32, path: *const c_char, container: i32, bitrate_kbps: u32, gop_frames: u32) -> i32 {
+    let Some(container) = H264Container::from_code(container)
+    else { return record_error(ERROR_INVALID_H264_SETTINGS, format!("Unknown H.264 container {}", container)) };
+
+    let Some(bitrate_bps) = (if bitrate_kbps == 0 { 2000 } else { bitrate_kbps }).checked_mul(1000)
+    else { return record_error(ERROR_INVALID_H264_SETTINGS, format!("H.264 bitrate {} kbps is too high", bitrate_kbps)) };
+
+    if path.is_null() {
+        return buffer_null();
+    }
+
+    let path = unsafe { CStr::from_ptr(path) };
+    let Ok(path) = path.to_str()
+    else { return record_error(ERROR_RECORDING_IO, "The recording path is not valid UTF-8") };
+
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    // Read before locking the recording, the capture thread holds the camera while writing to it
+    let format = match session.source.lock().format() {
+        Ok(f) => f,
+        Err(err) => return record_nokhwa_error(ERROR_READING_CAMERA_SESSION, "Error reading the session format", &err)
+    };
+
+    let frame_rate = format.frame_rate().max(1);
+    let settings = H264Settings {
+        container,
+        bitrate_bps,
+        gop_frames: if gop_frames == 0 { frame_rate * 2 } else { gop_frames },
+        frame_rate,
+    };
+
+    let mut recording = session.shared.h264_recording.lock();
+    if recording.is_some() {
+        return record_error(ERROR_RECORDING_ALREADY_STARTED, format!("An H.264 recording is already running on device {}", device.index));
+    }
+
+    match H264Recording::create(Path::new(path), format.resolution(), settings) {
+        Ok(writer) => {
+            *recording = Some(writer);
+            RESULT_OK
+        },
+        Err(err) => record_io_error(ERROR_RECORDING_IO, format!("Error creating H.264 recording {}", path), &err)
+    }
+}
+
+#[cfg(not(feature = "h264"))]
+#[no_mangle]
+pub extern "C" fn cnokhwa_start_h264_recording(_device_index: u32, _path: *const c_char, _container: i32, _bitrate_kbps: u32, _gop_frames: u32) -> i32 {
+    h264_not_available()
+}
+
+/// Stops the H.264 recording of a session and finishes the file.
+#[cfg(feature = "h264")]
+#[no_mangle]
+pub extern "C" fn cnokhwa_stop_h264_recording(device_index: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    match session.shared.finish_h264_recording() {
+        Some(Ok(_)) => RESULT_OK,
+        Some(Err(err)) => record_io_error(ERROR_RECORDING_IO, "Error finishing H.264 recording", &err),
+        None => record_error(ERROR_RECORDING_NOT_STARTED, format!("No H.264 recording running on device {}", device.index))
+    }
+}
+
+#[cfg(not(feature = "h264"))]
+#[no_mangle]
+pub extern "C" fn cnokhwa_stop_h264_recording(_device_index: u32) -> i32 {
+    h264_not_available()
+}
+
+/// Converts a frame to the input of the H.264 encoder, directly from YUYV and NV12 and through RGB otherwise.
+#[cfg(feature = "h264")]
+fn frame_to_i420(frame: &CapturedFrame, color_space: YuvColorSpace) -> Result<I420Frame, NokhwaError> {
+    let frame = &frame.buffer;
+    let resolution = frame.resolution();
+    let conversion_error = || NokhwaError::ProcessFrameError {
+        src: frame.source_frame_format(),
+        destination: "I420".to_string(),
+        error: "The frame is smaller than its resolution".to_string(),
+    };
+
+    match frame.source_frame_format() {
+        FrameFormat::YUYV => I420Frame::from_yuyv(frame.buffer(), resolution).ok_or_else(conversion_error),
+        FrameFormat::NV12 => I420Frame::from_nv12(frame.buffer(), resolution).ok_or_else(conversion_error),
+        _ => {
+            let stride = resolution.width() as usize * OutputFormat::Rgb.bytes_per_pixel();
+            let mut rgb = vec![0u8; stride * resolution.height() as usize];
+            let source_color_space = color_space.resolve(frame.source_frame_format(), resolution);
+            convert_to_rgb(frame.clone(), OutputFormat::Rgb, source_color_space, stride, &mut rgb)?;
+
+            // Streams carry no color information, players take them as limited range BT.709 from 720 lines and
+            // BT.601 below, like the automatic choice for YUV frames
+            let stream_color_space = YuvColorSpace { matrix: color_space.matrix, range: YuvRange::Limited }
+                .resolve(FrameFormat::NV12, resolution);
+            I420Frame::from_rgb(&rgb, resolution, stream_color_space).ok_or_else(conversion_error)
+        }
+    }
+}
+
+/// Encodes the latest frame of a session as JPEG (0), PNG (1) or BMP (2) and writes it to `path`, overwriting any
+/// existing file. `quality` only applies to JPEG, from 1 to 100 or 0 for the default. JPEG snapshots of MJPEG
+/// frames are the frames as received from the camera, without re-encoding, with the standard Huffman tables added
+/// when the camera leaves them out. Other snapshots are encoded from the frame converted to RGB.
+#[no_mangle]
+pub extern "C" fn cnokhwa_snapshot(device_index: u32, format: i32, quality: u32, path: *const c_char) -> i32 {
+    let Some(format) = SnapshotFormat::from_code(format)
+    else { return record_error(ERROR_INVALID_SNAPSHOT_FORMAT, format!("Unknown snapshot format {}", format)) };
+
+    if path.is_null() {
+        return buffer_null();
+    }
+
+    let path = unsafe { CStr::from_ptr(path) };
+    let Ok(path) = path.to_str()
+    else { return record_error(ERROR_SNAPSHOT_IO, "The snapshot path is not valid UTF-8") };
+
+    let (frame, color_space) = match latest_session_frame(device_index) {
+        Ok((_, frame, color_space)) => (frame, color_space),
+        Err(err) => return err
+    };
+
+    let data = match encode_snapshot(&frame, format, snapshot::jpeg_quality(quality), color_space) {
+        Ok(data) => data,
+        Err(err) => return record_nokhwa_error(ERROR_ENCODING_SNAPSHOT, format!("Error encoding {} snapshot", format), &err)
+    };
+
+    match std::fs::write(path, data) {
+        Ok(()) => RESULT_OK,
+        Err(err) => record_io_error(ERROR_SNAPSHOT_IO, format!("Error writing snapshot {}", path), &err)
+    }
+}
+
+/// Same as `cnokhwa_snapshot` but copies the encoded image into `buffer`, returning its size in bytes.
+/// Passing a null buffer returns the size without copying: the image is kept, and the next call from the same
+/// thread for the same device, format and quality copies that very image instead of encoding a newer frame.
+#[no_mangle]
+pub extern "C" fn cnokhwa_snapshot_to_buffer(device_index: u32, format: i32, quality: u32, buffer: *mut u8, buffer_len: usize) -> i32 {
+    let Some(format) = SnapshotFormat::from_code(format)
+    else { return record_error(ERROR_INVALID_SNAPSHOT_FORMAT, format!("Unknown snapshot format {}", format)) };
+
+    let quality = snapshot::jpeg_quality(quality);
+
+    let (unique_id, frame, color_space) = match latest_session_frame(device_index) {
+        Ok(r) => r,
+        Err(err) => return err
+    };
+
+    let data = match snapshot::take_pending(&unique_id, format, quality) {
+        Some(data) => data,
+        None => match encode_snapshot(&frame, format, quality, color_space) {
+            Ok(data) => data,
+            Err(err) => return record_nokhwa_error(ERROR_ENCODING_SNAPSHOT, format!("Error encoding {} snapshot", format), &err)
+        }
+    };
+
+    let size = data.len() as i32;
+
+    if buffer.is_null() {
+        snapshot::keep_pending(PendingSnapshot { unique_id, format, quality, data });
+        return size;
+    }
+
+    if buffer_len < data.len() {
+        let needed = data.len();
+        // Kept so the caller can come back with a bigger buffer
+        snapshot::keep_pending(PendingSnapshot { unique_id, format, quality, data });
+        return record_error(ERROR_BUFFER_NOT_ENOUGH_CAPACITY, format!("The snapshot needs {} bytes but the buffer has {}", needed, buffer_len));
+    }
+
+    unsafe {
+        ptr::copy_nonoverlapping(data.as_ptr(), buffer, data.len());
+    }
+
+    size
+}
+
+fn latest_session_frame(device_index: u32) -> Result<(String, CapturedFrame, YuvColorSpace), i32> {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return Err(state_not_initialized())
+    };
+
+    let device = state.device(device_index)?;
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return Err(session_not_started()) };
+
+    let Some(frame) = session.shared.latest_frame()
+    else { return Err(no_frame_yet()) };
+
+    let color_space = session.shared.settings.lock().color_space;
+
+    Ok((device.unique_id.clone(), frame, color_space))
+}
+
+/// Encodes a frame as an image file, reusing the bytes of MJPEG frames as they are when JPEG is asked for.
+fn encode_snapshot(frame: &CapturedFrame, format: SnapshotFormat, quality: u8, color_space: YuvColorSpace) -> Result<Vec<u8>, NokhwaError> {
+    let frame = &frame.buffer;
+
+    if format == SnapshotFormat::Jpeg && frame.source_frame_format() == FrameFormat::MJPEG {
+        return Ok(snapshot::with_huffman_tables(frame.buffer()).into_owned());
+    }
+
+    let resolution = frame.resolution();
+    let stride = resolution.width() as usize * OutputFormat::Rgb.bytes_per_pixel();
+    let mut rgb = vec![0u8; stride * resolution.height() as usize];
+    let color_space = color_space.resolve(frame.source_frame_format(), resolution);
+    convert_to_rgb(frame.clone(), OutputFormat::Rgb, color_space, stride, &mut rgb)?;
+
+    snapshot::encode_rgb(&rgb, resolution.width(), resolution.height(), format, quality)
+        .map_err(|e| NokhwaError::ProcessFrameError {
+            src: frame.source_frame_format(),
+            destination: format.to_string(),
+            error: e.to_string(),
+        })
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_frame_width(device_index: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    let settings = *session.shared.settings.lock();
+
+    match session_output_resolution(session, &settings) {
+        Ok((width, _)) => width as i32,
+        Err(err) => err
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_frame_height(device_index: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    let settings = *session.shared.settings.lock();
+
+    match session_output_resolution(session, &settings) {
+        Ok((_, height)) => height as i32,
+        Err(err) => err
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_frame_bytes_per_row(device_index: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
+
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    let settings = *session.shared.settings.lock();
+
+    match session_output_resolution(session, &settings) {
+        Ok((width, _)) => (width * settings.output_format.bytes_per_pixel()) as i32,
+        Err(err) => err
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn cnokhwa_frame_size(device_index: u32) -> i32 {
+    let mut state_guard = STATE.lock();
+    let state = match state_guard.as_mut() {
+        Some(s) => s,
+        None => return state_not_initialized()
+    };
+
+    let device = match state.device(device_index) {
+        Ok(dev) => dev,
+        Err(err) => return err
+    };
 
-    match camera_session_guard.camera_format() {
-        Ok(f) => (f.width() as i32) * 3, // RGB
-        Err(_) => ERROR_READING_CAMERA_SESSION,
+    let Some(session) = state.camera_sessions.get(&device.unique_id)
+    else { return session_not_started() };
+
+    let settings = *session.shared.settings.lock();
+
+    match session_output_resolution(session, &settings) {
+        Ok((width, height)) => (width * height * settings.output_format.bytes_per_pixel()) as i32,
+        Err(err) => err
     }
 }
 
+fn state_not_initialized() -> i32 {
+    record_error(ERROR_STATE_NOT_INITIALIZED, "cnokhwa_initialize has not been called")
+}
+
+fn control_not_found(control_index: i32) -> i32 {
+    record_error(ERROR_CONTROL_NOT_FOUND, format!("No control at index {}, call cnokhwa_device_controls_count first", control_index))
+}
+
+fn profiles_disabled() -> i32 {
+    record_error(ERROR_PROFILES_DISABLED, "No profiles directory set")
+}
+
+fn profile_not_found(device: &VideoDevice) -> i32 {
+    record_error(ERROR_PROFILE_NOT_FOUND, format!("No profile saved for device {}", device.unique_id))
+}
+
+fn buffer_null() -> i32 {
+    record_error(ERROR_BUFFER_NULL, "A required pointer argument is null")
+}
+
+fn invalid_output_format(code: i32) -> i32 {
+    record_error(ERROR_INVALID_OUTPUT_FORMAT, format!("Unknown output format {}", code))
+}
+
+fn no_frame_yet() -> i32 {
+    record_error(ERROR_READING_FRAME, "No frame has been captured yet")
+}
+
+fn session_not_started() -> i32 {
+    record_error(ERROR_SESSION_NOT_STARTED, "No capture session started on the device")
+}
+
+fn ring_not_started(device: &VideoDevice) -> i32 {
+    record_error(ERROR_RING_NOT_STARTED, format!("No ring running on device {}", device.index))
+}
+
+#[cfg(not(feature = "h264"))]
+fn h264_not_available() -> i32 {
+    record_error(ERROR_H264_NOT_AVAILABLE, "H.264 recording needs cnokhwa to be built with the h264 feature")
+}
+
 /// Copies a Rust string into a C buffer, similar to `strncpy` in C.
 ///
 /// # Arguments
@@ -619,32 +2620,210 @@ unsafe fn copy_str(s: &str, buf: *mut c_char, length: usize) -> usize {
     len_to_copy
 }
 
-fn convert_to_rgb(frame: Buffer, output: &mut [u8]) -> Result<(), NokhwaError> {
-    let buffer = frame.buffer();
+/// Converts `frame` into `output` like `convert_to_rgb`, scaled to `output_size` and turned when asked for.
+/// The output size is the size of the turned frame.
+fn convert_frame(frame: Buffer, output_format: OutputFormat, output_size: Option<OutputSize>, orientation: Orientation, color_space: YuvColorSpace, stride: usize, output: &mut [u8]) -> Result<(), NokhwaError> {
+    if orientation.is_identity() {
+        return scale_frame(frame, output_format, output_size, color_space, stride, output);
+    }
 
-    match frame.source_frame_format() {
-        FrameFormat::NV12 => convert_to_rgb_with_dcv(
-            buffer,
+    let upright_size = output_size.map(|size| {
+        let (width, height) = orientation.turned_size(size.width, size.height);
+        OutputSize { width, height, ..size }
+    });
+    let (width, height) = output_resolution(frame.resolution(), upright_size, Orientation::default());
+    let bytes_per_pixel = output_format.bytes_per_pixel();
+
+    let mut upright = vec![0u8; width * bytes_per_pixel * height];
+    scale_frame(frame, output_format, upright_size, color_space, width * bytes_per_pixel, &mut upright)?;
+    orientation::orient(&upright, width, height, bytes_per_pixel, orientation, stride, output);
+
+    Ok(())
+}
+
+fn scale_frame(frame: Buffer, output_format: OutputFormat, output_size: Option<OutputSize>, color_space: YuvColorSpace, stride: usize, output: &mut [u8]) -> Result<(), NokhwaError> {
+    let Some(size) = output_size
+    else { return convert_to_rgb(frame, output_format, color_space, stride, output) };
+
+    let resolution = frame.resolution();
+    let (width, height) = (resolution.width() as usize, resolution.height() as usize);
+    let bytes_per_pixel = output_format.bytes_per_pixel();
+
+    // MJPEG frames are decoded straight to the smallest fraction of their size still covering the output
+    if frame.source_frame_format() == FrameFormat::MJPEG {
+        let eighths = mjpeg::pick_scale(width, height, size.width as usize, size.height as usize);
+        let (scaled_width, scaled_height) = mjpeg::scaled_size(width, height, eighths);
+
+        let mut scaled = vec![0u8; scaled_width * bytes_per_pixel * scaled_height];
+        mjpeg::decode(&frame, eighths, output_format, color_space, scaled_width * bytes_per_pixel, &mut scaled)?;
+        resize::resize(&scaled, scaled_width, scaled_height, bytes_per_pixel, size, stride, output);
+
+        return Ok(());
+    }
+
+    let mut full_size = vec![0u8; width * bytes_per_pixel * height];
+    convert_to_rgb(frame, output_format, color_space, width * bytes_per_pixel, &mut full_size)?;
+    resize::resize(&full_size, width, height, bytes_per_pixel, size, stride, output);
+
+    Ok(())
+}
+
+// Width and height of the frames written by convert_frame for frames of `resolution`, cropped already
+fn output_resolution(resolution: Resolution, output_size: Option<OutputSize>, orientation: Orientation) -> (usize, usize) {
+    match output_size {
+        Some(size) => (size.width as usize, size.height as usize),
+        None => orientation.turned_size(resolution.width() as usize, resolution.height() as usize)
+    }
+}
+
+// Width and height of the frames written by the grab functions of a session
+fn session_output_resolution(session: &Session, settings: &SessionSettings) -> Result<(usize, usize), i32> {
+    let resolution = match settings.crop {
+        Some(crop) => Resolution::new(crop.width, crop.height),
+        None => match session.source.lock().format() {
+            Ok(f) => f.resolution(),
+            Err(err) => return Err(record_nokhwa_error(ERROR_READING_CAMERA_SESSION, "Error reading the session format", &err))
+        }
+    };
+
+    Ok(output_resolution(resolution, settings.output_size, settings.orientation))
+}
+
+/// Converts `frame` into `output`, which holds `height` rows of `stride` bytes. YUV frames are converted with
+/// `color_space`, resolved for the camera frames since `frame` may be cropped.
+fn convert_to_rgb(frame: Buffer, output_format: OutputFormat, color_space: YuvColorSpace, stride: usize, output: &mut [u8]) -> Result<(), NokhwaError> {
+    let resolution = frame.resolution();
+    let row_bytes = resolution.width() as usize * output_format.bytes_per_pixel();
+    let coefficients = color_space.coefficients();
+
+    // Vectorized and strided, grayscale only takes the luma plane below
+    if frame.source_frame_format() == FrameFormat::YUYV && output_format != OutputFormat::Gray8 {
+        return yuyv::convert(frame.buffer(), resolution, PackedYuv::Yuyv, &coefficients, output_format, stride, output);
+    }
+
+    // libjpeg-turbo decodes straight into the strided output, in every output format
+    if frame.source_frame_format() == FrameFormat::MJPEG {
+        return mjpeg::decode(&frame, 8, output_format, color_space, stride, output);
+    }
+
+    if frame.source_frame_format() == FrameFormat::NV12 && output_format != OutputFormat::Gray8 {
+        // DCV supports strided destinations natively
+        return convert_to_rgb_with_dcv(
+            frame.buffer(),
             frame.source_frame_format(),
-            frame.resolution(),
+            resolution,
+            output_format,
+            color_space,
+            stride,
             output,
-        ),
-        _ => {
-            match frame.decode_image_to_buffer::<RgbFormat>(output) {
-                Ok(_) => {
-                    Ok(())
-                }
-                Err(e) => Err(e),
+        );
+    }
+
+    if stride == row_bytes {
+        return convert_packed(&frame, output_format, &coefficients, output);
+    }
+
+    let mut packed = vec![0u8; row_bytes * resolution.height() as usize];
+    convert_packed(&frame, output_format, &coefficients, &mut packed)?;
+
+    for (dst, src) in output.chunks_mut(stride).zip(packed.chunks_exact(row_bytes)) {
+        dst[..row_bytes].copy_from_slice(src);
+    }
+
+    Ok(())
+}
+
+fn convert_packed(frame: &Buffer, output_format: OutputFormat, coefficients: &YuvCoefficients, output: &mut [u8]) -> Result<(), NokhwaError> {
+    match output_format {
+        OutputFormat::Rgb => frame.decode_image_to_buffer::<RgbFormat>(output),
+        OutputFormat::Bgr => {
+            frame.decode_image_to_buffer::<RgbFormat>(output)?;
+            swap_red_blue(output, 3);
+            Ok(())
+        }
+        OutputFormat::Rgba => frame.decode_image_to_buffer::<RgbAFormat>(output),
+        OutputFormat::Bgra => {
+            frame.decode_image_to_buffer::<RgbAFormat>(output)?;
+            swap_red_blue(output, 4);
+            Ok(())
+        }
+        OutputFormat::Gray8 => convert_to_gray(frame, coefficients, output),
+    }
+}
+
+// nokhwa's LumaFormat can't decode into a buffer for most formats, so grayscale is done here.
+// YUV sources just take the luma plane, range-expanded when limited, RGB sources use BT.601 weights.
+fn convert_to_gray(frame: &Buffer, coefficients: &YuvCoefficients, output: &mut [u8]) -> Result<(), NokhwaError> {
+    let resolution = frame.resolution();
+    let pixels = resolution.width() as usize * resolution.height() as usize;
+    let buffer = frame.buffer();
+
+    if output.len() != pixels {
+        return Err(NokhwaError::ProcessFrameError {
+            src: frame.source_frame_format(),
+            destination: "GRAY8".to_string(),
+            error: format!("Output buffer size mismatch: expected {}, got {}", pixels, output.len()),
+        });
+    }
+
+    let not_enough_data = || NokhwaError::ProcessFrameError {
+        src: frame.source_frame_format(),
+        destination: "GRAY8".to_string(),
+        error: format!("Frame buffer too small for {}x{}", resolution.width(), resolution.height()),
+    };
+
+    match frame.source_frame_format() {
+        FrameFormat::GRAY => {
+            let Some(src) = buffer.get(..pixels) else { return Err(not_enough_data()) };
+            output.copy_from_slice(src);
+        }
+        FrameFormat::NV12 => {
+            let Some(src) = buffer.get(..pixels) else { return Err(not_enough_data()) };
+            for (dst, y) in output.iter_mut().zip(src) {
+                *dst = coefficients.gray(*y);
+            }
+        }
+        FrameFormat::YUYV => {
+            if buffer.len() < pixels * 2 {
+                return Err(not_enough_data());
+            }
+            for (dst, yuyv) in output.iter_mut().zip(buffer.chunks_exact(2)) {
+                *dst = coefficients.gray(yuyv[0]);
+            }
+        }
+        FrameFormat::RAWRGB | FrameFormat::RAWBGR | FrameFormat::MJPEG => {
+            let rgb = match frame.source_frame_format() {
+                FrameFormat::MJPEG => frame.decode_image::<RgbFormat>()?.into_raw(),
+                _ => buffer.to_vec(),
+            };
+            if rgb.len() < pixels * 3 {
+                return Err(not_enough_data());
+            }
+            let (r, b) = if frame.source_frame_format() == FrameFormat::RAWBGR { (2, 0) } else { (0, 2) };
+            for (dst, px) in output.iter_mut().zip(rgb.chunks_exact(3)) {
+                let luma = 77 * px[r] as u32 + 150 * px[1] as u32 + 29 * px[b] as u32;
+                *dst = ((luma + 128) >> 8) as u8;
             }
         }
     }
+
+    Ok(())
+}
+
+fn swap_red_blue(output: &mut [u8], bytes_per_pixel: usize) {
+    for px in output.chunks_exact_mut(bytes_per_pixel) {
+        px.swap(0, 2);
+    }
 }
 
-// DCV has faster implementations but only works for NV12 to RGB
+// DCV has faster implementations but only works for NV12 to RGB, RGBA and BGRA
 fn convert_to_rgb_with_dcv(
     buffer: &[u8],
     frame_format: FrameFormat,
     resolution: Resolution,
+    output_format: OutputFormat,
+    color_space: YuvColorSpace,
+    stride: usize,
     output: &mut [u8],
 ) -> Result<(), NokhwaError> {
     let width = resolution.width();
@@ -652,12 +2831,13 @@ fn convert_to_rgb_with_dcv(
     let width_usize = width as usize;
     let height_usize = height as usize;
 
-    let dst_size = width_usize * height_usize * 3; // RGB output
+    let row_bytes = width_usize * output_format.bytes_per_pixel();
+    let dst_size = stride * height_usize;
 
     if output.len() != dst_size {
         return Err(NokhwaError::ProcessFrameError {
             src: frame_format,
-            destination: "RGB".to_string(),
+            destination: output_format.to_string(),
             error: format!(
                 "Output buffer size mismatch: expected {}, got {}",
                 dst_size,
@@ -670,11 +2850,30 @@ fn convert_to_rgb_with_dcv(
         FrameFormat::NV12 => {
             let src_format = ImageFormat {
                 pixel_format: PixelFormat::Nv12,
-                color_space: ColorSpace::Bt601,
+                color_space: match (color_space.matrix, color_space.range) {
+                    (YuvMatrix::Bt709, YuvRange::Full) => ColorSpace::Bt709FR,
+                    (YuvMatrix::Bt709, _) => ColorSpace::Bt709,
+                    (_, YuvRange::Full) => ColorSpace::Bt601FR,
+                    _ => ColorSpace::Bt601,
+                },
                 num_planes: 1,
             };
+
+            // DCV has no NV12 to BGR conversion, it's swizzled from RGB afterwards
+            let (dst_pixel_format, swap) = match output_format {
+                OutputFormat::Rgb => (PixelFormat::Rgb, false),
+                OutputFormat::Bgr => (PixelFormat::Rgb, true),
+                OutputFormat::Rgba => (PixelFormat::Rgba, false),
+                OutputFormat::Bgra => (PixelFormat::Bgra, false),
+                OutputFormat::Gray8 => {
+                    return Err(NokhwaError::NotImplementedError(
+                        "Gray output is not converted with dcv".to_string(),
+                    ));
+                }
+            };
+
             let dst_format = ImageFormat {
-                pixel_format: PixelFormat::Rgb,
+                pixel_format: dst_pixel_format,
                 color_space: ColorSpace::Rgb,
                 num_planes: 1,
             };
@@ -686,14 +2885,20 @@ fn convert_to_rgb_with_dcv(
                 None,
                 &[buffer],
                 &dst_format,
-                None,
+                Some(&[stride]),
                 &mut [&mut output[..]],
             )
                 .map_err(|e| NokhwaError::ProcessFrameError {
                     src: frame_format,
-                    destination: "RGB".to_string(),
+                    destination: output_format.to_string(),
                     error: format!("Conversion error: {:?}", e),
                 })?;
+
+            if swap {
+                for row in output.chunks_mut(stride) {
+                    swap_red_blue(&mut row[..row_bytes], 3);
+                }
+            }
             Ok(())
         }
         _ => Err(NokhwaError::NotImplementedError(format!(
@@ -701,4 +2906,498 @@ fn convert_to_rgb_with_dcv(
             frame_format
         ))),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::{pattern_frames, read_counter, temp_path, TestDevice};
+    use std::ffi::CString;
+
+    const FRAMES: u64 = 4;
+
+    #[test]
+    fn grabs_frames_with_their_info() {
+        let device = TestDevice::start("c-api", &pattern_frames(320, 240, FrameFormat::YUYV, FRAMES));
+        let handle = device.handle;
+
+        assert_eq!(cnokhwa_device_formats_count(handle as i32), 1);
+        device.next_frame();
+
+        let size = cnokhwa_frame_size(handle) as usize;
+        assert_eq!(size, 320 * 240 * 3);
+
+        let mut frame = vec![0u8; size];
+        let mut info = FrameInfo::default();
+        assert_eq!(cnokhwa_grab_frame_with_info(handle, frame.as_mut_ptr(), size, 0, &mut info), RESULT_OK);
+        // Sequence numbers start at 1 while the recording counts frames from 0
+        assert_eq!(read_counter(&frame, 320, 240) as u64, (info.sequence - 1) % FRAMES);
+
+        assert_eq!(cnokhwa_stop_capture(handle), RESULT_OK);
+    }
+
+    #[test]
+    fn grabs_report_the_frame_they_convert() {
+        let format = CameraFormat::new(Resolution::new(64, 48), FrameFormat::YUYV, 30);
+        let mut frame = vec![0u8; 64 * 48 * 3];
+
+        for session in 0..2 {
+            let (device, feed) = TestDevice::manual("frame-info", format);
+            let mut previous = FrameInfo::default();
+            assert_eq!(cnokhwa_grab_frame_with_info(device.handle, frame.as_mut_ptr(), frame.len(), 0, &mut previous), ERROR_READING_FRAME);
+
+            for (n, buffer) in pattern_frames(64, 48, FrameFormat::YUYV, FRAMES).into_iter().enumerate() {
+                assert!(feed.push(buffer));
+
+                let (mut latest, mut info) = (FrameInfo::default(), FrameInfo::default());
+                assert_eq!(cnokhwa_frame_info(device.handle, &mut latest), RESULT_OK);
+                assert_eq!(cnokhwa_grab_frame_with_info(device.handle, frame.as_mut_ptr(), frame.len(), 0, &mut info), RESULT_OK);
+
+                // Every session numbers its frames from 1
+                assert_eq!(info, latest, "session {}", session);
+                assert_eq!(info.sequence, n as u64 + 1);
+                assert_eq!(read_counter(&frame, 64, 48), n as u32);
+                assert!(info.timestamp_us >= previous.timestamp_us && info.wall_clock_us >= previous.wall_clock_us);
+                previous = info;
+            }
+
+            assert_eq!(cnokhwa_stop_capture(device.handle), RESULT_OK);
+            assert!(!feed.push(pattern_frames(64, 48, FrameFormat::YUYV, 1).remove(0)));
+        }
+    }
+
+    #[test]
+    fn waits_for_frames() {
+        let (device, feed) = TestDevice::manual("wait-frame", CameraFormat::new(Resolution::new(64, 48), FrameFormat::YUYV, 30));
+        let handle = device.handle;
+        assert_eq!(cnokhwa_wait_frame(handle, 0, 10), ERROR_TIMEOUT);
+
+        assert!(feed.push(pattern_frames(64, 48, FrameFormat::YUYV, 1).remove(0)));
+        assert_eq!(cnokhwa_wait_frame(handle, 0, 0), RESULT_OK);
+        assert_eq!(cnokhwa_wait_frame(handle, 1, 10), ERROR_TIMEOUT);
+
+        assert_eq!(cnokhwa_stop_capture(handle), RESULT_OK);
+        assert_eq!(cnokhwa_wait_frame(handle, 1, 10), ERROR_SESSION_NOT_STARTED);
+    }
+
+    #[test]
+    fn reports_control_errors() {
+        let (device, _feed) = TestDevice::manual("controls", CameraFormat::new(Resolution::new(64, 48), FrameFormat::YUYV, 30));
+        let mut value = 0.0;
+
+        assert_eq!(cnokhwa_get_control(device.handle, 100, &mut value), ERROR_CONTROL_NOT_FOUND);
+        assert_eq!(cnokhwa_set_control(device.handle, 100, 1.0), ERROR_CONTROL_NOT_FOUND);
+
+        // The source has no controls at all
+        assert_eq!(cnokhwa_get_control(device.handle, 0, &mut value), ERROR_READING_CONTROLS);
+        assert_eq!(cnokhwa_set_control(device.handle, 0, 1.0), ERROR_READING_CONTROLS);
+    }
+
+    #[test]
+    fn grabs_in_every_output_format() {
+        // A flat orange frame
+        let yuyv = virtual_camera::encode(&[200, 100, 50].repeat(64 * 48), 64, 48, FrameFormat::YUYV).unwrap();
+        let device = TestDevice::start("output-formats", &[Buffer::new(Resolution::new(64, 48), &yuyv, FrameFormat::YUYV)]);
+        let handle = device.handle;
+        device.next_frame();
+
+        assert_eq!(cnokhwa_set_output_format(handle, 5), ERROR_INVALID_OUTPUT_FORMAT);
+
+        let expected: [(i32, &[u8]); 5] = [(0, &[200, 100, 50]), (1, &[50, 100, 200]), (2, &[200, 100, 50, 255]), (3, &[50, 100, 200, 255]), (4, &[124])];
+        for (code, pixel) in expected {
+            assert_eq!(cnokhwa_set_output_format(handle, code), RESULT_OK);
+            assert_eq!(cnokhwa_output_format(handle), code);
+            assert_eq!(cnokhwa_frame_bytes_per_row(handle), 64 * pixel.len() as i32);
+            assert_eq!(cnokhwa_frame_size(handle), 64 * 48 * pixel.len() as i32);
+
+            let mut frame = vec![0u8; 64 * 48 * pixel.len()];
+            assert_eq!(cnokhwa_grab_frame(handle, frame.as_mut_ptr(), frame.len()), RESULT_OK);
+            assert!(frame.chunks_exact(pixel.len()).all(|px| px.iter().zip(pixel).all(|(a, b)| a.abs_diff(*b) <= 3)), "{}: {:?}", code, &frame[..pixel.len()]);
+        }
+    }
+
+    // A device replaying the test pattern, once its first frame has arrived
+    fn pattern_device(name: &str, width: u32, height: u32, format: FrameFormat) -> TestDevice {
+        let device = TestDevice::start(name, &pattern_frames(width, height, format, FRAMES));
+        device.next_frame();
+        device
+    }
+
+    // Checks that an RGB image shows one of the replayed test patterns
+    fn assert_pattern(rgb: &[u8], width: u32, height: u32) {
+        assert!(read_counter(rgb, width, height) < FRAMES as u32);
+    }
+
+    // Frame count of the main header of a finished AVI file
+    fn avi_frames(avi: &[u8]) -> u32 {
+        assert_eq!(u32::from_le_bytes(avi[4..8].try_into().unwrap()) as usize, avi.len() - 8);
+        u32::from_le_bytes(avi[48..52].try_into().unwrap())
+    }
+
+    #[test]
+    fn records_and_replays_raw_streams() {
+        let camera = pattern_device("replay-camera", 64, 48, FrameFormat::YUYV);
+
+        let recording = temp_path("replay-recording", "raw");
+        let recording_path = CString::new(recording.to_str().unwrap()).unwrap();
+        assert_eq!(cnokhwa_stop_raw_recording(camera.handle), ERROR_RECORDING_NOT_STARTED);
+        assert_eq!(cnokhwa_start_raw_recording(camera.handle, recording_path.as_ptr()), RESULT_OK);
+        assert_eq!(cnokhwa_start_raw_recording(camera.handle, recording_path.as_ptr()), ERROR_RECORDING_ALREADY_STARTED);
+        for _ in 0..3 {
+            camera.next_frame();
+        }
+        assert_eq!(cnokhwa_stop_raw_recording(camera.handle), RESULT_OK);
+
+        let replay = TestDevice::add(&recording);
+        assert_eq!(cnokhwa_device_formats_count(replay as i32), 1);
+        assert_eq!(cnokhwa_device_format_width(replay as i32, 0), 64);
+        assert_eq!(cnokhwa_start_capture(replay, 64, 48), RESULT_OK);
+        assert_eq!(cnokhwa_wait_frame(replay, 0, test_device::WAIT_MS), RESULT_OK);
+
+        let mut frame = vec![0u8; 64 * 48 * 3];
+        assert_eq!(cnokhwa_grab_frame(replay, frame.as_mut_ptr(), frame.len()), RESULT_OK);
+        assert_pattern(&frame, 64, 48);
+        assert_eq!(cnokhwa_stop_capture(replay), RESULT_OK);
+
+        let _ = std::fs::remove_file(&recording);
+    }
+
+    #[test]
+    fn records_avi_files() {
+        let device = pattern_device("avi", 64, 48, FrameFormat::YUYV);
+        let file = temp_path("avi", "avi");
+        let path = CString::new(file.to_str().unwrap()).unwrap();
+
+        assert_eq!(cnokhwa_stop_recording(device.handle), ERROR_RECORDING_NOT_STARTED);
+        assert_eq!(cnokhwa_start_recording(device.handle, path.as_ptr()), RESULT_OK);
+        assert_eq!(cnokhwa_start_recording(device.handle, path.as_ptr()), ERROR_RECORDING_ALREADY_STARTED);
+        for _ in 0..3 {
+            device.next_frame();
+        }
+        // Recordings are finished when the capture stops
+        assert_eq!(cnokhwa_stop_capture(device.handle), RESULT_OK);
+
+        // YUYV frames are encoded to JPEG
+        let avi = std::fs::read(&file).unwrap();
+        assert!(avi_frames(&avi) >= 2);
+        let chunk = avi.windows(4).position(|w| w == b"00dc").unwrap();
+        assert_eq!(&avi[chunk + 8..chunk + 10], &[0xFF, 0xD8]);
+
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[test]
+    fn dumps_rings() {
+        let device = pattern_device("ring", 64, 48, FrameFormat::YUYV);
+        let file = temp_path("ring", "avi");
+        let path = CString::new(file.to_str().unwrap()).unwrap();
+
+        assert_eq!(cnokhwa_dump_ring(device.handle, path.as_ptr(), 0), ERROR_RING_NOT_STARTED);
+        assert_eq!(cnokhwa_start_ring(device.handle, 0, 0), ERROR_INVALID_RING_SIZE);
+        assert_eq!(cnokhwa_start_ring(device.handle, 10_000, 0), RESULT_OK);
+        for _ in 0..3 {
+            device.next_frame();
+        }
+
+        // Without frames after the event, the file is finished before returning
+        assert_eq!(cnokhwa_dump_ring(device.handle, path.as_ptr(), 0), RESULT_OK);
+        assert!(avi_frames(&std::fs::read(&file).unwrap()) >= 2);
+
+        assert_eq!(cnokhwa_stop_ring(device.handle), RESULT_OK);
+        assert_eq!(cnokhwa_stop_ring(device.handle), ERROR_RING_NOT_STARTED);
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[test]
+    fn takes_snapshots() {
+        let device = pattern_device("snapshot", 320, 240, FrameFormat::NV12);
+
+        // Size query, then the same image copied into a buffer
+        let png_size = cnokhwa_snapshot_to_buffer(device.handle, SnapshotFormat::Png as i32, 0, ptr::null_mut(), 0);
+        assert!(png_size > 0);
+        let mut png = vec![0u8; png_size as usize];
+        assert_eq!(cnokhwa_snapshot_to_buffer(device.handle, SnapshotFormat::Png as i32, 0, png.as_mut_ptr(), png.len()), png_size);
+        let snapshot = image::load_from_memory_with_format(&png, image::ImageFormat::Png).unwrap().to_rgb8();
+        assert_eq!(snapshot.dimensions(), (320, 240));
+        assert_pattern(snapshot.as_raw(), 320, 240);
+
+        let file = temp_path("snapshot", "jpg");
+        let path = CString::new(file.to_str().unwrap()).unwrap();
+        assert_eq!(cnokhwa_snapshot(device.handle, SnapshotFormat::Jpeg as i32, 90, path.as_ptr()), RESULT_OK);
+        assert_eq!(image::open(&file).unwrap().to_rgb8().dimensions(), (320, 240));
+        assert_eq!(cnokhwa_snapshot(device.handle, 7, 90, path.as_ptr()), ERROR_INVALID_SNAPSHOT_FORMAT);
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[test]
+    fn scales_frames() {
+        let device = pattern_device("resize", 320, 240, FrameFormat::YUYV);
+        let handle = device.handle;
+
+        // For a single grab
+        let mut small = vec![0u8; 160 * 120 * 3];
+        assert_eq!(cnokhwa_grab_frame_scaled(handle, 160, 120, Interpolation::Area as i32, ScaleMode::Stretch as i32, small.as_mut_ptr(), small.len(), 0), RESULT_OK);
+        assert_pattern(&small, 160, 120);
+        assert_eq!(cnokhwa_grab_frame_scaled(handle, 160, 0, 0, 0, small.as_mut_ptr(), small.len(), 0), ERROR_INVALID_OUTPUT_SIZE);
+        assert_eq!(cnokhwa_grab_frame_scaled(handle, 160, 120, 3, 0, small.as_mut_ptr(), small.len(), 0), ERROR_INVALID_OUTPUT_SIZE);
+        assert_eq!(cnokhwa_set_output_size(handle, MAX_OUTPUT_SIDE + 1, 120, 0, 0), ERROR_INVALID_OUTPUT_SIZE);
+        assert_eq!(cnokhwa_set_output_size(handle, u32::MAX, u32::MAX, 0, 0), ERROR_INVALID_OUTPUT_SIZE);
+
+        // For the whole session
+        assert_eq!(cnokhwa_set_output_size(handle, 224, 224, Interpolation::Bilinear as i32, ScaleMode::Letterbox as i32), RESULT_OK);
+        assert_eq!((cnokhwa_frame_width(handle), cnokhwa_frame_height(handle)), (224, 224));
+        assert_eq!(cnokhwa_frame_size(handle), 224 * 224 * 3);
+        let mut square = vec![0u8; 224 * 224 * 3];
+        assert_eq!(cnokhwa_grab_frame(handle, square.as_mut_ptr(), square.len()), RESULT_OK);
+        // The top letterbox bar
+        assert!(square[..224 * 3].iter().all(|&c| c == 0));
+
+        assert_eq!(cnokhwa_set_output_size(handle, 0, 0, 0, 0), RESULT_OK);
+        assert_eq!(cnokhwa_frame_size(handle), 320 * 240 * 3);
+    }
+
+    #[test]
+    fn crops_frames() {
+        let device = pattern_device("crop", 320, 240, FrameFormat::YUYV);
+        let handle = device.handle;
+
+        // YUYV rectangles start on even columns and must fit in the frame
+        assert_eq!(cnokhwa_set_crop(handle, 1, 0, 160, 120), ERROR_INVALID_CROP);
+        assert_eq!(cnokhwa_set_crop(handle, 0, 0, 322, 120), ERROR_INVALID_CROP);
+
+        // The top of the frame keeps the counter band, at the full width
+        assert_eq!(cnokhwa_set_crop(handle, 0, 0, 320, 120), RESULT_OK);
+        assert_eq!(cnokhwa_frame_height(handle), 120);
+        assert_eq!(cnokhwa_frame_bytes_per_row(handle), 320 * 3);
+        let mut top = vec![0u8; 320 * 120 * 3];
+        assert_eq!(cnokhwa_grab_frame(handle, top.as_mut_ptr(), top.len()), RESULT_OK);
+        assert_pattern(&top, 320, 240);
+
+        assert_eq!(cnokhwa_set_crop(handle, 0, 0, 0, 0), RESULT_OK);
+        assert_eq!(cnokhwa_frame_size(handle), 320 * 240 * 3);
+    }
+
+    #[test]
+    fn turns_frames() {
+        let device = pattern_device("orientation", 320, 240, FrameFormat::YUYV);
+        let handle = device.handle;
+
+        assert_eq!(cnokhwa_set_orientation(handle, 45, 0, 0), ERROR_INVALID_ORIENTATION);
+        assert_eq!(cnokhwa_set_orientation(handle, 90, 1, 0), RESULT_OK);
+        assert_eq!((cnokhwa_frame_width(handle), cnokhwa_frame_height(handle)), (240, 320));
+
+        // Turned twice, the counter reads the same
+        assert_eq!(cnokhwa_set_orientation(handle, 180, 1, 1), RESULT_OK);
+        assert_eq!((cnokhwa_frame_width(handle), cnokhwa_frame_height(handle)), (320, 240));
+        let mut turned = vec![0u8; 320 * 240 * 3];
+        assert_eq!(cnokhwa_grab_frame(handle, turned.as_mut_ptr(), turned.len()), RESULT_OK);
+        assert_pattern(&turned, 320, 240);
+
+        assert_eq!(cnokhwa_set_orientation(handle, 0, 0, 0), RESULT_OK);
+    }
+
+    #[test]
+    fn reports_corrupt_mjpeg_frames() {
+        let jpeg = pattern_frames(320, 240, FrameFormat::MJPEG, 1).remove(0);
+        let truncated = Buffer::new(Resolution::new(320, 240), &jpeg.buffer()[..jpeg.buffer().len() / 2], FrameFormat::MJPEG);
+        let device = TestDevice::start("mjpeg-truncated", &[truncated]);
+        assert_eq!(cnokhwa_wait_frame(device.handle, 0, test_device::WAIT_MS), RESULT_OK);
+
+        let mut frame = vec![0u8; 320 * 240 * 3];
+        assert_eq!(cnokhwa_grab_frame(device.handle, frame.as_mut_ptr(), frame.len()), ERROR_CORRUPT_FRAME);
+
+        // Scaled grabs decode the frame themselves
+        let mut small = vec![0u8; 80 * 60 * 3];
+        assert_eq!(cnokhwa_grab_frame_scaled(device.handle, 80, 60, 0, 0, small.as_mut_ptr(), small.len(), 0), ERROR_CORRUPT_FRAME);
+    }
+
+    // Grabs a flat reddish frame in every matrix and range, whose RGB values depend on both
+    fn converts_color_spaces(name: &str, format: FrameFormat, data: &[u8], tolerance: u8) {
+        let device = TestDevice::start(name, &[Buffer::new(Resolution::new(64, 48), data, format)]);
+        device.next_frame();
+
+        assert_eq!(cnokhwa_set_color_space(device.handle, 3, 0), ERROR_INVALID_COLOR_SPACE);
+        assert_eq!(cnokhwa_set_color_space(device.handle, 0, 3), ERROR_INVALID_COLOR_SPACE);
+
+        let grab_pixel = |matrix, range| {
+            assert_eq!(cnokhwa_set_color_space(device.handle, matrix, range), RESULT_OK);
+            let mut rgb = vec![0u8; 64 * 48 * 3];
+            assert_eq!(cnokhwa_grab_frame(device.handle, rgb.as_mut_ptr(), rgb.len()), RESULT_OK);
+            assert!(rgb.chunks_exact(3).all(|px| px == &rgb[..3]));
+            [rgb[0], rgb[1], rgb[2]]
+        };
+
+        let spaces = [(1, 1, YuvMatrix::Bt601, YuvRange::Limited), (2, 1, YuvMatrix::Bt709, YuvRange::Limited), (1, 2, YuvMatrix::Bt601, YuvRange::Full), (2, 2, YuvMatrix::Bt709, YuvRange::Full)];
+        let pixels: Vec<[u8; 3]> = spaces.iter().map(|&(matrix, range, expected_matrix, expected_range)| {
+            let pixel = grab_pixel(matrix, range);
+            let expected = YuvColorSpace { matrix: expected_matrix, range: expected_range }.coefficients().to_rgb(REDDISH[0], REDDISH[1], REDDISH[2]);
+            assert!(pixel.iter().zip(expected).all(|(a, b)| a.abs_diff(b) <= tolerance), "{:?} {:?}: {:?} {:?}", expected_matrix, expected_range, pixel, expected);
+            pixel
+        }).collect();
+
+        // Every matrix and range gives its own color, and below 720 lines the default is BT.601 limited range
+        for (i, a) in pixels.iter().enumerate() {
+            for b in &pixels[i + 1..] {
+                assert!(a.iter().zip(b).any(|(a, b)| a.abs_diff(*b) > 2 * tolerance), "{:?} {:?}", a, b);
+            }
+        }
+        assert_eq!(grab_pixel(0, 0), pixels[0]);
+    }
+
+    // Y, U and V of the frames converted in every color space
+    const REDDISH: [u8; 3] = [120, 100, 190];
+
+    #[test]
+    fn converts_yuyv_in_every_color_space() {
+        let [y, u, v] = REDDISH;
+        converts_color_spaces("color-space-yuyv", FrameFormat::YUYV, &[y, u, y, v].repeat(64 * 48 / 2), 0);
+    }
+
+    #[test]
+    fn converts_nv12_in_every_color_space() {
+        let [y, u, v] = REDDISH;
+        let mut nv12 = vec![y; 64 * 48];
+        nv12.extend([u, v].repeat(64 * 48 / 4));
+        // DCV rounds on its own
+        converts_color_spaces("color-space-nv12", FrameFormat::NV12, &nv12, 2);
+    }
+
+    // Shared with a frame callback through its user data
+    #[derive(Default)]
+    struct CallbackProbe {
+        entered: Mutex<bool>,
+        entered_changed: parking_lot::Condvar,
+        busy: std::sync::atomic::AtomicBool,
+    }
+
+    extern "C" fn slow_callback(user_data: *mut c_void, _frame: *const CnokhwaFrame) {
+        let probe = unsafe { &*(user_data as *const CallbackProbe) };
+        probe.busy.store(true, std::sync::atomic::Ordering::SeqCst);
+        *probe.entered.lock() = true;
+        probe.entered_changed.notify_all();
+
+        std::thread::sleep(Duration::from_millis(50));
+        probe.busy.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    #[test]
+    fn removing_the_frame_callback_waits_for_a_call_in_progress() {
+        let device = TestDevice::start("frame-callback", &pattern_frames(64, 48, FrameFormat::YUYV, FRAMES));
+        let probe = CallbackProbe::default();
+        let user_data = &probe as *const CallbackProbe as *mut c_void;
+        assert_eq!(cnokhwa_set_frame_callback(device.handle, Some(slow_callback), user_data), RESULT_OK);
+
+        let mut entered = probe.entered.lock();
+        let deadline = Instant::now() + Duration::from_millis(test_device::WAIT_MS as u64);
+        while !*entered {
+            assert!(!probe.entered_changed.wait_until(&mut entered, deadline).timed_out());
+        }
+        drop(entered);
+
+        assert_eq!(cnokhwa_set_frame_callback(device.handle, None, ptr::null_mut()), RESULT_OK);
+        assert!(!probe.busy.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn http_servers_need_an_ip_address() {
+        let address = CString::new("camera.local").unwrap();
+        assert_eq!(cnokhwa_start_http_server_on(address.as_ptr(), 0), ERROR_HTTP_SERVER_IO);
+        assert_eq!(cnokhwa_start_http_server_on(ptr::null(), 0), ERROR_BUFFER_NULL);
+    }
+
+    #[test]
+    fn copies_the_last_error_message() {
+        record_error(ERROR_DEVICE_NOT_FOUND, "No device at index 7");
+        assert_eq!(cnokhwa_last_error_code(), ERROR_DEVICE_NOT_FOUND);
+
+        let mut message = [b'x' as c_char; 32];
+        assert_eq!(cnokhwa_last_error_message(message.as_mut_ptr(), message.len()), 20);
+        assert_eq!(unsafe { CStr::from_ptr(message.as_ptr()) }.to_str().unwrap(), "No device at index 7");
+
+        // Messages are cut to leave room for the terminating NUL
+        let mut message = [b'x' as c_char; 8];
+        assert_eq!(cnokhwa_last_error_message(message.as_mut_ptr(), message.len()), 7);
+        assert_eq!(unsafe { CStr::from_ptr(message.as_ptr()) }.to_str().unwrap(), "No devi");
+
+        assert_eq!(cnokhwa_last_error_message(message.as_mut_ptr(), 0), 0);
+        assert_eq!(message[0], b'N' as c_char);
+
+        let mut kind = [b'x' as c_char; 8];
+        assert_eq!(cnokhwa_last_error_kind(kind.as_mut_ptr(), kind.len()), 0);
+        assert_eq!(kind[0], 0);
+        assert_eq!(cnokhwa_last_error_os_code(), 0);
+    }
+
+    #[test]
+    fn prefers_the_profile_format() {
+        let format = |index, format, frame_rate| VideoFormat { index, width: 640, height: 480, format, frame_rate };
+        let formats = [format(0, FrameFormat::MJPEG, 30), format(1, FrameFormat::YUYV, 30), format(2, FrameFormat::YUYV, 15)];
+        let saved = |frame_rate| ProfileFormat { width: 640, height: 480, format: "MJPEG".to_string(), frame_rate };
+
+        assert_eq!(pick_format(&formats, 640, 480, None).map(|f| f.index), Some(1));
+        assert_eq!(pick_format(&formats, 640, 480, Some(&saved(30))).map(|f| f.index), Some(0));
+        // A profile format the device doesn't have, or not of the requested size, leaves the choice unchanged
+        assert_eq!(pick_format(&formats, 640, 480, Some(&saved(60))).map(|f| f.index), Some(1));
+        assert_eq!(pick_format(&formats, 320, 240, Some(&saved(30))).map(|f| f.index), None);
+    }
+
+    #[test]
+    fn reports_profile_formats_not_applied() {
+        let directory = temp_path("profile-format", "d");
+        let unique_id = "manual:profile-format";
+        profile::save_profile(&directory, &profile::DeviceProfile {
+            unique_id: unique_id.to_string(),
+            name: "profile-format".to_string(),
+            format: Some(ProfileFormat { width: 64, height: 48, format: "MJPEG".to_string(), frame_rate: 30 }),
+            controls: vec![],
+        }).unwrap();
+
+        let path = CString::new(directory.to_str().unwrap()).unwrap();
+        assert_eq!(cnokhwa_set_profiles_directory(path.as_ptr()), RESULT_OK);
+        let (device, _feed) = TestDevice::manual("profile-format", CameraFormat::new_from(64, 48, FrameFormat::YUYV, 30));
+        assert_eq!(cnokhwa_set_profiles_directory(ptr::null()), RESULT_OK);
+
+        let handle = device.handle as i32;
+        assert_eq!(cnokhwa_profile_failures_count(handle), 1);
+        let mut message = [0 as c_char; 128];
+        cnokhwa_profile_failure(handle, 0, message.as_mut_ptr(), message.len());
+        let message = unsafe { CStr::from_ptr(message.as_ptr()) }.to_str().unwrap();
+        assert_eq!(message, "format: the capture is not running at 64x48 MJPEG 30fps");
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(not(feature = "h264"))]
+    #[test]
+    fn h264_recordings_need_the_feature() {
+        let device = TestDevice::start("h264-unavailable", &pattern_frames(64, 48, FrameFormat::YUYV, 1));
+        let path = CString::new(temp_path("h264-unavailable", "mp4").to_str().unwrap()).unwrap();
+
+        assert_eq!(cnokhwa_start_h264_recording(device.handle, path.as_ptr(), 1, 500, 2), ERROR_H264_NOT_AVAILABLE);
+        assert_eq!(cnokhwa_stop_h264_recording(device.handle), ERROR_H264_NOT_AVAILABLE);
+    }
+
+    #[cfg(feature = "h264")]
+    #[test]
+    fn records_mp4_files() {
+        let device = pattern_device("h264", 64, 48, FrameFormat::YUYV);
+        let file = temp_path("h264", "mp4");
+        let path = CString::new(file.to_str().unwrap()).unwrap();
+
+        assert_eq!(cnokhwa_start_h264_recording(device.handle, path.as_ptr(), 2, 0, 0), ERROR_INVALID_H264_SETTINGS);
+        assert_eq!(cnokhwa_start_h264_recording(device.handle, path.as_ptr(), H264Container::Mp4 as i32, 500, 2), RESULT_OK);
+        assert_eq!(cnokhwa_start_h264_recording(device.handle, path.as_ptr(), H264Container::Mp4 as i32, 500, 2), ERROR_RECORDING_ALREADY_STARTED);
+        for _ in 0..4 {
+            device.next_frame();
+        }
+        // Recordings are finished when the capture stops
+        assert_eq!(cnokhwa_stop_capture(device.handle), RESULT_OK);
+
+        // Keyframes every two frames start a new fragment
+        let mp4 = std::fs::read(&file).unwrap();
+        assert_eq!(&mp4[4..8], b"ftyp");
+        assert!(mp4.windows(4).filter(|w| w == b"moof").count() >= 2);
+
+        let _ = std::fs::remove_file(&file);
+    }
+}
@@ -0,0 +1,246 @@
+use crate::color_space::{self, YuvColorSpace, YuvRange};
+use crate::output_format::OutputFormat;
+use mozjpeg::{ColorSpace, Decompress};
+use mozjpeg_sys::{jpeg_common_struct, jpeg_error_mgr, jpeg_std_error};
+use mozjpeg_sys::{JWRN_HIT_MARKER, JWRN_HUFF_BAD_CODE, JWRN_JPEG_EOF, JWRN_MUST_RESYNC};
+use nokhwa::utils::FrameFormat;
+use nokhwa::{Buffer, NokhwaError};
+use std::mem;
+use std::os::raw::c_int;
+use std::panic::{self, AssertUnwindSafe};
+
+/// The smallest fraction of the full size, in eighths, at which a `width` x `height` JPEG is still at least
+/// `min_width` x `min_height`. libjpeg decodes at 1/2, 1/4 and 1/8 by dropping DCT coefficients, which is
+/// much cheaper than decoding the whole frame to shrink it afterwards.
+pub fn pick_scale(width: usize, height: usize, min_width: usize, min_height: usize) -> u8 {
+    [1, 2, 4]
+        .into_iter()
+        .find(|&eighths| {
+            let (scaled_width, scaled_height) = scaled_size(width, height, eighths);
+            scaled_width >= min_width && scaled_height >= min_height
+        })
+        .unwrap_or(8)
+}
+
+/// Size of a `width` x `height` JPEG decoded at `eighths` / 8, rounded up like libjpeg does.
+pub fn scaled_size(width: usize, height: usize, eighths: u8) -> (usize, usize) {
+    let eighths = eighths as usize;
+    ((width * eighths).div_ceil(8), (height * eighths).div_ceil(8))
+}
+
+/// Decodes an MJPEG frame at `eighths` / 8 of its size into `output`, which holds rows of `stride` bytes, with a
+/// resolved color space. Frames that are truncated, corrupt or of another size than their resolution fail with an
+/// error recognized by `is_corrupt_frame`. A frame is only missing its end marker when all its rows decoded, and is kept.
+pub fn decode(frame: &Buffer, eighths: u8, output_format: OutputFormat, color_space: YuvColorSpace, stride: usize, output: &mut [u8]) -> Result<(), NokhwaError> {
+    // libjpeg errors unwind out of the decoder, see error_manager
+    match panic::catch_unwind(AssertUnwindSafe(|| decode_rows(frame, eighths, output_format, color_space, stride, output))) {
+        Ok(decoded) => decoded,
+        Err(payload) => {
+            let message = payload.downcast::<String>().map(|message| *message).unwrap_or_else(|_| "libjpeg error".to_string());
+            Err(corrupt_frame(message))
+        }
+    }
+}
+
+/// Whether `error` comes from `decode` failing on a truncated or corrupt frame, the only conversion failures
+/// reported as `ReadFrameError`.
+pub fn is_corrupt_frame(error: &NokhwaError) -> bool {
+    matches!(error, NokhwaError::ReadFrameError(_))
+}
+
+fn decode_rows(frame: &Buffer, eighths: u8, output_format: OutputFormat, color_space: YuvColorSpace, stride: usize, output: &mut [u8]) -> Result<(), NokhwaError> {
+    let (width, height) = (frame.resolution().width() as usize, frame.resolution().height() as usize);
+    let mut decompress = Decompress::with_err(error_manager())
+        .from_mem(frame.buffer())
+        .map_err(|e| corrupt_frame(e.to_string()))?;
+
+    if decompress.size() != (width, height) {
+        let (jpeg_width, jpeg_height) = decompress.size();
+        return Err(corrupt_frame(format!("The JPEG is {}x{} in {}x{} frames", jpeg_width, jpeg_height, width, height)));
+    }
+
+    decompress.scale(eighths);
+
+    // libjpeg only converts with the JFIF color space, the others are converted here from its YCbCr output
+    let jfif = color_space == YuvColorSpace::JFIF;
+    let jpeg_color_space = match output_format {
+        OutputFormat::Gray8 => ColorSpace::JCS_GRAYSCALE,
+        _ if !jfif => ColorSpace::JCS_YCbCr,
+        OutputFormat::Rgb => ColorSpace::JCS_RGB,
+        OutputFormat::Bgr => ColorSpace::JCS_EXT_BGR,
+        OutputFormat::Rgba => ColorSpace::JCS_EXT_RGBA,
+        OutputFormat::Bgra => ColorSpace::JCS_EXT_BGRA,
+    };
+    let mut started = decompress.to_colorspace(jpeg_color_space).map_err(|e| corrupt_frame(e.to_string()))?;
+
+    let row_bytes = started.width() * output_format.bytes_per_pixel();
+    let rows = started.height();
+    if stride < row_bytes || output.len() < stride * (rows - 1) + row_bytes {
+        return Err(NokhwaError::ProcessFrameError {
+            src: FrameFormat::MJPEG,
+            destination: output_format.to_string(),
+            error: format!("Output buffer too small for {}x{}", started.width(), rows),
+        });
+    }
+
+    let coefficients = color_space.coefficients();
+    let mut ycbcr = vec![0u8; if jfif { 0 } else { started.width() * 3 }];
+
+    for row in output.chunks_mut(stride).take(rows) {
+        let row = &mut row[..row_bytes];
+
+        if output_format != OutputFormat::Gray8 && !jfif {
+            started.read_scanlines_into(&mut ycbcr).map_err(|e| corrupt_frame(e.to_string()))?;
+            for (px, yuv) in row.chunks_exact_mut(output_format.bytes_per_pixel()).zip(ycbcr.chunks_exact(3)) {
+                color_space::write_pixel(coefficients.to_rgb(yuv[0], yuv[1], yuv[2]), output_format, px);
+            }
+            continue;
+        }
+
+        started.read_scanlines_into(row).map_err(|e| corrupt_frame(e.to_string()))?;
+        if output_format == OutputFormat::Gray8 && color_space.range == YuvRange::Limited {
+            for px in row.iter_mut() {
+                *px = coefficients.gray(*px);
+            }
+        }
+    }
+
+    // Not finishing the decompression skips the checks of what follows the last row
+    Ok(())
+}
+
+// The frame itself is unreadable, conversions fail with other variants for everything else
+fn corrupt_frame(error: String) -> NokhwaError {
+    NokhwaError::ReadFrameError(format!("Corrupt JPEG frame: {}", error))
+}
+
+// Like mozjpeg's own error manager, which unwinds on errors and ignores warnings, except that the warnings of
+// truncated or corrupt data unwind too. libjpeg would otherwise fill the missing blocks with gray.
+fn error_manager() -> jpeg_error_mgr {
+    unsafe {
+        let mut err: jpeg_error_mgr = mem::zeroed();
+        jpeg_std_error(&mut err);
+        err.error_exit = Some(unwind_error);
+        err.emit_message = Some(unwind_corrupt_data_warning);
+        err
+    }
+}
+
+extern "C-unwind" fn unwind_error(cinfo: &mut jpeg_common_struct) {
+    panic::resume_unwind(Box::new(message(cinfo)));
+}
+
+extern "C-unwind" fn unwind_corrupt_data_warning(cinfo: &mut jpeg_common_struct, level: c_int) {
+    // Data between markers is skipped harmlessly, a few cameras pad every frame with it
+    const CORRUPT_DATA: [c_int; 4] = [JWRN_JPEG_EOF, JWRN_HIT_MARKER, JWRN_HUFF_BAD_CODE, JWRN_MUST_RESYNC];
+
+    // Negative levels are warnings, the others trace messages
+    let code = unsafe { cinfo.err.as_ref() }.map(|err| err.msg_code);
+    if level < 0 && code.is_some_and(|code| CORRUPT_DATA.contains(&code)) {
+        panic::resume_unwind(Box::new(message(cinfo)));
+    }
+}
+
+fn message(cinfo: &mut jpeg_common_struct) -> String {
+    let Some((format_message, code)) = (unsafe { cinfo.err.as_ref() }).map(|err| (err.format_message, err.msg_code))
+    else { return "libjpeg error".to_string() };
+
+    let Some(format_message) = format_message
+    else { return format!("libjpeg error {}", code) };
+
+    // The binding declares the message buffer immutable, while libjpeg writes the message into it
+    let format_message = unsafe {
+        mem::transmute::<
+            unsafe extern "C-unwind" fn(&mut jpeg_common_struct, &[u8; 80]),
+            unsafe extern "C-unwind" fn(&mut jpeg_common_struct, &mut [u8; 80]),
+        >(format_message)
+    };
+    let mut buffer = [0u8; 80];
+    unsafe { format_message(cinfo, &mut buffer) };
+
+    let length = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::jpeg::JpegEncoder;
+    use crate::color_space::YuvMatrix;
+    use image::ExtendedColorType;
+    use nokhwa::utils::Resolution;
+
+    // 32x16, left half red and right half blue
+    fn jpeg() -> Vec<u8> {
+        let rgb: Vec<u8> = (0..32 * 16).flat_map(|i| if i % 32 < 16 { [255, 0, 0] } else { [0, 0, 255] }).collect();
+        let mut jpeg = vec![];
+        JpegEncoder::new_with_quality(&mut jpeg, 95).encode(&rgb, 32, 16, ExtendedColorType::Rgb8).unwrap();
+        jpeg
+    }
+
+    fn frame(jpeg: &[u8], width: u32, height: u32) -> Buffer {
+        Buffer::new(Resolution::new(width, height), jpeg, FrameFormat::MJPEG)
+    }
+
+    #[test]
+    fn picks_the_smallest_scale_covering_the_output() {
+        assert_eq!(pick_scale(640, 480, 80, 60), 1);
+        assert_eq!(pick_scale(640, 480, 81, 60), 2);
+        assert_eq!(pick_scale(640, 480, 224, 224), 4);
+        assert_eq!(pick_scale(640, 480, 400, 300), 8);
+        assert_eq!(scaled_size(642, 481, 1), (81, 61));
+    }
+
+    #[test]
+    fn decodes_scaled_into_strided_rows() {
+        let mut output = vec![7u8; 4 * 36];
+        let too_small = decode(&frame(&jpeg(), 32, 16), 2, OutputFormat::Bgra, YuvColorSpace::JFIF, 36, &mut output[..3 * 36]).unwrap_err();
+        assert!(!is_corrupt_frame(&too_small));
+
+        decode(&frame(&jpeg(), 32, 16), 2, OutputFormat::Bgra, YuvColorSpace::JFIF, 36, &mut output).unwrap();
+
+        for row in output.chunks_exact(36) {
+            let (first, last) = (&row[..4], &row[28..32]);
+            assert!(first[2] > 240 && first[0] < 16 && first[3] == 255, "{:?}", first);
+            assert!(last[0] > 240 && last[2] < 16, "{:?}", last);
+            assert_eq!(&row[32..], &[7, 7, 7, 7]);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_and_mismatched_frames() {
+        let jpeg = jpeg();
+        let mut output = vec![0u8; 32 * 16 * 3];
+
+        let truncated = decode(&frame(&jpeg[..jpeg.len() / 2], 32, 16), 8, OutputFormat::Rgb, YuvColorSpace::JFIF, 96, &mut output).unwrap_err();
+        assert!(is_corrupt_frame(&truncated), "{}", truncated);
+
+        let garbage = decode(&frame(&[0xFF, 0xD8, 0x12, 0x34], 32, 16), 8, OutputFormat::Rgb, YuvColorSpace::JFIF, 96, &mut output).unwrap_err();
+        assert!(is_corrupt_frame(&garbage), "{}", garbage);
+
+        let resized = decode(&frame(&jpeg, 16, 16), 8, OutputFormat::Rgb, YuvColorSpace::JFIF, 48, &mut output).unwrap_err();
+        assert!(is_corrupt_frame(&resized), "{}", resized);
+
+        // Losing only the end marker keeps the frame
+        decode(&frame(&jpeg[..jpeg.len() - 2], 32, 16), 8, OutputFormat::Rgb, YuvColorSpace::JFIF, 96, &mut output).unwrap();
+    }
+
+    #[test]
+    fn converts_other_color_spaces_itself() {
+        let jpeg = frame(&jpeg(), 32, 16);
+        let mut jfif = vec![0u8; 32 * 16 * 3];
+        let mut bt709 = vec![0u8; 32 * 16 * 3];
+        decode(&jpeg, 8, OutputFormat::Rgb, YuvColorSpace::JFIF, 96, &mut jfif).unwrap();
+        decode(&jpeg, 8, OutputFormat::Rgb, YuvColorSpace { matrix: YuvMatrix::Bt709, range: YuvRange::Full }, 96, &mut bt709).unwrap();
+
+        // The BT.601 red has some green in BT.709
+        assert!(jfif[1] < 8 && bt709[1] > 16, "{:?} {:?}", &jfif[..3], &bt709[..3]);
+
+        let limited = YuvColorSpace { matrix: YuvMatrix::Bt601, range: YuvRange::Limited };
+        let mut gray = vec![0u8; 32 * 16];
+        let mut expanded = vec![0u8; 32 * 16];
+        decode(&jpeg, 8, OutputFormat::Gray8, YuvColorSpace::JFIF, 32, &mut gray).unwrap();
+        decode(&jpeg, 8, OutputFormat::Gray8, limited, 32, &mut expanded).unwrap();
+        assert_eq!(expanded, gray.iter().map(|&y| limited.coefficients().gray(y)).collect::<Vec<_>>());
+    }
+}
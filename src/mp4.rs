@@ -0,0 +1,429 @@
+use std::io::{self, ErrorKind, Write};
+
+// Sample times are stored in 90 kHz ticks, the usual video timescale
+const TIMESCALE: u64 = 90_000;
+const TRACK_ID: u32 = 1;
+
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_AUD: u8 = 9;
+
+// trun sample flags
+const SAMPLE_FLAGS_SYNC: u32 = 0x0200_0000;
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x0101_0000;
+
+struct Sample {
+    timestamp_us: u64,
+    // Length prefixed NAL units
+    data: Vec<u8>,
+    keyframe: bool,
+}
+
+/// Writes H.264 access units into a fragmented MP4 file, one fragment per group of pictures.
+/// Fragments are complete on their own, so a file cut short by a crash plays up to its last full fragment.
+/// The headers are written with the first keyframe, which must carry the SPS and PPS; frames before it are dropped.
+/// Dropping the writer finishes the file too, errors are ignored then.
+pub struct Mp4Writer<W: Write> {
+    output: W,
+    width: u32,
+    height: u32,
+    frame_duration_us: u64,
+    header_written: bool,
+    fragments: u32,
+    decode_time: u64,
+    pending: Vec<Sample>,
+    finished: bool,
+}
+
+impl<W: Write> Mp4Writer<W> {
+    pub fn new(output: W, width: u32, height: u32, frame_rate: u32) -> Mp4Writer<W> {
+        Mp4Writer {
+            output,
+            width,
+            height,
+            frame_duration_us: 1_000_000 / frame_rate.max(1) as u64,
+            header_written: false,
+            fragments: 0,
+            decode_time: 0,
+            pending: vec![],
+            finished: false,
+        }
+    }
+
+    /// Adds an access unit in Annex-B format (start code delimited NAL units) captured at `timestamp_us`.
+    pub fn write_frame(&mut self, timestamp_us: u64, annex_b: &[u8]) -> io::Result<()> {
+        let mut sps = None;
+        let mut pps = None;
+        let mut keyframe = false;
+        let mut data = vec![];
+
+        for nal in nal_units(annex_b) {
+            match nal[0] & 0x1F {
+                NAL_SPS => sps = Some(nal),
+                NAL_PPS => pps = Some(nal),
+                NAL_AUD => {}
+                nal_type => {
+                    keyframe |= nal_type == NAL_IDR;
+                    data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    data.extend_from_slice(nal);
+                }
+            }
+        }
+
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        if !self.header_written {
+            let (Some(sps), Some(pps), true) = (sps, pps, keyframe) else { return Ok(()) };
+            self.write_header(sps, pps)?;
+        }
+
+        if let Some(last) = self.pending.last() {
+            if timestamp_us <= last.timestamp_us {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "Frame timestamps must increase"));
+            }
+        }
+
+        if keyframe && !self.pending.is_empty() {
+            self.write_fragment(Some(timestamp_us))?;
+        }
+
+        self.pending.push(Sample { timestamp_us, data, keyframe });
+
+        Ok(())
+    }
+
+    /// Writes the last fragment and flushes the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finalize()
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        if !self.pending.is_empty() {
+            self.write_fragment(None)?;
+        }
+
+        self.output.flush()
+    }
+
+    fn write_header(&mut self, sps: &[u8], pps: &[u8]) -> io::Result<()> {
+        if sps.len() < 4 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Invalid SPS"));
+        }
+
+        let mut ftyp = vec![];
+        ftyp.extend_from_slice(b"isom");
+        put_u32(&mut ftyp, 0x200);
+        for brand in [b"isom", b"iso5", b"iso6", b"avc1", b"mp41"] {
+            ftyp.extend_from_slice(brand);
+        }
+
+        let mut avcc = vec![1, sps[1], sps[2], sps[3], 0xFF, 0xE1];
+        avcc.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+        avcc.extend_from_slice(sps);
+        avcc.push(1);
+        avcc.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+        avcc.extend_from_slice(pps);
+
+        let mut avc1 = vec![0u8; 6];
+        avc1.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+        avc1.extend_from_slice(&[0u8; 16]);
+        avc1.extend_from_slice(&(self.width as u16).to_be_bytes());
+        avc1.extend_from_slice(&(self.height as u16).to_be_bytes());
+        put_u32(&mut avc1, 0x0048_0000); // 72 dpi
+        put_u32(&mut avc1, 0x0048_0000);
+        put_u32(&mut avc1, 0);
+        avc1.extend_from_slice(&1u16.to_be_bytes()); // frame count
+        avc1.extend_from_slice(&[0u8; 32]); // compressor name
+        avc1.extend_from_slice(&0x18u16.to_be_bytes()); // depth
+        avc1.extend_from_slice(&(-1i16).to_be_bytes());
+        avc1.extend_from_slice(&mp4_box(b"avcC", &avcc));
+
+        let mut stsd = vec![];
+        put_u32(&mut stsd, 1);
+        stsd.extend_from_slice(&mp4_box(b"avc1", &avc1));
+
+        // Samples are described by the fragments, the sample tables of the movie are empty
+        let empty_table = [0u8; 4];
+        let stbl = [
+            full_box(b"stsd", 0, 0, &stsd),
+            full_box(b"stts", 0, 0, &empty_table),
+            full_box(b"stsc", 0, 0, &empty_table),
+            full_box(b"stsz", 0, 0, &[0u8; 8]),
+            full_box(b"stco", 0, 0, &empty_table),
+        ].concat();
+
+        let mut dref = vec![];
+        put_u32(&mut dref, 1);
+        dref.extend_from_slice(&full_box(b"url ", 0, 1, &[]));
+
+        let minf = [
+            full_box(b"vmhd", 0, 1, &[0u8; 8]),
+            mp4_box(b"dinf", &full_box(b"dref", 0, 0, &dref)),
+            mp4_box(b"stbl", &stbl),
+        ].concat();
+
+        let mut mdhd = vec![0u8; 8];
+        put_u32(&mut mdhd, TIMESCALE as u32);
+        put_u32(&mut mdhd, 0);
+        mdhd.extend_from_slice(&0x55C4u16.to_be_bytes()); // "und"
+        mdhd.extend_from_slice(&[0u8; 2]);
+
+        let mut hdlr = vec![0u8; 4];
+        hdlr.extend_from_slice(b"vide");
+        hdlr.extend_from_slice(&[0u8; 12]);
+        hdlr.extend_from_slice(b"VideoHandler\0");
+
+        let mdia = [
+            full_box(b"mdhd", 0, 0, &mdhd),
+            full_box(b"hdlr", 0, 0, &hdlr),
+            mp4_box(b"minf", &minf),
+        ].concat();
+
+        let mut tkhd = vec![0u8; 8];
+        put_u32(&mut tkhd, TRACK_ID);
+        put_u32(&mut tkhd, 0);
+        put_u32(&mut tkhd, 0); // duration
+        tkhd.extend_from_slice(&[0u8; 16]); // reserved, layer, alternate group, volume
+        put_matrix(&mut tkhd);
+        put_u32(&mut tkhd, self.width << 16);
+        put_u32(&mut tkhd, self.height << 16);
+
+        let trak = [full_box(b"tkhd", 0, 3, &tkhd), mp4_box(b"mdia", &mdia)].concat();
+
+        let mut mvhd = vec![0u8; 8];
+        put_u32(&mut mvhd, 1000);
+        put_u32(&mut mvhd, 0); // duration
+        put_u32(&mut mvhd, 0x0001_0000); // rate
+        mvhd.extend_from_slice(&0x0100u16.to_be_bytes()); // volume
+        mvhd.extend_from_slice(&[0u8; 10]);
+        put_matrix(&mut mvhd);
+        mvhd.extend_from_slice(&[0u8; 24]);
+        put_u32(&mut mvhd, TRACK_ID + 1);
+
+        let mut trex = vec![];
+        put_u32(&mut trex, TRACK_ID);
+        put_u32(&mut trex, 1);
+        put_u32(&mut trex, 0);
+        put_u32(&mut trex, 0);
+        put_u32(&mut trex, 0);
+
+        let moov = [
+            full_box(b"mvhd", 0, 0, &mvhd),
+            mp4_box(b"trak", &trak),
+            mp4_box(b"mvex", &full_box(b"trex", 0, 0, &trex)),
+        ].concat();
+
+        self.output.write_all(&mp4_box(b"ftyp", &ftyp))?;
+        self.output.write_all(&mp4_box(b"moov", &moov))?;
+        self.header_written = true;
+
+        Ok(())
+    }
+
+    // Writes the pending samples as a fragment. The last sample lasts until `next_timestamp_us`,
+    // or a frame interval at the end of the file
+    fn write_fragment(&mut self, next_timestamp_us: Option<u64>) -> io::Result<()> {
+        let samples = std::mem::take(&mut self.pending);
+        self.fragments += 1;
+
+        let mut trun = vec![];
+        put_u32(&mut trun, samples.len() as u32);
+        let data_offset_position = trun.len();
+        put_u32(&mut trun, 0);
+
+        let mut duration_total = 0;
+        for (i, sample) in samples.iter().enumerate() {
+            let next = samples.get(i + 1).map(|s| s.timestamp_us)
+                .or(next_timestamp_us)
+                .unwrap_or(sample.timestamp_us + self.frame_duration_us);
+            let duration = ((next - sample.timestamp_us) * TIMESCALE / 1_000_000).max(1);
+            duration_total += duration;
+
+            put_u32(&mut trun, duration as u32);
+            put_u32(&mut trun, sample.data.len() as u32);
+            put_u32(&mut trun, if sample.keyframe { SAMPLE_FLAGS_SYNC } else { SAMPLE_FLAGS_NON_SYNC });
+        }
+
+        let mut mfhd = vec![];
+        put_u32(&mut mfhd, self.fragments);
+
+        let mut tfhd = vec![];
+        put_u32(&mut tfhd, TRACK_ID);
+
+        // Data offset, sample durations, sizes and flags
+        let trun_flags = 0x0001 | 0x0100 | 0x0200 | 0x0400;
+        let trun = full_box(b"trun", 0, trun_flags, &trun);
+
+        let traf = [
+            full_box(b"tfhd", 0, 0x02_0000, &tfhd), // default base is moof
+            full_box(b"tfdt", 1, 0, &self.decode_time.to_be_bytes()),
+            trun,
+        ].concat();
+
+        let mut moof = mp4_box(b"moof", &[full_box(b"mfhd", 0, 0, &mfhd), mp4_box(b"traf", &traf)].concat());
+
+        // The sample data starts right after the moof and the mdat header
+        let data_offset = (moof.len() + 8) as u32;
+        let trun_data = moof.windows(4).position(|w| w == b"trun").unwrap() + 8;
+        let position = trun_data + data_offset_position;
+        moof[position..position + 4].copy_from_slice(&data_offset.to_be_bytes());
+
+        let data_size: usize = samples.iter().map(|s| s.data.len()).sum();
+
+        self.output.write_all(&moof)?;
+        self.output.write_all(&((data_size + 8) as u32).to_be_bytes())?;
+        self.output.write_all(b"mdat")?;
+        for sample in &samples {
+            self.output.write_all(&sample.data)?;
+        }
+
+        self.decode_time += duration_total;
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for Mp4Writer<W> {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}
+
+/// Splits Annex-B data at its 3 and 4 byte start codes.
+pub fn nal_units(annex_b: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = vec![];
+    let mut i = 0;
+
+    while i + 3 <= annex_b.len() {
+        if annex_b[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    let ends: Vec<usize> = starts.iter().skip(1)
+        .map(|&start| if start >= 4 && annex_b[start - 4] == 0 { start - 4 } else { start - 3 })
+        .chain(std::iter::once(annex_b.len()))
+        .collect();
+
+    starts.into_iter().zip(ends)
+        .map(move |(start, end)| &annex_b[start..end])
+        .filter(|nal| !nal.is_empty())
+}
+
+fn mp4_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(content.len() + 8);
+    put_u32(&mut result, (content.len() + 8) as u32);
+    result.extend_from_slice(kind);
+    result.extend_from_slice(content);
+    result
+}
+
+fn full_box(kind: &[u8; 4], version: u8, flags: u32, content: &[u8]) -> Vec<u8> {
+    let mut full = Vec::with_capacity(content.len() + 4);
+    put_u32(&mut full, (version as u32) << 24 | (flags & 0xFF_FFFF));
+    full.extend_from_slice(content);
+    mp4_box(kind, &full)
+}
+
+fn put_u32(output: &mut Vec<u8>, value: u32) {
+    output.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_matrix(output: &mut Vec<u8>) {
+    for value in [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000] {
+        put_u32(output, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: &[u8] = &[0x67, 0x42, 0xC0, 0x1E, 0xAA];
+    const PPS: &[u8] = &[0x68, 0xCE, 0x3C];
+
+    fn access_unit(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter().flat_map(|nal| [&[0u8, 0, 0, 1][..], nal].concat()).collect()
+    }
+
+    // (type, payload) of the top level boxes
+    fn boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut result = vec![];
+        let mut i = 0;
+        while i < data.len() {
+            let size = u32::from_be_bytes(data[i..i + 4].try_into().unwrap()) as usize;
+            result.push((data[i + 4..i + 8].try_into().unwrap(), &data[i + 8..i + size]));
+            i += size;
+        }
+        result
+    }
+
+    fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> &'a [u8] {
+        let (kind, rest) = path.split_first().unwrap();
+        let content = boxes(data).into_iter().find(|(k, _)| k == *kind).unwrap().1;
+        if rest.is_empty() { content } else { find(content, rest) }
+    }
+
+    #[test]
+    fn splits_nal_units() {
+        let data = [0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 3, 0, 0, 0, 1, 0x65, 4, 5];
+        let nals: Vec<&[u8]> = nal_units(&data).collect();
+
+        assert_eq!(nals, vec![&[0x67, 1, 2][..], &[0x68, 3], &[0x65, 4, 5]]);
+    }
+
+    #[test]
+    fn writes_a_fragment_per_group_of_pictures() {
+        let mut output = vec![];
+        {
+            let mut writer = Mp4Writer::new(&mut output, 320, 240, 25);
+            // Dropped, no keyframe seen yet
+            writer.write_frame(0, &access_unit(&[&[0x41, 9]])).unwrap();
+            writer.write_frame(40_000, &access_unit(&[SPS, PPS, &[0x65, 1, 2, 3]])).unwrap();
+            writer.write_frame(80_000, &access_unit(&[&[0x41, 4]])).unwrap();
+            writer.write_frame(140_000, &access_unit(&[SPS, PPS, &[0x65, 5]])).unwrap();
+            writer.finish().unwrap();
+        }
+
+        let kinds: Vec<[u8; 4]> = boxes(&output).iter().map(|(k, _)| *k).collect();
+        assert_eq!(kinds, vec![*b"ftyp", *b"moov", *b"moof", *b"mdat", *b"moof", *b"mdat"]);
+
+        let avcc = find(&output, &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"]);
+        // Entry count, then the avc1 sample entry
+        let avc1 = &avcc[4 + 4 + 8..];
+        assert_eq!(&avc1[24..28], &[1, 64, 0, 240]);
+        let avcc = &avc1[78 + 8..];
+        assert_eq!(&avcc[..4], &[1, 0x42, 0xC0, 0x1E]);
+        assert_eq!(&avcc[8..8 + SPS.len()], SPS);
+
+        let top = boxes(&output);
+        let (moof, mdat) = (top[2].1, top[3].1);
+        let trun = find(moof, &[b"traf", b"trun"]);
+        assert_eq!(u32::from_be_bytes(trun[4..8].try_into().unwrap()), 2);
+
+        // Data offset, then duration, size and flags of each sample
+        let data_offset = u32::from_be_bytes(trun[8..12].try_into().unwrap()) as usize;
+        assert_eq!(data_offset, moof.len() + 16);
+        let samples: Vec<u32> = trun[12..].chunks(4).map(|c| u32::from_be_bytes(c.try_into().unwrap())).collect();
+        assert_eq!(samples, vec![3600, 8, SAMPLE_FLAGS_SYNC, 5400, 6, SAMPLE_FLAGS_NON_SYNC]);
+
+        assert_eq!(mdat, &[0, 0, 0, 4, 0x65, 1, 2, 3, 0, 0, 0, 2, 0x41, 4]);
+
+        // The second fragment starts where the first one ends, its only sample lasts a frame interval
+        let tfdt = find(top[4].1, &[b"traf", b"tfdt"]);
+        assert_eq!(u64::from_be_bytes(tfdt[4..12].try_into().unwrap()), 9000);
+        let trun = find(top[4].1, &[b"traf", b"trun"]);
+        assert_eq!(u32::from_be_bytes(trun[12..16].try_into().unwrap()), 3600);
+    }
+}
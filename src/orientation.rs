@@ -0,0 +1,111 @@
+/// Clockwise rotation of output frames.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum Rotation {
+    #[default]
+    None,
+    Clockwise90,
+    Clockwise180,
+    Clockwise270,
+}
+
+impl Rotation {
+    pub fn from_degrees(degrees: u32) -> Option<Rotation> {
+        match degrees {
+            0 => Some(Rotation::None),
+            90 => Some(Rotation::Clockwise90),
+            180 => Some(Rotation::Clockwise180),
+            270 => Some(Rotation::Clockwise270),
+            _ => None,
+        }
+    }
+}
+
+/// How output frames are turned, for cameras mounted sideways or upside down and mirrored previews.
+/// Mirroring and flipping apply to the rotated frame, so they are horizontal and vertical as displayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Orientation {
+    pub rotation: Rotation,
+    pub mirror: bool,
+    pub flip: bool,
+}
+
+impl Orientation {
+    pub fn is_identity(&self) -> bool {
+        *self == Orientation::default()
+    }
+
+    /// Width and height of a `width` x `height` frame once turned.
+    pub fn turned_size<T>(&self, width: T, height: T) -> (T, T) {
+        match self.rotation {
+            Rotation::Clockwise90 | Rotation::Clockwise270 => (height, width),
+            Rotation::None | Rotation::Clockwise180 => (width, height),
+        }
+    }
+}
+
+/// Writes a packed image of `bytes_per_pixel` bytes per pixel, turned, into `output`, which holds rows of
+/// `stride` bytes for the turned size.
+pub fn orient(src: &[u8], width: usize, height: usize, bytes_per_pixel: usize, orientation: Orientation, stride: usize, output: &mut [u8]) {
+    let (out_width, out_height) = orientation.turned_size(width, height);
+
+    for (out_y, row) in output.chunks_mut(stride).take(out_height).enumerate() {
+        let row = &mut row[..out_width * bytes_per_pixel];
+        let y = if orientation.flip { out_height - 1 - out_y } else { out_y };
+
+        for (out_x, px) in row.chunks_exact_mut(bytes_per_pixel).enumerate() {
+            let x = if orientation.mirror { out_width - 1 - out_x } else { out_x };
+
+            let (src_x, src_y) = match orientation.rotation {
+                Rotation::None => (x, y),
+                Rotation::Clockwise90 => (y, height - 1 - x),
+                Rotation::Clockwise180 => (width - 1 - x, height - 1 - y),
+                Rotation::Clockwise270 => (width - 1 - y, x),
+            };
+
+            let offset = (src_y * width + src_x) * bytes_per_pixel;
+            px.copy_from_slice(&src[offset..offset + bytes_per_pixel]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 3x2 image:
+    // 1 2 3
+    // 4 5 6
+    const IMAGE: [u8; 6] = [1, 2, 3, 4, 5, 6];
+
+    fn turned(rotation: Rotation, mirror: bool, flip: bool) -> Vec<u8> {
+        let orientation = Orientation { rotation, mirror, flip };
+        let (width, _) = orientation.turned_size(3, 2);
+        let mut output = vec![0u8; 6];
+        orient(&IMAGE, 3, 2, 1, orientation, width, &mut output);
+        output
+    }
+
+    #[test]
+    fn rotates_clockwise() {
+        assert_eq!(turned(Rotation::None, false, false), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(turned(Rotation::Clockwise90, false, false), vec![4, 1, 5, 2, 6, 3]);
+        assert_eq!(turned(Rotation::Clockwise180, false, false), vec![6, 5, 4, 3, 2, 1]);
+        assert_eq!(turned(Rotation::Clockwise270, false, false), vec![3, 6, 2, 5, 1, 4]);
+    }
+
+    #[test]
+    fn mirrors_and_flips_the_rotated_frame() {
+        assert_eq!(turned(Rotation::None, true, false), vec![3, 2, 1, 6, 5, 4]);
+        assert_eq!(turned(Rotation::None, false, true), vec![4, 5, 6, 1, 2, 3]);
+        assert_eq!(turned(Rotation::Clockwise90, true, false), vec![1, 4, 2, 5, 3, 6]);
+        assert_eq!(turned(Rotation::None, true, true), turned(Rotation::Clockwise180, false, false));
+    }
+
+    #[test]
+    fn keeps_row_padding() {
+        let mut output = vec![9u8; 12];
+        orient(&[1, 2, 3, 4, 5, 6, 7, 8], 2, 1, 4, Orientation { rotation: Rotation::Clockwise90, ..Default::default() }, 6, &mut output);
+
+        assert_eq!(output, vec![1, 2, 3, 4, 9, 9, 5, 6, 7, 8, 9, 9]);
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt::{Display, Formatter};
+
+/// Pixel layout written into the caller's buffer by the grab functions.
+/// The discriminants are the values used by the C API.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum OutputFormat {
+    #[default]
+    Rgb = 0,
+    Bgr = 1,
+    Rgba = 2,
+    Bgra = 3,
+    Gray8 = 4,
+}
+
+impl OutputFormat {
+    pub fn from_code(code: i32) -> Option<OutputFormat> {
+        match code {
+            0 => Some(OutputFormat::Rgb),
+            1 => Some(OutputFormat::Bgr),
+            2 => Some(OutputFormat::Rgba),
+            3 => Some(OutputFormat::Bgra),
+            4 => Some(OutputFormat::Gray8),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            OutputFormat::Rgb | OutputFormat::Bgr => 3,
+            OutputFormat::Rgba | OutputFormat::Bgra => 4,
+            OutputFormat::Gray8 => 1,
+        }
+    }
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Rgb => write!(f, "RGB"),
+            OutputFormat::Bgr => write!(f, "BGR"),
+            OutputFormat::Rgba => write!(f, "RGBA"),
+            OutputFormat::Bgra => write!(f, "BGRA"),
+            OutputFormat::Gray8 => write!(f, "GRAY8"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color_space::YuvColorSpace;
+    use crate::virtual_camera::encode;
+    use nokhwa::utils::{FrameFormat, Resolution};
+    use nokhwa::Buffer;
+
+    const FORMATS: [OutputFormat; 5] = [OutputFormat::Rgb, OutputFormat::Bgr, OutputFormat::Rgba, OutputFormat::Bgra, OutputFormat::Gray8];
+    const WIDTH: u32 = 16;
+    const HEIGHT: u32 = 8;
+
+    // An orange, with its BT.601 luma
+    const ORANGE: [u8; 3] = [200, 100, 50];
+    const ORANGE_LUMA: u8 = 124;
+
+    fn pixel([r, g, b]: [u8; 3], luma: u8, format: OutputFormat) -> Vec<u8> {
+        match format {
+            OutputFormat::Rgb => vec![r, g, b],
+            OutputFormat::Bgr => vec![b, g, r],
+            OutputFormat::Rgba => vec![r, g, b, 255],
+            OutputFormat::Bgra => vec![b, g, r, 255],
+            OutputFormat::Gray8 => vec![luma],
+        }
+    }
+
+    // Converts a flat orange frame into rows padded by a few bytes, which must be left alone
+    fn convert(source: FrameFormat, format: OutputFormat) -> Vec<u8> {
+        let data = encode(&ORANGE.repeat((WIDTH * HEIGHT) as usize), WIDTH, HEIGHT, source).unwrap();
+        let frame = Buffer::new(Resolution::new(WIDTH, HEIGHT), &data, source);
+        let color_space = YuvColorSpace::default().resolve(source, frame.resolution());
+
+        let row_bytes = WIDTH as usize * format.bytes_per_pixel();
+        let stride = row_bytes + 5;
+        let mut output = vec![7u8; stride * HEIGHT as usize];
+        crate::convert_to_rgb(frame, format, color_space, stride, &mut output).unwrap();
+
+        output.chunks_exact(stride)
+            .flat_map(|row| {
+                assert_eq!(row[row_bytes..], [7; 5], "{:?} to {}", source, format);
+                row[..row_bytes].to_vec()
+            })
+            .collect()
+    }
+
+    fn assert_converts(source: FrameFormat, expected: [u8; 3], luma: u8, tolerance: u8) {
+        for format in FORMATS {
+            let expected = pixel(expected, luma, format);
+            let output = convert(source, format);
+            for px in output.chunks_exact(format.bytes_per_pixel()) {
+                assert!(px.iter().zip(&expected).all(|(a, b)| a.abs_diff(*b) <= tolerance), "{:?} to {}: {:?} instead of {:?}", source, format, px, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn maps_codes_and_pixel_sizes() {
+        for format in FORMATS {
+            assert_eq!(OutputFormat::from_code(format.code()), Some(format));
+        }
+        assert_eq!(OutputFormat::from_code(5), None);
+        assert_eq!(FORMATS.map(OutputFormat::bytes_per_pixel), [3, 3, 4, 4, 1]);
+    }
+
+    #[test]
+    fn converts_yuv_frames_to_every_format() {
+        // YUYV is converted by the crate, NV12 by dcv, and both take the gray levels from their luma plane
+        assert_converts(FrameFormat::YUYV, ORANGE, ORANGE_LUMA, 3);
+        assert_converts(FrameFormat::NV12, ORANGE, ORANGE_LUMA, 3);
+    }
+
+    #[test]
+    fn converts_rgb_and_gray_frames_to_every_format() {
+        // Converted by nokhwa, then swizzled for BGR
+        assert_converts(FrameFormat::RAWRGB, ORANGE, ORANGE_LUMA, 1);
+        assert_converts(FrameFormat::RAWBGR, ORANGE, ORANGE_LUMA, 1);
+        assert_converts(FrameFormat::GRAY, [ORANGE_LUMA; 3], ORANGE_LUMA, 1);
+    }
+
+    #[test]
+    fn converts_mjpeg_frames_to_every_format() {
+        assert_converts(FrameFormat::MJPEG, ORANGE, ORANGE_LUMA, 6);
+    }
+}
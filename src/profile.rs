@@ -0,0 +1,182 @@
+use crate::video_format::VideoFormat;
+use nokhwa::utils::CameraFormat;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+// Directory where profiles are stored, profiles are disabled while it's not set
+static PROFILES_DIRECTORY: LazyLock<Mutex<Option<PathBuf>>> = LazyLock::new(Default::default);
+
+// Settings that could not be applied the last time a profile was applied, by device unique id
+static APPLY_FAILURES: LazyLock<Mutex<HashMap<String, Vec<String>>>> = LazyLock::new(Default::default);
+
+/// Saved settings of a device, stored as JSON and keyed by the device unique id.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceProfile {
+    pub unique_id: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<ProfileFormat>,
+    #[serde(default)]
+    pub controls: Vec<ProfileControl>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileFormat {
+    pub width: u32,
+    pub height: u32,
+    pub format: String,
+    pub frame_rate: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProfileControl {
+    pub id: u64,
+    pub name: String,
+    pub value: f64,
+}
+
+impl ProfileFormat {
+    pub fn from_camera_format(format: &CameraFormat) -> ProfileFormat {
+        ProfileFormat {
+            width: format.width(),
+            height: format.height(),
+            format: format.format().to_string(),
+            frame_rate: format.frame_rate(),
+        }
+    }
+
+    pub fn matches(&self, format: &VideoFormat) -> bool {
+        self.width == format.width
+            && self.height == format.height
+            && self.frame_rate == format.frame_rate
+            && self.format == format.format.to_string()
+    }
+}
+
+impl Display for ProfileFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{} {} {}fps", self.width, self.height, self.format, self.frame_rate)
+    }
+}
+
+pub fn set_profiles_directory(directory: Option<PathBuf>) {
+    *PROFILES_DIRECTORY.lock() = directory;
+}
+
+pub fn profiles_directory() -> Option<PathBuf> {
+    PROFILES_DIRECTORY.lock().clone()
+}
+
+// Unique ids can contain path separators and other characters not allowed in file names
+fn profile_path(directory: &Path, unique_id: &str) -> PathBuf {
+    let file_name: String = unique_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect();
+
+    directory.join(format!("{}.json", file_name))
+}
+
+pub fn save_profile(directory: &Path, profile: &DeviceProfile) -> io::Result<()> {
+    let json = serde_json::to_string_pretty(profile)?;
+
+    fs::create_dir_all(directory)?;
+    fs::write(profile_path(directory, &profile.unique_id), json)
+}
+
+/// Loads the profile of a device, `Ok(None)` when there is no profile for it.
+pub fn load_profile(directory: &Path, unique_id: &str) -> io::Result<Option<DeviceProfile>> {
+    let path = profile_path(directory, unique_id);
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let json = fs::read_to_string(&path)?;
+    let profile: DeviceProfile = serde_json::from_str(&json)?;
+
+    // Different unique ids could map to the same file name
+    if profile.unique_id != unique_id {
+        return Ok(None);
+    }
+
+    Ok(Some(profile))
+}
+
+pub fn set_apply_failures(unique_id: &str, failures: Vec<String>) {
+    APPLY_FAILURES.lock().insert(unique_id.to_string(), failures);
+}
+
+pub fn apply_failures(unique_id: &str) -> Vec<String> {
+    APPLY_FAILURES.lock().get(unique_id).cloned().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nokhwa::utils::FrameFormat;
+
+    fn profile(unique_id: &str) -> DeviceProfile {
+        DeviceProfile {
+            unique_id: unique_id.to_string(),
+            name: "Front".to_string(),
+            format: Some(ProfileFormat { width: 640, height: 480, format: "MJPEG".to_string(), frame_rate: 30 }),
+            controls: vec![ProfileControl { id: 0, name: "Brightness".to_string(), value: 12.0 }],
+        }
+    }
+
+    fn directory(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cnokhwa-profiles-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn saves_and_loads_profiles() {
+        let directory = directory("round-trip");
+        assert_eq!(load_profile(&directory, "usb-1").unwrap(), None);
+
+        save_profile(&directory, &profile("usb-1")).unwrap();
+        assert_eq!(load_profile(&directory, "usb-1").unwrap(), Some(profile("usb-1")));
+
+        // Profiles written by hand may leave out the format and controls
+        fs::write(profile_path(&directory, "usb-2"), r#"{"unique_id": "usb-2", "name": "Back"}"#).unwrap();
+        let loaded = load_profile(&directory, "usb-2").unwrap().unwrap();
+        assert_eq!((loaded.format, loaded.controls), (None, vec![]));
+
+        fs::write(profile_path(&directory, "usb-3"), "{").unwrap();
+        assert!(load_profile(&directory, "usb-3").is_err());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn keeps_unique_ids_apart() {
+        let profiles = Path::new("profiles");
+        assert_eq!(profile_path(profiles, "/dev/video0"), profiles.join("_dev_video0.json"));
+        assert_eq!(profile_path(profiles, r"\\?\usb#vid_046d&pid_0825"), profiles.join("____usb_vid_046d_pid_0825.json"));
+        assert_eq!(profile_path(profiles, "cam-1.2"), profiles.join("cam-1.2.json"));
+
+        // Both ids map to the same file, which only holds the profile of the last one saved
+        let directory = directory("collisions");
+        save_profile(&directory, &profile("usb:1")).unwrap();
+        assert_eq!(load_profile(&directory, "usb/1").unwrap(), None);
+        assert!(load_profile(&directory, "usb:1").unwrap().is_some());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn matches_video_formats() {
+        let saved = profile("usb-1").format.unwrap();
+        let format = VideoFormat { index: 3, width: 640, height: 480, format: FrameFormat::MJPEG, frame_rate: 30 };
+        assert!(saved.matches(&format));
+        assert!(!saved.matches(&VideoFormat { format: FrameFormat::YUYV, ..format.clone() }));
+        assert!(!saved.matches(&VideoFormat { frame_rate: 15, ..format.clone() }));
+        assert!(!saved.matches(&VideoFormat { width: 320, height: 240, ..format }));
+        assert_eq!(saved.to_string(), "640x480 MJPEG 30fps");
+    }
+}
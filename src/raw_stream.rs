@@ -0,0 +1,253 @@
+use nokhwa::utils::{FrameFormat, Resolution};
+use nokhwa::Buffer;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+// Raw stream files store the undecoded frames of a session, so field captures can be replayed anywhere.
+// All numbers are little endian:
+//   header: magic, frame rate (u32), device name length (u32), device name (UTF-8)
+//   frame:  timestamp in microseconds since the first frame (u64), format (u32), width (u32), height (u32),
+//           payload length (u32), payload
+const MAGIC: &[u8; 8] = b"CNKRAW01";
+
+// Guards against allocating garbage lengths from corrupt files
+const MAX_NAME_LENGTH: u32 = 4096;
+const MAX_PAYLOAD_LENGTH: u32 = 256 * 1024 * 1024;
+
+pub type RawStreamFile = RawStreamWriter<BufWriter<File>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawStreamHeader {
+    pub name: String,
+    pub frame_rate: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawFrame {
+    pub timestamp_us: u64,
+    pub format: FrameFormat,
+    pub resolution: Resolution,
+    pub payload: Vec<u8>,
+}
+
+impl RawFrame {
+    pub fn to_buffer(&self) -> Buffer {
+        Buffer::new(self.resolution, &self.payload, self.format)
+    }
+}
+
+pub struct RawStreamWriter<W: Write> {
+    output: W,
+    first_timestamp_us: Option<u64>,
+}
+
+pub struct RawStreamReader<R: Read> {
+    input: R,
+    header: RawStreamHeader,
+}
+
+impl RawStreamFile {
+    /// Creates the file, overwriting any existing one.
+    pub fn create(path: &Path, header: &RawStreamHeader) -> io::Result<RawStreamFile> {
+        RawStreamWriter::new(BufWriter::new(File::create(path)?), header)
+    }
+}
+
+impl<W: Write> RawStreamWriter<W> {
+    pub fn new(mut output: W, header: &RawStreamHeader) -> io::Result<RawStreamWriter<W>> {
+        let name = header.name.as_bytes();
+        let name = &name[..name.len().min(MAX_NAME_LENGTH as usize)];
+
+        output.write_all(MAGIC)?;
+        output.write_all(&header.frame_rate.to_le_bytes())?;
+        output.write_all(&(name.len() as u32).to_le_bytes())?;
+        output.write_all(name)?;
+
+        Ok(RawStreamWriter { output, first_timestamp_us: None })
+    }
+
+    /// Appends a frame captured at `timestamp_us`, timestamps are stored relative to the first frame.
+    pub fn write_frame(&mut self, timestamp_us: u64, frame: &Buffer) -> io::Result<()> {
+        let payload = frame.buffer();
+        if payload.len() > MAX_PAYLOAD_LENGTH as usize {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Frame too large to record"));
+        }
+
+        let first_timestamp_us = *self.first_timestamp_us.get_or_insert(timestamp_us);
+        let resolution = frame.resolution();
+
+        self.output.write_all(&timestamp_us.saturating_sub(first_timestamp_us).to_le_bytes())?;
+        self.output.write_all(&format_code(frame.source_frame_format()).to_le_bytes())?;
+        self.output.write_all(&resolution.width().to_le_bytes())?;
+        self.output.write_all(&resolution.height().to_le_bytes())?;
+        self.output.write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.output.write_all(payload)
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+impl RawStreamReader<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<RawStreamReader<BufReader<File>>> {
+        RawStreamReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> RawStreamReader<R> {
+    pub fn new(mut input: R) -> io::Result<RawStreamReader<R>> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("Not a raw stream file"));
+        }
+
+        let frame_rate = read_u32(&mut input)?;
+        let name_length = read_u32(&mut input)?;
+        if name_length > MAX_NAME_LENGTH {
+            return Err(invalid_data("Invalid device name length"));
+        }
+
+        let mut name = vec![0u8; name_length as usize];
+        input.read_exact(&mut name)?;
+
+        let header = RawStreamHeader {
+            name: String::from_utf8_lossy(&name).to_string(),
+            frame_rate,
+        };
+
+        Ok(RawStreamReader { input, header })
+    }
+
+    pub fn header(&self) -> &RawStreamHeader {
+        &self.header
+    }
+
+    /// Reads the next frame, `None` at the end of the stream. A frame cut short, as left by a crash
+    /// while recording, is treated as the end of the stream too.
+    pub fn next_frame(&mut self) -> io::Result<Option<RawFrame>> {
+        match self.read_frame() {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            result => result.map(Some),
+        }
+    }
+
+    fn read_frame(&mut self) -> io::Result<RawFrame> {
+        let mut timestamp = [0u8; 8];
+        self.input.read_exact(&mut timestamp)?;
+
+        let format = frame_format(read_u32(&mut self.input)?).ok_or_else(|| invalid_data("Unknown frame format"))?;
+        let width = read_u32(&mut self.input)?;
+        let height = read_u32(&mut self.input)?;
+        let length = read_u32(&mut self.input)?;
+        if length > MAX_PAYLOAD_LENGTH {
+            return Err(invalid_data("Invalid frame length"));
+        }
+
+        let mut payload = vec![0u8; length as usize];
+        self.input.read_exact(&mut payload)?;
+
+        Ok(RawFrame {
+            timestamp_us: u64::from_le_bytes(timestamp),
+            format,
+            resolution: Resolution::new(width, height),
+            payload,
+        })
+    }
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+// Stable codes, stored in files
+fn format_code(format: FrameFormat) -> u32 {
+    match format {
+        FrameFormat::MJPEG => 0,
+        FrameFormat::YUYV => 1,
+        FrameFormat::NV12 => 2,
+        FrameFormat::GRAY => 3,
+        FrameFormat::RAWRGB => 4,
+        FrameFormat::RAWBGR => 5,
+    }
+}
+
+fn frame_format(code: u32) -> Option<FrameFormat> {
+    match code {
+        0 => Some(FrameFormat::MJPEG),
+        1 => Some(FrameFormat::YUYV),
+        2 => Some(FrameFormat::NV12),
+        3 => Some(FrameFormat::GRAY),
+        4 => Some(FrameFormat::RAWRGB),
+        5 => Some(FrameFormat::RAWBGR),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn header() -> RawStreamHeader {
+        RawStreamHeader { name: "Test Camera".to_string(), frame_rate: 30 }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let first = Buffer::new(Resolution::new(4, 2), &[1u8; 16], FrameFormat::YUYV);
+        let second = Buffer::new(Resolution::new(2, 2), &[7u8, 8, 9], FrameFormat::MJPEG);
+
+        let mut writer = RawStreamWriter::new(vec![], &header()).unwrap();
+        writer.write_frame(1_000_000, &first).unwrap();
+        writer.write_frame(1_033_333, &second).unwrap();
+        let bytes = writer.output;
+
+        let mut reader = RawStreamReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.header(), &header());
+
+        let frame = reader.next_frame().unwrap().unwrap();
+        assert_eq!(frame.timestamp_us, 0);
+        assert_eq!(frame.format, FrameFormat::YUYV);
+        assert_eq!(frame.resolution, Resolution::new(4, 2));
+        assert_eq!(frame.payload, vec![1u8; 16]);
+
+        let frame = reader.next_frame().unwrap().unwrap();
+        assert_eq!(frame.timestamp_us, 33_333);
+        assert_eq!(frame.format, FrameFormat::MJPEG);
+        assert_eq!(frame.payload, vec![7u8, 8, 9]);
+
+        assert_eq!(reader.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn truncated_frame_ends_the_stream() {
+        let frame = Buffer::new(Resolution::new(4, 2), &[1u8; 16], FrameFormat::YUYV);
+
+        let mut writer = RawStreamWriter::new(vec![], &header()).unwrap();
+        writer.write_frame(0, &frame).unwrap();
+        writer.write_frame(10, &frame).unwrap();
+        let mut bytes = writer.output;
+        bytes.truncate(bytes.len() - 5);
+
+        let mut reader = RawStreamReader::new(Cursor::new(bytes)).unwrap();
+        assert!(reader.next_frame().unwrap().is_some());
+        assert_eq!(reader.next_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let error = RawStreamReader::new(Cursor::new(b"RIFF0000AVI LIST".to_vec())).err().unwrap();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
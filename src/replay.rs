@@ -0,0 +1,259 @@
+use crate::frame_source::{FrameSink, FrameSource};
+use crate::raw_stream::RawStreamReader;
+use nokhwa::utils::{CameraControl, CameraFormat, CameraIndex, CameraInfo, ControlValueSetter, KnownCameraControl, RequestedFormat};
+use nokhwa::NokhwaError;
+use parking_lot::Mutex;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::LazyLock;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const INDEX_PREFIX: &str = "replay:";
+const DESCRIPTION: &str = "cnokhwa raw stream replay";
+
+// Raw stream files listed as devices, in the order they were added
+static REPLAY_FILES: LazyLock<Mutex<Vec<PathBuf>>> = LazyLock::new(Default::default);
+
+/// Lists a raw stream file as a device, returning its info. Adding the same file twice lists it once.
+pub fn add_file(path: &Path) -> io::Result<CameraInfo> {
+    let path = path.canonicalize()?;
+    let info = replay_info(&path)?;
+
+    let mut files = REPLAY_FILES.lock();
+    if !files.contains(&path) {
+        files.push(path);
+    }
+
+    Ok(info)
+}
+
+pub fn devices() -> Vec<CameraInfo> {
+    let files = REPLAY_FILES.lock().clone();
+
+    files.iter()
+        .filter_map(|path| match replay_info(path) {
+            Ok(info) => Some(info),
+            Err(err) => {
+                eprintln!("Error reading replay file {}: {}", path.display(), err);
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn is_replay(index: &CameraIndex) -> bool {
+    matches!(index, CameraIndex::String(s) if s.starts_with(INDEX_PREFIX))
+}
+
+/// Opens a replay device. Its only format is the one of the first recorded frame, at the recorded frame rate.
+pub fn open(index: &CameraIndex, requested: RequestedFormat) -> Result<Box<dyn FrameSource>, NokhwaError> {
+    let open_error = |error: String| NokhwaError::OpenDeviceError(index.to_string(), error);
+
+    let CameraIndex::String(s) = index else { return Err(open_error("Not a replay device".to_string())) };
+    let path = PathBuf::from(&s[INDEX_PREFIX.len()..]);
+
+    let format = recorded_format(&path).map_err(|e| open_error(e.to_string()))?;
+
+    if requested.fulfill(&[format]) != Some(format) {
+        return Err(open_error(format!("The recording is {}", format)));
+    }
+
+    Ok(Box::new(ReplaySource {
+        path,
+        format,
+        player: None,
+    }))
+}
+
+fn replay_info(path: &Path) -> io::Result<CameraInfo> {
+    let reader = RawStreamReader::open(path)?;
+    let path = path.to_string_lossy();
+
+    Ok(CameraInfo::new(
+        &format!("{} (replay)", reader.header().name),
+        DESCRIPTION,
+        &format!("{}{}", INDEX_PREFIX, path),
+        CameraIndex::String(format!("{}{}", INDEX_PREFIX, path)),
+    ))
+}
+
+fn recorded_format(path: &Path) -> io::Result<CameraFormat> {
+    let mut reader = RawStreamReader::open(path)?;
+    let frame_rate = reader.header().frame_rate;
+
+    let Some(frame) = reader.next_frame()?
+    else { return Err(io::Error::new(io::ErrorKind::InvalidData, "The recording has no frames")) };
+
+    Ok(CameraFormat::new(frame.resolution, frame.format, frame_rate))
+}
+
+struct Player {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+/// Plays a raw stream file in a loop, delivering every frame at the time it was recorded.
+struct ReplaySource {
+    path: PathBuf,
+    format: CameraFormat,
+    player: Option<Player>,
+}
+
+impl FrameSource for ReplaySource {
+    fn formats(&mut self) -> Result<Vec<CameraFormat>, NokhwaError> {
+        Ok(vec![self.format])
+    }
+
+    fn format(&self) -> Result<CameraFormat, NokhwaError> {
+        Ok(self.format)
+    }
+
+    fn start(&mut self, mut sink: FrameSink) -> Result<(), NokhwaError> {
+        if self.player.is_some() {
+            return Err(NokhwaError::OpenStreamError("Stream Already Open".to_string()));
+        }
+
+        let (stop, stop_receiver) = channel::<()>();
+        let path = self.path.clone();
+        let loop_gap = Duration::from_secs(1) / self.format.frame_rate().max(1);
+
+        let thread = std::thread::spawn(move || {
+            'replay: loop {
+                let mut reader = match RawStreamReader::open(&path) {
+                    Ok(r) => r,
+                    Err(err) => {
+                        eprintln!("Error opening replay file {}: {}", path.display(), err);
+                        break;
+                    }
+                };
+
+                let start = Instant::now();
+                let mut played = false;
+
+                loop {
+                    let frame = match reader.next_frame() {
+                        Ok(Some(f)) => f,
+                        Ok(None) => break,
+                        Err(err) => {
+                            eprintln!("Error reading replay file {}: {}", path.display(), err);
+                            break 'replay;
+                        }
+                    };
+
+                    let due = start + Duration::from_micros(frame.timestamp_us);
+                    match stop_receiver.recv_timeout(due.saturating_duration_since(Instant::now())) {
+                        Err(RecvTimeoutError::Timeout) => {}
+                        _ => break 'replay,
+                    }
+
+                    sink(frame.to_buffer());
+                    played = true;
+                }
+
+                // Leave a frame interval between the last frame and the first one of the next loop
+                if !played || !matches!(stop_receiver.recv_timeout(loop_gap), Err(RecvTimeoutError::Timeout)) {
+                    break;
+                }
+            }
+        });
+
+        self.player = Some(Player { stop, thread });
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), NokhwaError> {
+        if let Some(player) = self.player.take() {
+            let _ = player.stop.send(());
+            let _ = player.thread.join();
+        }
+
+        Ok(())
+    }
+
+    fn controls(&self) -> Result<Vec<CameraControl>, NokhwaError> {
+        Ok(vec![])
+    }
+
+    fn control(&self, control: KnownCameraControl) -> Result<CameraControl, NokhwaError> {
+        Err(NokhwaError::GetPropertyError {
+            property: control.to_string(),
+            error: "Replay devices have no controls".to_string(),
+        })
+    }
+
+    fn set_control(&mut self, control: KnownCameraControl, value: ControlValueSetter) -> Result<(), NokhwaError> {
+        Err(NokhwaError::SetPropertyError {
+            property: control.to_string(),
+            value: value.to_string(),
+            error: "Replay devices have no controls".to_string(),
+        })
+    }
+}
+
+impl Drop for ReplaySource {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw_stream::{RawStreamFile, RawStreamHeader};
+    use nokhwa::pixel_format::RgbFormat;
+    use nokhwa::utils::{FrameFormat, RequestedFormatType, Resolution};
+    use nokhwa::Buffer;
+    use std::sync::mpsc::{Receiver, TryRecvError};
+
+    fn record(path: &Path, frames: &[(u64, u8)]) {
+        let header = RawStreamHeader { name: "Recorded".to_string(), frame_rate: 20 };
+        let mut writer = RawStreamFile::create(path, &header).unwrap();
+
+        for &(timestamp_us, value) in frames {
+            let frame = Buffer::new(Resolution::new(4, 2), &[value; 24], FrameFormat::RAWRGB);
+            writer.write_frame(timestamp_us, &frame).unwrap();
+        }
+
+        writer.finish().unwrap();
+    }
+
+    fn start(source: &mut Box<dyn FrameSource>) -> Receiver<(Instant, Vec<u8>)> {
+        let (sender, receiver) = channel();
+        source.start(Box::new(move |buffer| {
+            let _ = sender.send((Instant::now(), buffer.buffer().to_vec()));
+        })).unwrap();
+
+        receiver
+    }
+
+    #[test]
+    fn replays_frames_with_their_timing_in_a_loop() {
+        let path = std::env::temp_dir().join(format!("cnokhwa-replay-test-{}.raw", std::process::id()));
+        record(&path, &[(5_000_000, 1), (5_100_000, 2), (5_200_000, 3)]);
+
+        let info = add_file(&path).unwrap();
+        assert_eq!(info.human_name(), "Recorded (replay)");
+        assert!(devices().contains(&info));
+        assert!(is_replay(info.index()));
+
+        let mut source = open(info.index(), RequestedFormat::new::<RgbFormat>(RequestedFormatType::None)).unwrap();
+        assert_eq!(source.formats().unwrap(), vec![CameraFormat::new(Resolution::new(4, 2), FrameFormat::RAWRGB, 20)]);
+
+        let frames = start(&mut source);
+        let received: Vec<(Instant, Vec<u8>)> = (0..4).map(|_| frames.recv_timeout(Duration::from_secs(2)).unwrap()).collect();
+
+        let values: Vec<u8> = received.iter().map(|(_, data)| data[0]).collect();
+        assert_eq!(values, vec![1, 2, 3, 1]);
+        assert!(received[2].0 - received[0].0 >= Duration::from_millis(195));
+
+        // The sink goes away with the player thread
+        source.stop().unwrap();
+        while frames.try_recv().is_ok() {}
+        assert_eq!(frames.try_recv(), Err(TryRecvError::Disconnected));
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,224 @@
+/// How output pixels are computed from the source pixels. The discriminants are the values used by the C API.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Interpolation {
+    Nearest = 0,
+    Bilinear = 1,
+    /// Averages every source pixel covered by an output pixel, the best choice to shrink. Same as bilinear to enlarge.
+    Area = 2,
+}
+
+impl Interpolation {
+    pub fn from_code(code: i32) -> Option<Interpolation> {
+        match code {
+            0 => Some(Interpolation::Nearest),
+            1 => Some(Interpolation::Bilinear),
+            2 => Some(Interpolation::Area),
+            _ => None,
+        }
+    }
+}
+
+/// What happens when the aspect ratio of the output differs from the source one.
+/// The discriminants are the values used by the C API.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum ScaleMode {
+    /// Fills the output, distorting the image
+    Stretch = 0,
+    /// Fits the whole image, centered between black bars
+    Letterbox = 1,
+    /// Fills the output with the center of the image, cutting the sides that don't fit
+    Crop = 2,
+}
+
+impl ScaleMode {
+    pub fn from_code(code: i32) -> Option<ScaleMode> {
+        match code {
+            0 => Some(ScaleMode::Stretch),
+            1 => Some(ScaleMode::Letterbox),
+            2 => Some(ScaleMode::Crop),
+            _ => None,
+        }
+    }
+}
+
+/// Largest output width or height, so a bad size can't make every frame allocate gigabytes.
+pub const MAX_OUTPUT_SIDE: u32 = 16384;
+
+/// Size frames are scaled to before being written to the caller's buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputSize {
+    pub width: u32,
+    pub height: u32,
+    pub interpolation: Interpolation,
+    pub mode: ScaleMode,
+}
+
+// Source pixels contributing to an output pixel along one axis, with their weights
+type Taps = Vec<(usize, f32)>;
+// x, y, width and height
+type Rect<T> = (T, T, T, T);
+
+/// Scales a packed image of `bytes_per_pixel` bytes per pixel into `output`, which holds `size.height` rows of
+/// `stride` bytes. Letterbox bars are black, and opaque for formats of 4 bytes per pixel.
+pub fn resize(src: &[u8], src_width: usize, src_height: usize, bytes_per_pixel: usize, size: OutputSize, stride: usize, output: &mut [u8]) {
+    let dst_width = size.width as usize;
+    let dst_height = size.height as usize;
+    if src_width == 0 || src_height == 0 || dst_width == 0 || dst_height == 0 {
+        return;
+    }
+
+    let (src_rect, dst_rect) = placement(src_width, src_height, dst_width, dst_height, size.mode);
+
+    if dst_rect != (0, 0, dst_width, dst_height) {
+        let mut black = [0u8; 4];
+        if bytes_per_pixel == 4 {
+            black[3] = 255;
+        }
+        for row in output.chunks_mut(stride).take(dst_height) {
+            for px in row[..dst_width * bytes_per_pixel].chunks_exact_mut(bytes_per_pixel) {
+                px.copy_from_slice(&black[..bytes_per_pixel]);
+            }
+        }
+    }
+
+    let (x, y, width, height) = dst_rect;
+    let columns = taps(src_rect.0, src_rect.2, src_width, width, size.interpolation);
+    let rows = taps(src_rect.1, src_rect.3, src_height, height, size.interpolation);
+    let src_stride = src_width * bytes_per_pixel;
+
+    for (dst_row, row_taps) in output.chunks_mut(stride).skip(y).zip(&rows) {
+        let dst_row = &mut dst_row[x * bytes_per_pixel..(x + width) * bytes_per_pixel];
+
+        for (px, column_taps) in dst_row.chunks_exact_mut(bytes_per_pixel).zip(&columns) {
+            if size.interpolation == Interpolation::Nearest {
+                let offset = row_taps[0].0 * src_stride + column_taps[0].0 * bytes_per_pixel;
+                px.copy_from_slice(&src[offset..offset + bytes_per_pixel]);
+                continue;
+            }
+
+            let mut sum = [0f32; 4];
+            for &(src_y, row_weight) in row_taps {
+                let src_row = &src[src_y * src_stride..];
+                for &(src_x, column_weight) in column_taps {
+                    let weight = row_weight * column_weight;
+                    let src_px = &src_row[src_x * bytes_per_pixel..(src_x + 1) * bytes_per_pixel];
+                    for (total, value) in sum.iter_mut().zip(src_px) {
+                        *total += *value as f32 * weight;
+                    }
+                }
+            }
+
+            for (value, total) in px.iter_mut().zip(sum) {
+                *value = (total + 0.5).clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+// Area of the source sampled and area of the output written to, in pixels
+fn placement(src_width: usize, src_height: usize, dst_width: usize, dst_height: usize, mode: ScaleMode) -> (Rect<f64>, Rect<usize>) {
+    let (sw, sh, dw, dh) = (src_width as f64, src_height as f64, dst_width as f64, dst_height as f64);
+    let full_src = (0.0, 0.0, sw, sh);
+    let full_dst = (0, 0, dst_width, dst_height);
+
+    match mode {
+        ScaleMode::Stretch => (full_src, full_dst),
+        ScaleMode::Letterbox => {
+            let scale = (dw / sw).min(dh / sh);
+            let width = ((sw * scale).round() as usize).clamp(1, dst_width);
+            let height = ((sh * scale).round() as usize).clamp(1, dst_height);
+            (full_src, ((dst_width - width) / 2, (dst_height - height) / 2, width, height))
+        }
+        ScaleMode::Crop => {
+            let scale = (dw / sw).max(dh / sh);
+            let (width, height) = (dw / scale, dh / scale);
+            (((sw - width) / 2.0, (sh - height) / 2.0, width, height), full_dst)
+        }
+    }
+}
+
+fn taps(src_start: f64, src_len: f64, src_count: usize, dst_count: usize, interpolation: Interpolation) -> Vec<Taps> {
+    let scale = src_len / dst_count as f64;
+    let last = src_count - 1;
+
+    (0..dst_count).map(|i| match interpolation {
+        Interpolation::Nearest => {
+            let center = src_start + (i as f64 + 0.5) * scale;
+            vec![((center.floor().max(0.0) as usize).min(last), 1.0)]
+        }
+        Interpolation::Area if scale > 1.0 => {
+            let start = src_start + i as f64 * scale;
+            let end = start + scale;
+            (start.floor() as usize..end.ceil() as usize)
+                .map(|s| {
+                    let covered = end.min(s as f64 + 1.0) - start.max(s as f64);
+                    (s.min(last), (covered / scale) as f32)
+                })
+                .filter(|&(_, weight)| weight > 0.0)
+                .collect()
+        }
+        Interpolation::Bilinear | Interpolation::Area => {
+            let center = (src_start + (i as f64 + 0.5) * scale - 0.5).max(0.0);
+            let first = center.floor();
+            let fraction = (center - first) as f32;
+            let first = (first as usize).min(last);
+            vec![(first, 1.0 - fraction), ((first + 1).min(last), fraction)]
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(width: u32, height: u32, interpolation: Interpolation, mode: ScaleMode) -> OutputSize {
+        OutputSize { width, height, interpolation, mode }
+    }
+
+    fn scaled(src: &[u8], src_width: usize, src_height: usize, size: OutputSize) -> Vec<u8> {
+        let mut output = vec![7u8; (size.width * size.height) as usize];
+        resize(src, src_width, src_height, 1, size, size.width as usize, &mut output);
+        output
+    }
+
+    #[test]
+    fn shrinks_with_every_interpolation() {
+        let src = [
+            0, 10, 20, 30,
+            40, 50, 60, 70,
+            80, 90, 100, 110,
+            120, 130, 140, 150,
+        ];
+
+        assert_eq!(scaled(&src, 4, 4, size(2, 2, Interpolation::Nearest, ScaleMode::Stretch)), vec![50, 70, 130, 150]);
+        assert_eq!(scaled(&src, 4, 4, size(2, 2, Interpolation::Bilinear, ScaleMode::Stretch)), vec![25, 45, 105, 125]);
+        assert_eq!(scaled(&src, 4, 4, size(2, 2, Interpolation::Area, ScaleMode::Stretch)), vec![25, 45, 105, 125]);
+        assert_eq!(scaled(&src, 4, 4, size(1, 1, Interpolation::Area, ScaleMode::Stretch)), vec![75]);
+    }
+
+    #[test]
+    fn enlarges_bilinearly() {
+        assert_eq!(scaled(&[0, 100], 2, 1, size(4, 1, Interpolation::Bilinear, ScaleMode::Stretch)), vec![0, 25, 75, 100]);
+        assert_eq!(scaled(&[0, 100], 2, 1, size(4, 1, Interpolation::Area, ScaleMode::Stretch)), vec![0, 25, 75, 100]);
+    }
+
+    #[test]
+    fn letterboxes_and_crops_to_keep_the_aspect_ratio() {
+        let src = [10, 20, 30, 40];
+
+        // 4x1 into 2x2: one row of image between two half rows of bars, rounded to the top
+        assert_eq!(scaled(&src, 4, 1, size(2, 2, Interpolation::Nearest, ScaleMode::Letterbox)), vec![20, 40, 0, 0]);
+        // 4x1 into 2x1 keeps the center half
+        assert_eq!(scaled(&src, 4, 1, size(2, 1, Interpolation::Nearest, ScaleMode::Crop)), vec![20, 30]);
+    }
+
+    #[test]
+    fn keeps_padding_and_opaque_bars() {
+        let src = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut output = vec![9u8; 2 * 12];
+        resize(&src, 2, 1, 4, size(2, 2, Interpolation::Nearest, ScaleMode::Letterbox), 12, &mut output);
+
+        assert_eq!(&output[..12], &[1, 2, 3, 4, 5, 6, 7, 8, 9, 9, 9, 9]);
+        assert_eq!(&output[12..], &[0, 0, 0, 255, 0, 0, 0, 255, 9, 9, 9, 9]);
+    }
+}
@@ -0,0 +1,300 @@
+use crate::frame_callback::FrameCallback;
+use crate::frame_info::FrameInfo;
+use crate::output_format::OutputFormat;
+use crate::frame_source::FrameSource;
+use crate::avi::AviFile;
+use crate::frame_ring::{FrameRing, RingFrame};
+#[cfg(feature = "h264")]
+use crate::h264::H264Recording;
+#[cfg(feature = "h264")]
+use crate::i420::I420Frame;
+use crate::raw_stream::RawStreamFile;
+use crate::resize::OutputSize;
+use crate::crop::CropRect;
+use crate::orientation::Orientation;
+use crate::color_space::YuvColorSpace;
+use nokhwa::{Buffer, NokhwaError};
+use parking_lot::{Condvar, Mutex};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Per-session settings, applied both by the grab functions and by the frame callback.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SessionSettings {
+    pub output_format: OutputFormat,
+    // Applied before scaling, None keeps the whole frame
+    pub crop: Option<CropRect>,
+    // None keeps the resolution of the camera
+    pub output_size: Option<OutputSize>,
+    pub orientation: Orientation,
+    pub color_space: YuvColorSpace
+}
+
+/// A raw frame received from the camera together with its capture metadata.
+#[derive(Clone)]
+pub struct CapturedFrame {
+    pub buffer: Buffer,
+    pub info: FrameInfo
+}
+
+#[derive(Default)]
+struct FrameSlot {
+    latest: Option<CapturedFrame>,
+    stopped: bool,
+    device_lost: bool
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WaitResult {
+    NewFrame,
+    TimedOut,
+    Stopped,
+    DeviceLost
+}
+
+/// Session data shared with the capture thread.
+#[derive(Default)]
+pub struct SessionShared {
+    pub settings: Mutex<SessionSettings>,
+    frames: Mutex<FrameSlot>,
+    frame_arrived: Condvar,
+    pub frame_callback: Mutex<Option<FrameCallback>>,
+    // Reused destination for frames converted for the callback
+    pub callback_buffer: Mutex<Vec<u8>>,
+    pub raw_recording: Mutex<Option<RawStreamFile>>,
+    pub video_recording: Mutex<Option<AviFile>>,
+    pub frame_ring: Mutex<Option<FrameRing>>,
+    #[cfg(feature = "h264")]
+    pub h264_recording: Mutex<Option<H264Recording>>
+}
+
+#[derive(Clone)]
+pub struct Session {
+    pub source: Arc<Mutex<Box<dyn FrameSource>>>,
+    pub shared: Arc<SessionShared>
+}
+
+impl SessionShared {
+    /// Stamps a new frame with the next sequence number and makes it the latest frame of the session.
+    pub fn record_frame(&self, buffer: Buffer) -> CapturedFrame {
+        let mut frames = self.frames.lock();
+        let sequence = frames.latest.as_ref().map_or(0, |f| f.info.sequence) + 1;
+
+        let frame = CapturedFrame {
+            buffer,
+            info: FrameInfo::captured_now(sequence)
+        };
+        frames.latest = Some(frame.clone());
+        self.frame_arrived.notify_all();
+
+        frame
+    }
+
+    /// Appends a frame to the raw recording, if any. Recording stops on the first write error.
+    pub fn write_raw_frame(&self, frame: &CapturedFrame) {
+        let mut recording = self.raw_recording.lock();
+        let Some(writer) = recording.as_mut() else { return };
+
+        if let Err(err) = writer.write_frame(frame.info.timestamp_us, &frame.buffer) {
+            eprintln!("Error writing raw recording, stopping it: {}", err);
+            *recording = None;
+        }
+    }
+
+    /// Appends a frame to the video recording, if any, encoded with `encode_jpeg`.
+    /// Recording stops, leaving a playable file, on the first write error.
+    pub fn write_video_frame(&self, frame: &CapturedFrame, encode_jpeg: impl FnOnce(&CapturedFrame) -> Result<Vec<u8>, NokhwaError>) {
+        let mut recording = self.video_recording.lock();
+        let Some(writer) = recording.as_mut() else { return };
+
+        let jpeg = match encode_jpeg(frame) {
+            Ok(jpeg) => jpeg,
+            Err(err) => {
+                eprintln!("Error encoding frame for video recording: {}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = writer.write_frame(frame.info.timestamp_us, &jpeg) {
+            eprintln!("Error writing video recording, stopping it: {}", err);
+            if let Some(writer) = recording.take() {
+                if let Err(err) = writer.finish() {
+                    eprintln!("Error finishing video recording: {}", err);
+                }
+            }
+        }
+    }
+
+    /// Adds a frame to the ring buffer, if any, encoded with `encode_jpeg`.
+    pub fn write_ring_frame(&self, frame: &CapturedFrame, encode_jpeg: impl FnOnce(&CapturedFrame) -> Result<Vec<u8>, NokhwaError>) {
+        let mut ring = self.frame_ring.lock();
+        let Some(ring) = ring.as_mut() else { return };
+
+        match encode_jpeg(frame) {
+            Ok(jpeg) => ring.push(RingFrame { timestamp_us: frame.info.timestamp_us, jpeg: jpeg.into() }),
+            Err(err) => eprintln!("Error encoding frame for ring buffer: {}", err)
+        }
+    }
+
+    /// Finishes the video recording, if any, so the file is playable.
+    pub fn finish_video_recording(&self) -> Option<std::io::Result<()>> {
+        self.video_recording.lock().take().map(|writer| writer.finish())
+    }
+
+    /// Appends a frame to the H.264 recording, if any, converted with `to_i420`.
+    /// Recording stops, leaving a playable file, on the first write error.
+    #[cfg(feature = "h264")]
+    pub fn write_h264_frame(&self, frame: &CapturedFrame, to_i420: impl FnOnce(&CapturedFrame) -> Result<I420Frame, NokhwaError>) {
+        let mut recording = self.h264_recording.lock();
+        let Some(writer) = recording.as_mut() else { return };
+
+        let i420 = match to_i420(frame) {
+            Ok(i420) => i420,
+            Err(err) => {
+                eprintln!("Error converting frame for H.264 recording: {}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = writer.write_frame(frame.info.timestamp_us, i420) {
+            eprintln!("Error writing H.264 recording, stopping it: {}", err);
+            if let Some(writer) = recording.take() {
+                if let Err(err) = writer.finish() {
+                    eprintln!("Error finishing H.264 recording: {}", err);
+                }
+            }
+        }
+    }
+
+    /// Finishes the H.264 recording, if any, so the file is playable.
+    #[cfg(feature = "h264")]
+    pub fn finish_h264_recording(&self) -> Option<std::io::Result<()>> {
+        self.h264_recording.lock().take().map(|writer| writer.finish())
+    }
+
+    pub fn latest_frame(&self) -> Option<CapturedFrame> {
+        self.frames.lock().latest.clone()
+    }
+
+    /// Wakes up every waiter, which will report the session as stopped.
+    pub fn mark_stopped(&self) {
+        self.frames.lock().stopped = true;
+        self.frame_arrived.notify_all();
+    }
+
+    /// Wakes up every waiter, which will report the device as lost, finishes the video recordings and drops
+    /// the ring buffer, which ends the dumps still following it.
+    pub fn mark_device_lost(&self) {
+        self.frames.lock().device_lost = true;
+        self.frame_arrived.notify_all();
+        *self.frame_ring.lock() = None;
+
+        if let Some(Err(err)) = self.finish_video_recording() {
+            eprintln!("Error finishing video recording of lost device: {}", err);
+        }
+
+        #[cfg(feature = "h264")]
+        if let Some(Err(err)) = self.finish_h264_recording() {
+            eprintln!("Error finishing H.264 recording of lost device: {}", err);
+        }
+    }
+
+    /// Blocks until a frame with a sequence number greater than `after_sequence` is available,
+    /// the session is stopped, the device is lost or the timeout elapses.
+    pub fn wait_frame(&self, after_sequence: u64, timeout: Duration) -> WaitResult {
+        let deadline = Instant::now() + timeout;
+        let mut frames = self.frames.lock();
+
+        loop {
+            if frames.latest.as_ref().is_some_and(|f| f.info.sequence > after_sequence) {
+                return WaitResult::NewFrame;
+            }
+
+            if frames.stopped {
+                return WaitResult::Stopped;
+            }
+
+            if frames.device_lost {
+                return WaitResult::DeviceLost;
+            }
+
+            if self.frame_arrived.wait_until(&mut frames, deadline).timed_out() {
+                return WaitResult::TimedOut;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nokhwa::utils::{FrameFormat, Resolution};
+
+    fn frame() -> Buffer {
+        Buffer::new(Resolution::new(2, 1), &[16, 128, 16, 128], FrameFormat::YUYV)
+    }
+
+    #[test]
+    fn numbers_frames_from_one() {
+        let shared = SessionShared::default();
+        assert!(shared.latest_frame().is_none());
+
+        let infos: Vec<FrameInfo> = (0..3).map(|_| shared.record_frame(frame()).info).collect();
+        assert_eq!(infos.iter().map(|info| info.sequence).collect::<Vec<_>>(), [1, 2, 3]);
+        assert!(infos.windows(2).all(|pair| pair[0].timestamp_us <= pair[1].timestamp_us && pair[0].wall_clock_us <= pair[1].wall_clock_us));
+        assert_eq!(shared.latest_frame().unwrap().info, infos[2]);
+
+        // Every session starts over
+        assert_eq!(SessionShared::default().record_frame(frame()).info.sequence, 1);
+    }
+
+    // Wakes a waiter blocked on `shared` with `wake` and returns what the waiter saw, and how long it waited
+    fn wake_waiter(wake: impl FnOnce(&SessionShared)) -> (WaitResult, Duration) {
+        let shared = Arc::new(SessionShared::default());
+        let waiter_shared = shared.clone();
+        let waiter = std::thread::spawn(move || {
+            let start = Instant::now();
+            (waiter_shared.wait_frame(0, Duration::from_secs(10)), start.elapsed())
+        });
+
+        // Lets the waiter block, the result is the same if it hasn't yet
+        std::thread::sleep(Duration::from_millis(20));
+        wake(&shared);
+
+        waiter.join().unwrap()
+    }
+
+    #[test]
+    fn waits_for_a_frame_newer_than_the_last_one() {
+        let shared = SessionShared::default();
+        assert_eq!(shared.wait_frame(0, Duration::from_millis(10)), WaitResult::TimedOut);
+
+        shared.record_frame(frame());
+        assert_eq!(shared.wait_frame(0, Duration::ZERO), WaitResult::NewFrame);
+        assert_eq!(shared.wait_frame(1, Duration::from_millis(10)), WaitResult::TimedOut);
+
+        let (result, waited) = wake_waiter(|shared| {
+            shared.record_frame(frame());
+        });
+        assert_eq!(result, WaitResult::NewFrame);
+        assert!(waited < Duration::from_secs(5), "{:?}", waited);
+    }
+
+    #[test]
+    fn wakes_waiters_when_the_session_ends() {
+        let (result, waited) = wake_waiter(SessionShared::mark_stopped);
+        assert_eq!(result, WaitResult::Stopped);
+        assert!(waited < Duration::from_secs(5), "{:?}", waited);
+
+        let (result, waited) = wake_waiter(SessionShared::mark_device_lost);
+        assert_eq!(result, WaitResult::DeviceLost);
+        assert!(waited < Duration::from_secs(5), "{:?}", waited);
+
+        // Frames already captured are still handed out
+        let shared = SessionShared::default();
+        shared.record_frame(frame());
+        shared.mark_stopped();
+        assert_eq!(shared.wait_frame(0, Duration::ZERO), WaitResult::NewFrame);
+        assert_eq!(shared.wait_frame(1, Duration::from_secs(10)), WaitResult::Stopped);
+    }
+}
@@ -0,0 +1,250 @@
+use image::codecs::bmp::BmpEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder, ImageResult};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter};
+
+pub const DEFAULT_JPEG_QUALITY: u8 = 85;
+
+// The Huffman tables of section K.3 of the JPEG specification, which MJPEG frames without tables are decoded with:
+// (table class and id, number of codes of each length, values)
+const STANDARD_HUFFMAN_TABLES: [(u8, [u8; 16], &[u8]); 4] = [
+    (0x00, [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0], &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]),
+    (0x01, [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0], &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]),
+    (0x10, [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D], &[
+        0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+        0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+        0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+        0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+        0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+        0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+        0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+        0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+        0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+        0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+        0xF9, 0xFA,
+    ]),
+    (0x11, [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77], &[
+        0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+        0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0,
+        0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26,
+        0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+        0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+        0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+        0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5,
+        0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3,
+        0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA,
+        0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+        0xF9, 0xFA,
+    ]),
+];
+
+/// Image file format of snapshots. The discriminants are the values used by the C API.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum SnapshotFormat {
+    Jpeg = 0,
+    Png = 1,
+    Bmp = 2,
+}
+
+impl SnapshotFormat {
+    pub fn from_code(code: i32) -> Option<SnapshotFormat> {
+        match code {
+            0 => Some(SnapshotFormat::Jpeg),
+            1 => Some(SnapshotFormat::Png),
+            2 => Some(SnapshotFormat::Bmp),
+            _ => None,
+        }
+    }
+}
+
+impl Display for SnapshotFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotFormat::Jpeg => write!(f, "JPEG"),
+            SnapshotFormat::Png => write!(f, "PNG"),
+            SnapshotFormat::Bmp => write!(f, "BMP"),
+        }
+    }
+}
+
+/// An image encoded by a size query, kept until the caller comes back with a buffer for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingSnapshot {
+    pub unique_id: String,
+    pub format: SnapshotFormat,
+    pub quality: u8,
+    pub data: Vec<u8>,
+}
+
+thread_local! {
+    static PENDING_SNAPSHOT: RefCell<Option<PendingSnapshot>> = const { RefCell::new(None) };
+}
+
+pub fn keep_pending(snapshot: PendingSnapshot) {
+    PENDING_SNAPSHOT.with(|pending| *pending.borrow_mut() = Some(snapshot));
+}
+
+/// Takes the image kept by the last size query of the current thread, if it was for the same request.
+pub fn take_pending(unique_id: &str, format: SnapshotFormat, quality: u8) -> Option<Vec<u8>> {
+    PENDING_SNAPSHOT.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        let matches = pending.as_ref()
+            .is_some_and(|p| p.unique_id == unique_id && p.format == format && p.quality == quality);
+
+        if matches { pending.take().map(|p| p.data) } else { None }
+    })
+}
+
+/// JPEG quality from the C API, where 0 picks the default.
+pub fn jpeg_quality(quality: u32) -> u8 {
+    if quality == 0 { DEFAULT_JPEG_QUALITY } else { quality.min(100) as u8 }
+}
+
+/// Encodes packed RGB pixels. The quality only applies to JPEG.
+pub fn encode_rgb(rgb: &[u8], width: u32, height: u32, format: SnapshotFormat, quality: u8) -> ImageResult<Vec<u8>> {
+    let mut encoded = vec![];
+
+    match format {
+        SnapshotFormat::Jpeg => JpegEncoder::new_with_quality(&mut encoded, quality)
+            .write_image(rgb, width, height, ExtendedColorType::Rgb8)?,
+        SnapshotFormat::Png => PngEncoder::new(&mut encoded)
+            .write_image(rgb, width, height, ExtendedColorType::Rgb8)?,
+        SnapshotFormat::Bmp => BmpEncoder::new(&mut encoded)
+            .write_image(rgb, width, height, ExtendedColorType::Rgb8)?,
+    }
+
+    Ok(encoded)
+}
+
+/// `jpeg` with the standard Huffman tables inserted before its first scan if it has none. Many cameras leave them
+/// out of their MJPEG frames, as AVI allows, but image viewers need them in JPEG files. Frames whose markers can't
+/// be followed are returned as they are.
+pub fn with_huffman_tables(jpeg: &[u8]) -> Cow<'_, [u8]> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return Cow::Borrowed(jpeg);
+    }
+
+    let mut position = 2;
+    while position + 4 <= jpeg.len() && jpeg[position] == 0xFF {
+        match jpeg[position + 1] {
+            // Fill bytes before a marker
+            0xFF => position += 1,
+            0xC4 => break,
+            0xDA => {
+                let mut with_tables = Vec::with_capacity(jpeg.len() + 420);
+                with_tables.extend_from_slice(&jpeg[..position]);
+                with_tables.extend_from_slice(&standard_huffman_tables());
+                with_tables.extend_from_slice(&jpeg[position..]);
+                return Cow::Owned(with_tables);
+            }
+            // Markers without a length
+            0x01 | 0xD0..=0xD7 => position += 2,
+            _ => position += 2 + u16::from_be_bytes([jpeg[position + 2], jpeg[position + 3]]) as usize,
+        }
+    }
+
+    Cow::Borrowed(jpeg)
+}
+
+// A DHT segment with the standard tables
+fn standard_huffman_tables() -> Vec<u8> {
+    let length = 2 + STANDARD_HUFFMAN_TABLES.iter().map(|(_, _, values)| 1 + 16 + values.len()).sum::<usize>();
+
+    let mut segment = vec![0xFF, 0xC4];
+    segment.extend_from_slice(&(length as u16).to_be_bytes());
+    for (class_and_id, counts, values) in STANDARD_HUFFMAN_TABLES {
+        segment.push(class_and_id);
+        segment.extend_from_slice(&counts);
+        segment.extend_from_slice(values);
+    }
+
+    segment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::ImageFormat;
+
+    #[test]
+    fn encodes_every_format() {
+        let rgb: Vec<u8> = (0..8 * 6 * 3).map(|i| (i * 5) as u8).collect();
+
+        for (format, image_format) in [
+            (SnapshotFormat::Jpeg, ImageFormat::Jpeg),
+            (SnapshotFormat::Png, ImageFormat::Png),
+            (SnapshotFormat::Bmp, ImageFormat::Bmp),
+        ] {
+            let encoded = encode_rgb(&rgb, 8, 6, format, DEFAULT_JPEG_QUALITY).unwrap();
+            assert_eq!(image::guess_format(&encoded).unwrap(), image_format);
+
+            if format != SnapshotFormat::Jpeg {
+                let decoded = image::load_from_memory_with_format(&encoded, image_format).unwrap();
+                assert_eq!(decoded.to_rgb8().into_raw(), rgb, "{}", format);
+            }
+        }
+    }
+
+    #[test]
+    fn pending_snapshots_are_taken_once_by_the_same_request() {
+        keep_pending(PendingSnapshot { unique_id: "a".to_string(), format: SnapshotFormat::Png, quality: 85, data: vec![1, 2] });
+
+        assert_eq!(take_pending("a", SnapshotFormat::Jpeg, 85), None);
+        assert_eq!(take_pending("b", SnapshotFormat::Png, 85), None);
+        assert_eq!(take_pending("a", SnapshotFormat::Png, 85), Some(vec![1, 2]));
+        assert_eq!(take_pending("a", SnapshotFormat::Png, 85), None);
+    }
+
+    // The markers of a JPEG up to its first scan, with the length of their segment
+    fn markers(jpeg: &[u8]) -> Vec<(u8, usize)> {
+        let mut markers = vec![];
+        let mut position = 2;
+        loop {
+            let marker = jpeg[position + 1];
+            let length = u16::from_be_bytes([jpeg[position + 2], jpeg[position + 3]]) as usize;
+            markers.push((marker, length));
+            if marker == 0xDA {
+                return markers;
+            }
+            position += 2 + length;
+        }
+    }
+
+    #[test]
+    fn adds_missing_huffman_tables() {
+        let rgb: Vec<u8> = (0..16 * 16 * 3).map(|i| (i * 7) as u8).collect();
+        let jpeg = encode_rgb(&rgb, 16, 16, SnapshotFormat::Jpeg, 90).unwrap();
+        assert!(matches!(with_huffman_tables(&jpeg), Cow::Borrowed(_)));
+
+        // Like the frames of cameras leaving the tables out, which the encoder took from the specification too
+        let mut without_tables = jpeg[..2].to_vec();
+        let mut position = 2;
+        for (marker, length) in markers(&jpeg) {
+            if marker != 0xC4 {
+                let end = if marker == 0xDA { jpeg.len() } else { position + 2 + length };
+                without_tables.extend_from_slice(&jpeg[position..end]);
+            }
+            position += 2 + length;
+        }
+
+        let with_tables = with_huffman_tables(&without_tables);
+        let dht: Vec<usize> = markers(&with_tables).iter().filter(|(marker, _)| *marker == 0xC4).map(|(_, length)| *length).collect();
+        assert_eq!(dht, vec![0x1A2]);
+        assert_eq!(markers(&with_tables).last(), Some(&(0xDA, 12)));
+
+        let decode = |jpeg: &[u8]| image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg).unwrap().to_rgb8().into_raw();
+        assert_eq!(decode(&with_tables), decode(&jpeg));
+
+        assert!(matches!(with_huffman_tables(&[0xFF, 0xD8, 0xFF, 0xE0, 0x40]), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn maps_quality() {
+        assert_eq!(jpeg_quality(0), DEFAULT_JPEG_QUALITY);
+        assert_eq!(jpeg_quality(40), 40);
+        assert_eq!(jpeg_quality(250), 100);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::device_handle;
+use crate::frame_info::FrameInfo;
+use crate::frame_source::{FrameSink, FrameSource};
+use crate::raw_stream::{RawStreamFile, RawStreamHeader};
+use crate::video_device::VideoDevice;
+use crate::video_format::VideoFormat;
+use crate::virtual_camera::{counter_band_height, encode, test_pattern, COUNTER_BITS, DEFAULT_BRIGHTNESS};
+use crate::{State, RESULT_OK, STATE};
+use nokhwa::utils::{CameraControl, CameraFormat, CameraIndex, ControlValueSetter, FrameFormat, KnownCameraControl, Resolution};
+use nokhwa::{Buffer, NokhwaError};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Frames are replayed every 10 ms, so waiting for a few of them is quick
+const FRAME_INTERVAL_US: u64 = 10_000;
+const FRAME_RATE: u32 = 100;
+
+// Only an upper bound, for loaded CI machines: frames usually arrive within milliseconds
+pub const WAIT_MS: u32 = 10_000;
+
+/// A replay device capturing frames recorded on the fly, for the tests of the C API. Every test records its own
+/// file, so tests get their own device and session, don't depend on the cameras of the machine and can run in
+/// parallel. The capture is stopped and the file removed on drop.
+pub struct TestDevice {
+    pub handle: u32,
+    path: PathBuf,
+}
+
+impl TestDevice {
+    /// Lists `frames`, replayed in a loop, as a device and starts capturing from it. `name` must be unique among
+    /// the tests.
+    pub fn start(name: &str, frames: &[Buffer]) -> TestDevice {
+        let path = temp_path(name, "raw");
+        let header = RawStreamHeader { name: name.to_string(), frame_rate: FRAME_RATE };
+        let mut writer = RawStreamFile::create(&path, &header).unwrap();
+        for (n, frame) in frames.iter().enumerate() {
+            writer.write_frame(n as u64 * FRAME_INTERVAL_US, frame).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let handle = TestDevice::add(&path);
+        let resolution = frames[0].resolution();
+        assert_eq!(crate::cnokhwa_start_capture(handle, resolution.width(), resolution.height()), RESULT_OK);
+
+        TestDevice { handle, path }
+    }
+
+    /// Lists a device capturing from a `ManualSource` and starts capturing from it, returning the feed of the
+    /// source. `name` must be unique among the tests.
+    pub fn manual(name: &str, format: CameraFormat) -> (TestDevice, ManualFeed) {
+        let unique_id = format!("manual:{}", name);
+        let device = VideoDevice {
+            index: CameraIndex::String(unique_id.clone()),
+            handle: device_handle::handle_for(&unique_id),
+            unique_id,
+            model_id: "Manual".to_string(),
+            name: name.to_string(),
+            formats: vec![VideoFormat {
+                index: 0,
+                width: format.width(),
+                height: format.height(),
+                format: format.format(),
+                frame_rate: format.frame_rate(),
+            }],
+        };
+
+        let (source, feed) = ManualSource::new(format);
+        let mut state = STATE.lock();
+        let state = state.get_or_insert_with(empty_state);
+        state.devices.retain(|d| d.unique_id != device.unique_id);
+        state.devices.push(device.clone());
+        assert_eq!(crate::start_session(state, &device, Box::new(source)), RESULT_OK);
+
+        (TestDevice { handle: device.handle, path: PathBuf::new() }, feed)
+    }
+
+    /// Lists a raw stream file as a device, initializing the state without the devices of the machine if needed.
+    pub fn add(path: &Path) -> u32 {
+        STATE.lock().get_or_insert_with(empty_state);
+
+        let path = CString::new(path.to_str().unwrap()).unwrap();
+        let handle = crate::cnokhwa_add_replay_device(path.as_ptr());
+        assert!(handle > 0, "{}", handle);
+
+        handle as u32
+    }
+
+    /// Waits for a frame newer than the latest one and returns its info.
+    pub fn next_frame(&self) -> FrameInfo {
+        let mut info = FrameInfo::default();
+        crate::cnokhwa_frame_info(self.handle, &mut info);
+
+        assert_eq!(crate::cnokhwa_wait_frame(self.handle, info.sequence, WAIT_MS), RESULT_OK);
+        assert_eq!(crate::cnokhwa_frame_info(self.handle, &mut info), RESULT_OK);
+
+        info
+    }
+}
+
+impl Drop for TestDevice {
+    fn drop(&mut self) {
+        // Tests may have stopped the capture themselves
+        crate::cnokhwa_stop_capture(self.handle);
+        if !self.path.as_os_str().is_empty() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn empty_state() -> State {
+    State { devices: vec![], camera_sessions: HashMap::new(), device_controls: HashMap::new() }
+}
+
+/// A source delivering the frames a test pushes through its `ManualFeed`, on the thread of the test, so sessions
+/// can be driven one frame at a time.
+pub struct ManualSource {
+    format: CameraFormat,
+    sink: Arc<Mutex<Option<FrameSink>>>,
+}
+
+impl ManualSource {
+    pub fn new(format: CameraFormat) -> (ManualSource, ManualFeed) {
+        let sink = Arc::new(Mutex::new(None));
+
+        (ManualSource { format, sink: sink.clone() }, ManualFeed(sink))
+    }
+}
+
+/// Pushes frames into a `ManualSource`.
+pub struct ManualFeed(Arc<Mutex<Option<FrameSink>>>);
+
+impl ManualFeed {
+    /// Delivers a frame to the sink of the source, returning false if the source is not started.
+    pub fn push(&self, frame: Buffer) -> bool {
+        match self.0.lock().as_mut() {
+            Some(sink) => {
+                sink(frame);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl FrameSource for ManualSource {
+    fn formats(&mut self) -> Result<Vec<CameraFormat>, NokhwaError> {
+        Ok(vec![self.format])
+    }
+
+    fn format(&self) -> Result<CameraFormat, NokhwaError> {
+        Ok(self.format)
+    }
+
+    fn start(&mut self, sink: FrameSink) -> Result<(), NokhwaError> {
+        let mut current = self.sink.lock();
+        if current.is_some() {
+            return Err(NokhwaError::OpenStreamError("Stream Already Open".to_string()));
+        }
+
+        *current = Some(sink);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), NokhwaError> {
+        *self.sink.lock() = None;
+        Ok(())
+    }
+
+    fn controls(&self) -> Result<Vec<CameraControl>, NokhwaError> {
+        Ok(vec![])
+    }
+
+    fn control(&self, control: KnownCameraControl) -> Result<CameraControl, NokhwaError> {
+        Err(NokhwaError::GetPropertyError { property: control.to_string(), error: "Manual sources have no controls".to_string() })
+    }
+
+    fn set_control(&mut self, control: KnownCameraControl, value: ControlValueSetter) -> Result<(), NokhwaError> {
+        Err(NokhwaError::SetPropertyError {
+            property: control.to_string(),
+            value: value.to_string(),
+            error: "Manual sources have no controls".to_string(),
+        })
+    }
+}
+
+/// A file name of the temporary directory unique to the test process.
+pub fn temp_path(name: &str, extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("cnokhwa-{}-{}.{}", name, std::process::id(), extension))
+}
+
+/// `count` frames of the virtual camera test pattern, with the frame counter going from 0 to `count - 1`.
+pub fn pattern_frames(width: u32, height: u32, format: FrameFormat, count: u64) -> Vec<Buffer> {
+    (0..count)
+        .map(|n| {
+            let data = encode(&test_pattern(width, height, n, DEFAULT_BRIGHTNESS), width, height, format).unwrap();
+            Buffer::new(Resolution::new(width, height), &data, format)
+        })
+        .collect()
+}
+
+/// Reads the frame counter back from an RGB frame of the test pattern.
+pub fn read_counter(rgb: &[u8], width: u32, height: u32) -> u32 {
+    let width = width as usize;
+    let block_width = width / COUNTER_BITS as usize;
+    let y = counter_band_height(height as usize) / 2;
+
+    (0..COUNTER_BITS as usize).fold(0, |counter, bit| {
+        let x = bit * block_width + block_width / 2;
+        let set = rgb[(y * width + x) * 3 + 1] > 128;
+        counter << 1 | set as u32
+    })
+}
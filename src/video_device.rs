@@ -4,6 +4,7 @@ use crate::video_format::VideoFormat;
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub struct VideoDevice {
     pub index: CameraIndex,
+    pub handle: u32,
     pub unique_id: String,
     pub model_id: String,
     pub name: String,
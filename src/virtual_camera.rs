@@ -0,0 +1,603 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::ExtendedColorType;
+use nokhwa::utils::{
+    ApiBackend, CameraControl, CameraFormat, CameraIndex, CameraInfo, ControlValueDescription, ControlValueSetter,
+    FrameFormat, KnownCameraControl, KnownCameraControlFlag, RequestedFormat, Resolution,
+};
+use nokhwa::camera_traits::CaptureBackendTrait;
+use nokhwa::{Buffer, Camera, NokhwaError};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Virtual devices are configured with this variable, e.g. "Front=MJPEG:640x480@30,YUYV:320x240@15;Back=NV12:1280x720@30".
+// "1" (or an empty value) enables the default device and "0" disables them, even when built with the virtual-camera feature
+const DEVICES_VARIABLE: &str = "CNOKHWA_VIRTUAL_CAMERAS";
+
+const INDEX_PREFIX: &str = "virtual:";
+const UNIQUE_ID_PREFIX: &str = "cnokhwa-virtual-";
+const DESCRIPTION: &str = "cnokhwa virtual camera";
+
+// The frame counter is drawn as 32 black or white blocks along the top of the image, most significant bit first
+pub const COUNTER_BITS: u32 = 32;
+
+// Colour bars, scrolling to the left a few pixels per frame
+const BARS: [[u8; 3]; 8] = [
+    [235, 235, 235],
+    [235, 235, 16],
+    [16, 235, 235],
+    [16, 235, 16],
+    [235, 16, 235],
+    [235, 16, 16],
+    [16, 16, 235],
+    [16, 16, 16],
+];
+const SCROLL_PIXELS_PER_FRAME: u64 = 4;
+
+pub const DEFAULT_BRIGHTNESS: i64 = 128;
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VirtualDevice {
+    pub name: String,
+    pub formats: Vec<CameraFormat>,
+}
+
+/// Virtual devices to list, empty unless enabled by the `virtual-camera` feature or `CNOKHWA_VIRTUAL_CAMERAS`.
+pub fn configured_devices() -> Vec<VirtualDevice> {
+    match std::env::var(DEVICES_VARIABLE) {
+        Ok(spec) if spec.trim() == "0" => vec![],
+        Ok(spec) if spec.trim().is_empty() || spec.trim() == "1" => vec![default_device()],
+        Ok(spec) => parse_devices(&spec),
+        Err(_) if cfg!(feature = "virtual-camera") => vec![default_device()],
+        Err(_) => vec![],
+    }
+}
+
+pub fn devices() -> Vec<CameraInfo> {
+    configured_devices()
+        .iter()
+        .enumerate()
+        .map(|(n, device)| camera_info(n, device))
+        .collect()
+}
+
+pub fn is_virtual(index: &CameraIndex) -> bool {
+    matches!(index, CameraIndex::String(s) if s.starts_with(INDEX_PREFIX))
+}
+
+/// Opens a virtual device with the format fulfilling `format`, wrapped as a regular nokhwa camera.
+pub fn open(index: &CameraIndex, format: RequestedFormat) -> Result<Camera, NokhwaError> {
+    let not_found = || NokhwaError::OpenDeviceError(index.to_string(), "No such virtual device".to_string());
+
+    let CameraIndex::String(s) = index else { return Err(not_found()) };
+    let n: usize = s.strip_prefix(INDEX_PREFIX).and_then(|n| n.parse().ok()).ok_or_else(not_found)?;
+    let device = configured_devices().into_iter().nth(n).ok_or_else(not_found)?;
+
+    open_device(n, device, format)
+}
+
+/// Opens `device` as the virtual device at position `n`, whether it is configured or not.
+pub fn open_device(n: usize, device: VirtualDevice, format: RequestedFormat) -> Result<Camera, NokhwaError> {
+    let index = CameraIndex::String(format!("{}{}", INDEX_PREFIX, n));
+
+    let camera_format = format.fulfill(&device.formats)
+        .filter(|f| device.formats.contains(f))
+        .ok_or_else(|| NokhwaError::OpenDeviceError(index.to_string(), "Format not supported".to_string()))?;
+
+    let camera = VirtualCamera {
+        info: camera_info(n, &device),
+        formats: device.formats,
+        format: camera_format,
+        brightness: DEFAULT_BRIGHTNESS,
+        stream_open: false,
+        frame_number: 0,
+        next_frame_at: Instant::now(),
+        last_frame: vec![],
+    };
+
+    // nokhwa has no backend variant for custom backends
+    Ok(Camera::with_custom(index, ApiBackend::Auto, Box::new(camera)))
+}
+
+fn camera_info(n: usize, device: &VirtualDevice) -> CameraInfo {
+    CameraInfo::new(
+        &device.name,
+        DESCRIPTION,
+        &format!("{}{}", UNIQUE_ID_PREFIX, n),
+        CameraIndex::String(format!("{}{}", INDEX_PREFIX, n)),
+    )
+}
+
+fn default_device() -> VirtualDevice {
+    let formats = [FrameFormat::MJPEG, FrameFormat::YUYV, FrameFormat::NV12, FrameFormat::RAWRGB, FrameFormat::GRAY]
+        .iter()
+        .flat_map(|&format| [
+            CameraFormat::new(Resolution::new(640, 480), format, 30),
+            CameraFormat::new(Resolution::new(320, 240), format, 15),
+        ])
+        .collect();
+
+    VirtualDevice { name: "Virtual Camera".to_string(), formats }
+}
+
+// Invalid entries are skipped, so a typo doesn't hide the remaining devices
+fn parse_devices(spec: &str) -> Vec<VirtualDevice> {
+    spec.split(';')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .filter_map(|device| {
+            let Some((name, formats)) = device.split_once('=') else {
+                eprintln!("Invalid virtual device '{}', expected NAME=FORMAT:WIDTHxHEIGHT@FPS,...", device);
+                return None;
+            };
+
+            let formats: Vec<CameraFormat> = formats.split(',').filter_map(|f| {
+                let format = parse_format(f.trim());
+                if format.is_none() {
+                    eprintln!("Invalid format '{}' for virtual device {}", f.trim(), name.trim());
+                }
+                format
+            }).collect();
+
+            if formats.is_empty() {
+                return None;
+            }
+
+            Some(VirtualDevice { name: name.trim().to_string(), formats })
+        })
+        .collect()
+}
+
+fn parse_format(spec: &str) -> Option<CameraFormat> {
+    let (format, mode) = spec.split_once(':')?;
+    let (resolution, frame_rate) = mode.split_once('@')?;
+    let (width, height) = resolution.split_once('x')?;
+
+    let format = match format.to_ascii_uppercase().as_str() {
+        "MJPEG" => FrameFormat::MJPEG,
+        "YUYV" => FrameFormat::YUYV,
+        "NV12" => FrameFormat::NV12,
+        "RAWRGB" => FrameFormat::RAWRGB,
+        "RAWBGR" => FrameFormat::RAWBGR,
+        "GRAY" => FrameFormat::GRAY,
+        _ => return None,
+    };
+    let width: u32 = width.parse().ok()?;
+    let height: u32 = height.parse().ok()?;
+    let frame_rate: u32 = frame_rate.parse().ok()?;
+
+    // Chroma is shared by pixel pairs in YUYV and by 2x2 blocks in NV12
+    let even_width = width.is_multiple_of(2);
+    let even_height = height.is_multiple_of(2);
+    let valid = width >= COUNTER_BITS && height > 0 && frame_rate > 0 && match format {
+        FrameFormat::YUYV => even_width,
+        FrameFormat::NV12 => even_width && even_height,
+        _ => true,
+    };
+
+    valid.then(|| CameraFormat::new(Resolution::new(width, height), format, frame_rate))
+}
+
+/// Capture backend generating test patterns, paced to the frame rate of its format.
+struct VirtualCamera {
+    info: CameraInfo,
+    formats: Vec<CameraFormat>,
+    format: CameraFormat,
+    brightness: i64,
+    stream_open: bool,
+    frame_number: u64,
+    next_frame_at: Instant,
+    last_frame: Vec<u8>,
+}
+
+impl VirtualCamera {
+    fn switch_format(&mut self, format: CameraFormat) -> Result<(), NokhwaError> {
+        if !self.formats.contains(&format) {
+            return Err(NokhwaError::SetPropertyError {
+                property: "format".to_string(),
+                value: format.to_string(),
+                error: "Format not supported".to_string(),
+            });
+        }
+
+        self.format = format;
+        Ok(())
+    }
+
+    // Picks the supported format closest to the current one after changing one of its properties
+    fn find_format(&self, matches: impl Fn(&CameraFormat) -> bool) -> Result<CameraFormat, NokhwaError> {
+        self.formats.iter()
+            .filter(|f| matches(f))
+            .max_by_key(|f| (f.format() == self.format.format(), f.resolution() == self.format.resolution(), f.frame_rate() == self.format.frame_rate()))
+            .copied()
+            .ok_or_else(|| NokhwaError::SetPropertyError {
+                property: "format".to_string(),
+                value: self.format.to_string(),
+                error: "Format not supported".to_string(),
+            })
+    }
+
+    fn brightness_control(&self) -> CameraControl {
+        CameraControl::new(
+            KnownCameraControl::Brightness,
+            "Brightness".to_string(),
+            ControlValueDescription::IntegerRange {
+                min: 0,
+                max: 255,
+                value: self.brightness,
+                step: 1,
+                default: DEFAULT_BRIGHTNESS,
+            },
+            vec![KnownCameraControlFlag::Manual],
+            true,
+        )
+    }
+
+    fn capture(&mut self) -> Result<(), NokhwaError> {
+        if !self.stream_open {
+            return Err(NokhwaError::ReadFrameError("Stream is not open".to_string()));
+        }
+
+        let interval = Duration::from_secs(1) / self.format.frame_rate();
+        if let Some(wait) = self.next_frame_at.checked_duration_since(Instant::now()) {
+            std::thread::sleep(wait);
+        }
+        // A slow consumer makes frames be skipped, like a real camera, instead of delivering a burst to catch up
+        self.next_frame_at = self.next_frame_at.max(Instant::now()) + interval;
+
+        let resolution = self.format.resolution();
+        let rgb = test_pattern(resolution.width(), resolution.height(), self.frame_number, self.brightness);
+        self.last_frame = encode(&rgb, resolution.width(), resolution.height(), self.format.format())?;
+        self.frame_number += 1;
+
+        Ok(())
+    }
+}
+
+impl CaptureBackendTrait for VirtualCamera {
+    fn backend(&self) -> ApiBackend {
+        ApiBackend::Auto
+    }
+
+    fn camera_info(&self) -> &CameraInfo {
+        &self.info
+    }
+
+    fn refresh_camera_format(&mut self) -> Result<(), NokhwaError> {
+        Ok(())
+    }
+
+    fn camera_format(&self) -> CameraFormat {
+        self.format
+    }
+
+    fn set_camera_format(&mut self, new_fmt: CameraFormat) -> Result<(), NokhwaError> {
+        self.switch_format(new_fmt)
+    }
+
+    fn compatible_list_by_resolution(&mut self, fourcc: FrameFormat) -> Result<HashMap<Resolution, Vec<u32>>, NokhwaError> {
+        let mut resolutions: HashMap<Resolution, Vec<u32>> = HashMap::new();
+
+        for format in self.formats.iter().filter(|f| f.format() == fourcc) {
+            resolutions.entry(format.resolution()).or_default().push(format.frame_rate());
+        }
+
+        Ok(resolutions)
+    }
+
+    fn compatible_fourcc(&mut self) -> Result<Vec<FrameFormat>, NokhwaError> {
+        let mut fourccs: Vec<FrameFormat> = vec![];
+
+        for format in &self.formats {
+            if !fourccs.contains(&format.format()) {
+                fourccs.push(format.format());
+            }
+        }
+
+        Ok(fourccs)
+    }
+
+    fn resolution(&self) -> Resolution {
+        self.format.resolution()
+    }
+
+    fn set_resolution(&mut self, new_res: Resolution) -> Result<(), NokhwaError> {
+        let format = self.find_format(|f| f.resolution() == new_res)?;
+        self.switch_format(format)
+    }
+
+    fn frame_rate(&self) -> u32 {
+        self.format.frame_rate()
+    }
+
+    fn set_frame_rate(&mut self, new_fps: u32) -> Result<(), NokhwaError> {
+        let format = self.find_format(|f| f.frame_rate() == new_fps)?;
+        self.switch_format(format)
+    }
+
+    fn frame_format(&self) -> FrameFormat {
+        self.format.format()
+    }
+
+    fn set_frame_format(&mut self, fourcc: FrameFormat) -> Result<(), NokhwaError> {
+        let format = self.find_format(|f| f.format() == fourcc)?;
+        self.switch_format(format)
+    }
+
+    fn camera_control(&self, control: KnownCameraControl) -> Result<CameraControl, NokhwaError> {
+        match control {
+            KnownCameraControl::Brightness => Ok(self.brightness_control()),
+            _ => Err(NokhwaError::GetPropertyError {
+                property: control.to_string(),
+                error: "Not supported by virtual cameras".to_string(),
+            }),
+        }
+    }
+
+    fn camera_controls(&self) -> Result<Vec<CameraControl>, NokhwaError> {
+        Ok(vec![self.brightness_control()])
+    }
+
+    fn set_camera_control(&mut self, id: KnownCameraControl, value: ControlValueSetter) -> Result<(), NokhwaError> {
+        match (id, &value) {
+            (KnownCameraControl::Brightness, ControlValueSetter::Integer(v)) if (0..=255).contains(v) => {
+                self.brightness = *v;
+                Ok(())
+            }
+            _ => Err(NokhwaError::SetPropertyError {
+                property: id.to_string(),
+                value: value.to_string(),
+                error: "Not supported by virtual cameras".to_string(),
+            }),
+        }
+    }
+
+    fn open_stream(&mut self) -> Result<(), NokhwaError> {
+        self.stream_open = true;
+        self.frame_number = 0;
+        self.next_frame_at = Instant::now();
+
+        Ok(())
+    }
+
+    fn is_stream_open(&self) -> bool {
+        self.stream_open
+    }
+
+    fn frame(&mut self) -> Result<Buffer, NokhwaError> {
+        self.capture()?;
+
+        Ok(Buffer::new(self.format.resolution(), &self.last_frame, self.format.format()))
+    }
+
+    fn frame_raw(&mut self) -> Result<Cow<'_, [u8]>, NokhwaError> {
+        self.capture()?;
+
+        Ok(Cow::Borrowed(&self.last_frame))
+    }
+
+    fn stop_stream(&mut self) -> Result<(), NokhwaError> {
+        self.stream_open = false;
+
+        Ok(())
+    }
+}
+
+/// RGB test pattern of a frame: scrolling colour bars, shifted by `brightness`, under a band with the frame counter.
+pub fn test_pattern(width: u32, height: u32, frame_number: u64, brightness: i64) -> Vec<u8> {
+    let width = width as usize;
+    let height = height as usize;
+    let band_height = counter_band_height(height);
+    let block_width = width / COUNTER_BITS as usize;
+    let counter = frame_number as u32;
+    let scroll = (frame_number * SCROLL_PIXELS_PER_FRAME % width as u64) as usize;
+    let offset = brightness - DEFAULT_BRIGHTNESS;
+
+    let mut rgb = vec![0u8; width * height * 3];
+
+    for (y, row) in rgb.chunks_exact_mut(width * 3).enumerate() {
+        for (x, pixel) in row.chunks_exact_mut(3).enumerate() {
+            if y < band_height {
+                let bit = (x / block_width) as u32;
+                let set = bit < COUNTER_BITS && counter & (1 << (COUNTER_BITS - 1 - bit)) != 0;
+                pixel.fill(if set { 255 } else { 0 });
+            } else {
+                let bar = BARS[(x + scroll) % width * BARS.len() / width];
+                for (p, c) in pixel.iter_mut().zip(bar) {
+                    *p = (c as i64 + offset).clamp(0, 255) as u8;
+                }
+            }
+        }
+    }
+
+    rgb
+}
+
+pub fn counter_band_height(height: usize) -> usize {
+    (height / 16).clamp(1, 16)
+}
+
+pub fn encode(rgb: &[u8], width: u32, height: u32, format: FrameFormat) -> Result<Vec<u8>, NokhwaError> {
+    let width = width as usize;
+
+    let data = match format {
+        FrameFormat::RAWRGB => rgb.to_vec(),
+        FrameFormat::RAWBGR => rgb.chunks_exact(3).flat_map(|p| [p[2], p[1], p[0]]).collect(),
+        FrameFormat::GRAY => rgb.chunks_exact(3).map(full_range_luma).collect(),
+        FrameFormat::YUYV => rgb.chunks_exact(6).flat_map(|pair| {
+            let (u, v) = chroma(&[&pair[..3], &pair[3..]]);
+            [luma(&pair[..3]), u, luma(&pair[3..]), v]
+        }).collect(),
+        FrameFormat::NV12 => {
+            let mut data: Vec<u8> = rgb.chunks_exact(3).map(luma).collect();
+
+            for rows in rgb.chunks_exact(width * 6) {
+                let (top, bottom) = rows.split_at(width * 3);
+                for (t, b) in top.chunks_exact(6).zip(bottom.chunks_exact(6)) {
+                    let (u, v) = chroma(&[&t[..3], &t[3..], &b[..3], &b[3..]]);
+                    data.extend_from_slice(&[u, v]);
+                }
+            }
+
+            data
+        }
+        FrameFormat::MJPEG => {
+            let mut jpeg = vec![];
+            JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY)
+                .encode(rgb, width as u32, height, ExtendedColorType::Rgb8)
+                .map_err(|e| NokhwaError::ProcessFrameError {
+                    src: FrameFormat::RAWRGB,
+                    destination: "MJPEG".to_string(),
+                    error: e.to_string(),
+                })?;
+            jpeg
+        }
+    };
+
+    Ok(data)
+}
+
+// BT.601 limited range, as sent by most webcams
+
+fn luma(p: &[u8]) -> u8 {
+    let (r, g, b) = (p[0] as i32, p[1] as i32, p[2] as i32);
+    (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8
+}
+
+fn chroma(pixels: &[&[u8]]) -> (u8, u8) {
+    let n = pixels.len() as i32;
+    let (r, g, b) = pixels.iter().fold((0, 0, 0), |(r, g, b), p| (r + p[0] as i32, g + p[1] as i32, b + p[2] as i32));
+    let (r, g, b) = (r / n, g / n, b / n);
+
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+
+    (u as u8, v as u8)
+}
+
+fn full_range_luma(p: &[u8]) -> u8 {
+    ((77 * p[0] as u32 + 150 * p[1] as u32 + 29 * p[2] as u32) >> 8) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::read_counter;
+    use nokhwa::pixel_format::RgbFormat;
+
+    fn decode(data: &[u8], width: u32, height: u32, format: FrameFormat) -> Vec<u8> {
+        let buffer = Buffer::new(Resolution::new(width, height), data, format);
+        buffer.decode_image::<RgbFormat>().unwrap().into_raw()
+    }
+
+    #[test]
+    fn test_pattern_is_deterministic_and_moves() {
+        let frame = test_pattern(320, 240, 7, DEFAULT_BRIGHTNESS);
+
+        assert_eq!(frame, test_pattern(320, 240, 7, DEFAULT_BRIGHTNESS));
+        assert_ne!(frame, test_pattern(320, 240, 8, DEFAULT_BRIGHTNESS));
+        assert_eq!(frame.len(), 320 * 240 * 3);
+        assert_eq!(read_counter(&frame, 320, 240), 7);
+    }
+
+    #[test]
+    fn brightness_shifts_the_bars_only() {
+        let dark = test_pattern(320, 240, 0x1234_5678, 0);
+        let bright = test_pattern(320, 240, 0x1234_5678, 255);
+
+        assert_eq!(read_counter(&dark, 320, 240), 0x1234_5678);
+        assert_eq!(read_counter(&bright, 320, 240), 0x1234_5678);
+
+        let bars_start = counter_band_height(240) * 320 * 3;
+        assert!(dark[bars_start..].iter().zip(&bright[bars_start..]).all(|(d, b)| d <= b));
+    }
+
+    #[test]
+    fn every_format_decodes_back_to_the_counter() {
+        let (width, height) = (320, 240);
+        let counter = 0xA5C3_0F01;
+        let rgb = test_pattern(width, height, counter as u64, DEFAULT_BRIGHTNESS);
+
+        for format in [FrameFormat::MJPEG, FrameFormat::YUYV, FrameFormat::NV12, FrameFormat::RAWRGB, FrameFormat::RAWBGR, FrameFormat::GRAY] {
+            let data = encode(&rgb, width, height, format).unwrap();
+            let decoded = decode(&data, width, height, format);
+
+            assert_eq!(read_counter(&decoded, width, height), counter, "{}", format);
+        }
+    }
+
+    #[test]
+    fn encoded_sizes_match_the_formats() {
+        let rgb = test_pattern(64, 48, 0, DEFAULT_BRIGHTNESS);
+
+        assert_eq!(encode(&rgb, 64, 48, FrameFormat::YUYV).unwrap().len(), 64 * 48 * 2);
+        assert_eq!(encode(&rgb, 64, 48, FrameFormat::NV12).unwrap().len(), 64 * 48 * 3 / 2);
+        assert_eq!(encode(&rgb, 64, 48, FrameFormat::GRAY).unwrap().len(), 64 * 48);
+    }
+
+    #[test]
+    fn parses_device_configuration() {
+        let devices = parse_devices("Front=MJPEG:640x480@30, yuyv:320x240@15; Bad ; Back=NV12:1280x720@30,NV12:33x20@30");
+
+        assert_eq!(devices, vec![
+            VirtualDevice {
+                name: "Front".to_string(),
+                formats: vec![
+                    CameraFormat::new(Resolution::new(640, 480), FrameFormat::MJPEG, 30),
+                    CameraFormat::new(Resolution::new(320, 240), FrameFormat::YUYV, 15),
+                ],
+            },
+            VirtualDevice {
+                name: "Back".to_string(),
+                formats: vec![CameraFormat::new(Resolution::new(1280, 720), FrameFormat::NV12, 30)],
+            },
+        ]);
+    }
+
+    fn camera(format: CameraFormat) -> VirtualCamera {
+        VirtualCamera {
+            info: camera_info(0, &VirtualDevice { name: "Test".to_string(), formats: vec![format] }),
+            format,
+            formats: vec![format],
+            brightness: DEFAULT_BRIGHTNESS,
+            stream_open: false,
+            frame_number: 0,
+            next_frame_at: Instant::now(),
+            last_frame: vec![],
+        }
+    }
+
+    #[test]
+    fn frames_are_paced_to_the_frame_rate() {
+        let mut camera = camera(CameraFormat::new(Resolution::new(64, 48), FrameFormat::RAWRGB, 20));
+
+        assert!(camera.frame().is_err());
+
+        camera.open_stream().unwrap();
+        let start = Instant::now();
+        for n in 0..5 {
+            let frame = camera.frame().unwrap();
+            assert_eq!(read_counter(frame.buffer(), 64, 48), n);
+        }
+
+        // The first frame is immediate, the next four are 50 ms apart
+        assert!(start.elapsed() >= Duration::from_millis(195));
+    }
+
+    #[test]
+    fn brightness_is_a_control() {
+        let mut camera = camera(CameraFormat::new(Resolution::new(64, 48), FrameFormat::RAWRGB, 30));
+
+        camera.set_camera_control(KnownCameraControl::Brightness, ControlValueSetter::Integer(200)).unwrap();
+        let control = camera.camera_control(KnownCameraControl::Brightness).unwrap();
+        assert_eq!(control.value(), ControlValueSetter::Integer(200));
+
+        assert!(camera.set_camera_control(KnownCameraControl::Brightness, ControlValueSetter::Integer(256)).is_err());
+        assert!(camera.set_camera_control(KnownCameraControl::Contrast, ControlValueSetter::Integer(1)).is_err());
+
+        camera.open_stream().unwrap();
+        let frame = camera.frame().unwrap();
+        assert_eq!(frame.buffer(), test_pattern(64, 48, 0, 200));
+    }
+}
@@ -0,0 +1,326 @@
+use crate::color_space::{self, YuvCoefficients};
+use crate::output_format::OutputFormat;
+use nokhwa::utils::{FrameFormat, Resolution};
+use nokhwa::NokhwaError;
+
+/// Byte order of packed 4:2:2 YUV, where every pair of pixels shares its chroma.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum PackedYuv {
+    Yuyv,
+    // Frame formats are nokhwa's, which has no UYVY in 0.10, so no camera, replay or virtual device can deliver it:
+    // only the tests convert it until nokhwa has one
+    #[allow(dead_code)]
+    Uyvy,
+}
+
+/// Converts packed 4:2:2 YUV into RGB, BGR, RGBA or BGRA rows `stride` bytes apart with `coefficients`, rounding
+/// like `YuvCoefficients::to_rgb`, eight pixels at a time with SSE2 on x86_64 and with plain code elsewhere. Gray
+/// output is taken from the luma plane instead, and frames or outputs too small for the resolution are errors.
+pub fn convert(src: &[u8], resolution: Resolution, layout: PackedYuv, coefficients: &YuvCoefficients, output_format: OutputFormat, stride: usize, output: &mut [u8]) -> Result<(), NokhwaError> {
+    let (width, height) = (resolution.width() as usize, resolution.height() as usize);
+    let bytes_per_pixel = output_format.bytes_per_pixel();
+    let row_bytes = width * bytes_per_pixel;
+    let error = |error: String| NokhwaError::ProcessFrameError { src: FrameFormat::YUYV, destination: output_format.to_string(), error };
+
+    if output_format == OutputFormat::Gray8 {
+        return Err(NokhwaError::NotImplementedError("Gray output is not converted from packed YUV".to_string()));
+    }
+    if src.len() < width * 2 * height {
+        return Err(error(format!("Frame buffer too small for {}x{}", width, height)));
+    }
+    if stride < row_bytes || output.len() < stride * height.saturating_sub(1) + row_bytes {
+        return Err(error(format!("Output buffer too small for {} rows of {} bytes", height, stride)));
+    }
+
+    let rows = output.chunks_mut(stride).take(height).enumerate();
+    if width.is_multiple_of(2) {
+        for (row, dst_row) in rows {
+            convert_row(&src[row * width * 2..(row + 1) * width * 2], layout, coefficients, output_format, &mut dst_row[..row_bytes]);
+        }
+        return Ok(());
+    }
+
+    // With an odd width, every other row starts in the middle of a pair. As with nokhwa, the frame is one run of
+    // pairs, and the pairs straddling two rows are converted pixel by pixel.
+    for (row, dst_row) in rows {
+        let first = row * width;
+        let paired = (width - 1) * bytes_per_pixel;
+        if row.is_multiple_of(2) {
+            let (pairs, last) = dst_row[..row_bytes].split_at_mut(paired);
+            convert_row(&src[first * 2..(first + width - 1) * 2], layout, coefficients, output_format, pairs);
+            convert_pixel(src, first + width - 1, layout, coefficients, output_format, last);
+        } else {
+            let (single, pairs) = dst_row[..row_bytes].split_at_mut(bytes_per_pixel);
+            convert_pixel(src, first, layout, coefficients, output_format, single);
+            convert_row(&src[(first + 1) * 2..(first + width) * 2], layout, coefficients, output_format, pairs);
+        }
+    }
+
+    Ok(())
+}
+
+fn convert_row(src: &[u8], layout: PackedYuv, coefficients: &YuvCoefficients, output_format: OutputFormat, dst: &mut [u8]) {
+    let bytes_per_pixel = output_format.bytes_per_pixel();
+
+    #[cfg(target_arch = "x86_64")]
+    let converted = {
+        let pixels = src.len() / 2 / 8 * 8;
+        // SSE2 is part of the x86_64 baseline
+        unsafe { sse2::convert_pixels(&src[..pixels * 2], layout, coefficients, output_format, &mut dst[..pixels * bytes_per_pixel]) };
+        pixels
+    };
+    #[cfg(not(target_arch = "x86_64"))]
+    let converted = 0;
+
+    convert_pixels(&src[converted * 2..], layout, coefficients, output_format, &mut dst[converted * bytes_per_pixel..]);
+}
+
+// Byte offsets of the first luma, U, second luma and V in a pair
+fn offsets(layout: PackedYuv) -> (usize, usize, usize, usize) {
+    match layout {
+        PackedYuv::Yuyv => (0, 1, 2, 3),
+        PackedYuv::Uyvy => (1, 0, 3, 2),
+    }
+}
+
+// Reference conversion, also used for the pixels that don't fill a whole vector
+fn convert_pixels(src: &[u8], layout: PackedYuv, coefficients: &YuvCoefficients, output_format: OutputFormat, dst: &mut [u8]) {
+    let bytes_per_pixel = output_format.bytes_per_pixel();
+    let (y0, u, y1, v) = offsets(layout);
+
+    for (pair, out) in src.chunks_exact(4).zip(dst.chunks_exact_mut(bytes_per_pixel * 2)) {
+        let (first, second) = out.split_at_mut(bytes_per_pixel);
+        color_space::write_pixel(coefficients.to_rgb(pair[y0], pair[u], pair[v]), output_format, first);
+        color_space::write_pixel(coefficients.to_rgb(pair[y1], pair[u], pair[v]), output_format, second);
+    }
+}
+
+// Converts the pixel `index` of the frame. The last pixel of a frame with an odd number of pixels has no pair, its
+// missing chroma is taken as neutral.
+fn convert_pixel(src: &[u8], index: usize, layout: PackedYuv, coefficients: &YuvCoefficients, output_format: OutputFormat, dst: &mut [u8]) {
+    let (y0, u, y1, v) = offsets(layout);
+    let pair = index / 2 * 4;
+    let y = if index.is_multiple_of(2) { y0 } else { y1 };
+    let chroma = |offset: usize| src.get(pair + offset).copied().unwrap_or(128);
+
+    color_space::write_pixel(coefficients.to_rgb(src[pair + y], chroma(u), chroma(v)), output_format, dst);
+}
+
+#[cfg(target_arch = "x86_64")]
+mod sse2 {
+    use super::PackedYuv;
+    use crate::color_space::YuvCoefficients;
+    use crate::output_format::OutputFormat;
+    use std::arch::x86_64::*;
+
+    /// Converts a multiple of 8 pixels.
+    ///
+    /// # Safety
+    ///
+    /// `dst` must hold as many pixels as `src` in the output format.
+    pub unsafe fn convert_pixels(src: &[u8], layout: PackedYuv, coefficients: &YuvCoefficients, output_format: OutputFormat, dst: &mut [u8]) {
+        let bytes_per_pixel = output_format.bytes_per_pixel();
+        let low_bytes = _mm_set1_epi16(0x00FF);
+        let low_words = _mm_set1_epi32(0x0000_FFFF);
+        // Coefficient pairs for _mm_madd_epi16, which sums the products of neighboring 16-bit lanes
+        let YuvCoefficients { luma_offset, luma, red_v, green_u, green_v, blue_u } = *coefficients;
+        let red = _mm_set1_epi32(pair(luma, red_v));
+        let green_luma = _mm_set1_epi32(pair(luma, -green_u));
+        let green_chroma = _mm_set1_epi32(pair(-green_v, 0));
+        let blue = _mm_set1_epi32(pair(luma, blue_u));
+        let luma_offset = _mm_set1_epi16(luma_offset as i16);
+        let zero = _mm_setzero_si128();
+
+        for (block, out) in src.chunks_exact(16).zip(dst.chunks_exact_mut(8 * bytes_per_pixel)) {
+            let packed = _mm_loadu_si128(block.as_ptr() as *const __m128i);
+
+            // 8 lumas and 4 U/V pairs as 16-bit lanes
+            let (lumas, chroma) = match layout {
+                PackedYuv::Yuyv => (_mm_and_si128(packed, low_bytes), _mm_srli_epi16(packed, 8)),
+                PackedYuv::Uyvy => (_mm_srli_epi16(packed, 8), _mm_and_si128(packed, low_bytes)),
+            };
+
+            // Every pixel of a pair gets the U and V of the pair
+            let u = _mm_and_si128(chroma, low_words);
+            let u = _mm_or_si128(u, _mm_slli_epi32(u, 16));
+            let v = _mm_srli_epi32(chroma, 16);
+            let v = _mm_or_si128(v, _mm_slli_epi32(v, 16));
+
+            let y = _mm_sub_epi16(lumas, luma_offset);
+            let d = _mm_sub_epi16(u, _mm_set1_epi16(128));
+            let e = _mm_sub_epi16(v, _mm_set1_epi16(128));
+
+            let r = channel(y, e, red, zero, zero);
+            let g = channel(y, d, green_luma, e, green_chroma);
+            let b = channel(y, d, blue, zero, zero);
+
+            let (first, third) = match output_format {
+                OutputFormat::Bgr | OutputFormat::Bgra => (b, r),
+                _ => (r, b),
+            };
+
+            if bytes_per_pixel == 4 {
+                let first_second = _mm_unpacklo_epi8(first, g);
+                let third_alpha = _mm_unpacklo_epi8(third, _mm_set1_epi8(-1));
+                _mm_storeu_si128(out.as_mut_ptr() as *mut __m128i, _mm_unpacklo_epi16(first_second, third_alpha));
+                _mm_storeu_si128(out.as_mut_ptr().add(16) as *mut __m128i, _mm_unpackhi_epi16(first_second, third_alpha));
+            } else {
+                // SSE2 has no byte shuffle, 3 byte pixels are interleaved from the computed channels
+                let mut channels = [[0u8; 16]; 3];
+                for (lanes, value) in channels.iter_mut().zip([first, g, third]) {
+                    _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, value);
+                }
+                for (i, px) in out.chunks_exact_mut(3).enumerate() {
+                    px.copy_from_slice(&[channels[0][i], channels[1][i], channels[2][i]]);
+                }
+            }
+        }
+    }
+
+    // One channel of 8 pixels as bytes in the low half: (luma * y + c1 * first + c2 * second + 128) >> 8, saturated
+    // to 0-255 like the clamp of the reference, where c1 comes with the luma coefficient in `first_coefficients`
+    unsafe fn channel(y: __m128i, first: __m128i, first_coefficients: __m128i, second: __m128i, second_coefficients: __m128i) -> __m128i {
+        let zero = _mm_setzero_si128();
+        let rounding = _mm_set1_epi32(128);
+
+        let low = _mm_add_epi32(
+            _mm_madd_epi16(_mm_unpacklo_epi16(y, first), first_coefficients),
+            _mm_madd_epi16(_mm_unpacklo_epi16(second, zero), second_coefficients),
+        );
+        let high = _mm_add_epi32(
+            _mm_madd_epi16(_mm_unpackhi_epi16(y, first), first_coefficients),
+            _mm_madd_epi16(_mm_unpackhi_epi16(second, zero), second_coefficients),
+        );
+
+        let words = _mm_packs_epi32(
+            _mm_srai_epi32(_mm_add_epi32(low, rounding), 8),
+            _mm_srai_epi32(_mm_add_epi32(high, rounding), 8),
+        );
+        _mm_packus_epi16(words, words)
+    }
+
+    // Two 16-bit coefficients in the lane order of _mm_madd_epi16
+    fn pair(low: i32, high: i32) -> i32 {
+        (low as u16 as u32 | (high as u16 as u32) << 16) as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color_space::{YuvColorSpace, YuvMatrix, YuvRange};
+    use nokhwa::pixel_format::{RgbAFormat, RgbFormat};
+    use nokhwa::Buffer;
+
+    const FORMATS: [OutputFormat; 4] = [OutputFormat::Rgb, OutputFormat::Bgr, OutputFormat::Rgba, OutputFormat::Bgra];
+
+    // Deterministic bytes covering the whole range, including values outside of the limited range
+    fn pattern(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    fn bt601() -> YuvCoefficients {
+        YuvColorSpace { matrix: YuvMatrix::Bt601, range: YuvRange::Limited }.coefficients()
+    }
+
+    fn reference(src: &[u8], layout: PackedYuv, coefficients: &YuvCoefficients, output_format: OutputFormat) -> Vec<u8> {
+        let mut dst = vec![0u8; src.len() / 2 * output_format.bytes_per_pixel()];
+        convert_pixels(src, layout, coefficients, output_format, &mut dst);
+        dst
+    }
+
+    fn resolution(width: usize, height: usize) -> Resolution {
+        Resolution::new(width as u32, height as u32)
+    }
+
+    #[test]
+    fn matches_the_reference_for_every_layout_and_format() {
+        // Widths exercising whole vectors, leftovers and rows shorter than a vector
+        for (width, height) in [(16, 3), (22, 2), (6, 4), (640, 2)] {
+            let yuyv = pattern(width * 2 * height, width as u32);
+            for layout in [PackedYuv::Yuyv, PackedYuv::Uyvy] {
+                for format in FORMATS {
+                    let mut output = vec![0u8; width * format.bytes_per_pixel() * height];
+                    convert(&yuyv, resolution(width, height), layout, &bt601(), format, width * format.bytes_per_pixel(), &mut output).unwrap();
+                    assert_eq!(output, reference(&yuyv, layout, &bt601(), format), "{}x{} {:?} {}", width, height, layout, format);
+                }
+            }
+
+            // Full range has no luma offset, and BT.709 other chroma coefficients
+            let full_709 = YuvColorSpace { matrix: YuvMatrix::Bt709, range: YuvRange::Full }.coefficients();
+            let mut output = vec![0u8; width * 4 * height];
+            convert(&yuyv, resolution(width, height), PackedYuv::Yuyv, &full_709, OutputFormat::Bgra, width * 4, &mut output).unwrap();
+            assert_eq!(output, reference(&yuyv, PackedYuv::Yuyv, &full_709, OutputFormat::Bgra), "{}x{} BT.709 full range", width, height);
+        }
+    }
+
+    #[test]
+    fn matches_nokhwa() {
+        let yuyv = pattern(64 * 2 * 4, 7);
+        let frame = Buffer::new(Resolution::new(64, 4), &yuyv, FrameFormat::YUYV);
+
+        let mut rgb = vec![0u8; 64 * 4 * 3];
+        convert(&yuyv, resolution(64, 4), PackedYuv::Yuyv, &bt601(), OutputFormat::Rgb, 64 * 3, &mut rgb).unwrap();
+        assert_eq!(rgb, frame.decode_image::<RgbFormat>().unwrap().into_raw());
+
+        let mut rgba = vec![0u8; 64 * 4 * 4];
+        convert(&yuyv, resolution(64, 4), PackedYuv::Yuyv, &bt601(), OutputFormat::Rgba, 64 * 4, &mut rgba).unwrap();
+        assert_eq!(rgba, frame.decode_image::<RgbAFormat>().unwrap().into_raw());
+    }
+
+    #[test]
+    fn swaps_yuyv_and_uyvy_bytes() {
+        let yuyv = pattern(32 * 2, 3);
+        let uyvy: Vec<u8> = yuyv.chunks_exact(2).flat_map(|pair| [pair[1], pair[0]]).collect();
+
+        let mut from_yuyv = vec![0u8; 32 * 3];
+        let mut from_uyvy = vec![0u8; 32 * 3];
+        convert(&yuyv, resolution(32, 1), PackedYuv::Yuyv, &bt601(), OutputFormat::Rgb, 32 * 3, &mut from_yuyv).unwrap();
+        convert(&uyvy, resolution(32, 1), PackedYuv::Uyvy, &bt601(), OutputFormat::Rgb, 32 * 3, &mut from_uyvy).unwrap();
+        assert_eq!(from_yuyv, from_uyvy);
+    }
+
+    #[test]
+    fn writes_strided_rows_and_rejects_what_it_cannot_convert() {
+        let yuyv = pattern(8 * 2 * 2, 5);
+        let mut output = vec![9u8; 30 * 2];
+        convert(&yuyv, resolution(8, 2), PackedYuv::Yuyv, &bt601(), OutputFormat::Rgb, 30, &mut output).unwrap();
+        assert_eq!(&output[..24], &reference(&yuyv[..16], PackedYuv::Yuyv, &bt601(), OutputFormat::Rgb)[..]);
+        assert_eq!(&output[24..30], &[9; 6]);
+        assert_eq!(&output[30..54], &reference(&yuyv[16..], PackedYuv::Yuyv, &bt601(), OutputFormat::Rgb)[..]);
+
+        assert!(convert(&yuyv, resolution(8, 2), PackedYuv::Yuyv, &bt601(), OutputFormat::Gray8, 8, &mut output).is_err());
+        assert!(convert(&yuyv[..20], resolution(8, 2), PackedYuv::Yuyv, &bt601(), OutputFormat::Rgb, 24, &mut output).is_err());
+        assert!(convert(&yuyv, resolution(8, 2), PackedYuv::Yuyv, &bt601(), OutputFormat::Rgb, 23, &mut output).is_err());
+    }
+
+    #[test]
+    fn converts_odd_widths_like_nokhwa() {
+        // Rows of 7 pixels, the second one starting with the second pixel of a pair
+        let yuyv = pattern(7 * 2 * 4, 11);
+        let frame = Buffer::new(Resolution::new(7, 4), &yuyv, FrameFormat::YUYV);
+        let mut rgb = vec![0u8; 7 * 4 * 3];
+        convert(&yuyv, resolution(7, 4), PackedYuv::Yuyv, &bt601(), OutputFormat::Rgb, 7 * 3, &mut rgb).unwrap();
+        assert_eq!(rgb, frame.decode_image::<RgbFormat>().unwrap().into_raw());
+
+        for layout in [PackedYuv::Yuyv, PackedYuv::Uyvy] {
+            for format in FORMATS {
+                let stride = 9 * format.bytes_per_pixel();
+                let mut output = vec![0u8; stride * 4];
+                convert(&yuyv, resolution(7, 4), layout, &bt601(), format, stride, &mut output).unwrap();
+                let packed: Vec<u8> = output.chunks_exact(stride).flat_map(|row| row[..7 * format.bytes_per_pixel()].to_vec()).collect();
+                assert_eq!(packed, reference(&yuyv, layout, &bt601(), format), "{:?} {}", layout, format);
+            }
+        }
+
+        // The last pixel of a 7x3 frame has no V
+        let mut rgb = vec![0u8; 7 * 3 * 3];
+        convert(&yuyv[..7 * 2 * 3], resolution(7, 3), PackedYuv::Yuyv, &bt601(), OutputFormat::Rgb, 7 * 3, &mut rgb).unwrap();
+        assert_eq!(rgb[..20 * 3], reference(&yuyv[..40], PackedYuv::Yuyv, &bt601(), OutputFormat::Rgb)[..]);
+        assert_eq!(rgb[20 * 3..], bt601().to_rgb(yuyv[40], yuyv[41], 128));
+    }
+}